@@ -9,6 +9,9 @@ pub struct Camera {
     u: Vec3,
     v: Vec3,
     lens_radius: f32,
+    // Shutter open and close time
+    time0: f32,
+    time1: f32,
 }
 
 impl Camera {
@@ -43,17 +46,32 @@ impl Camera {
             u,
             v,
             lens_radius: aperture / 2.0,
+            time0: 0.0,
+            time1: 0.0,
         }
     }
 
+    /// Open the shutter over an interval, rays get a random time within it
+    pub fn with_shutter(mut self, time0: f32, time1: f32) -> Self {
+        self.time0 = time0;
+        self.time1 = time1;
+        self
+    }
+
     /// Generate a ray using the lens model
     pub fn get_ray(&self, rng: &mut RayRng, s: f32, t: f32) -> Ray {
         let rd = self.lens_radius * random_in_unit_disk(rng);
         let offset = self.u * rd.x + self.v * rd.y;
+        let time = if self.time1 > self.time0 {
+            rng.gen_range(self.time0..self.time1)
+        } else {
+            self.time0
+        };
 
         Ray::new(
             self.origin + offset,
             self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
+            time,
         )
     }
 }
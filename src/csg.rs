@@ -0,0 +1,251 @@
+use crate::object::*;
+use crate::shared::*;
+
+use smallvec::SmallVec;
+
+/// Boolean operator combining two closed objects
+#[derive(Copy, Clone)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    // Left minus right
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(&self, inside_left: bool, inside_right: bool) -> bool {
+        match self {
+            CsgOperation::Union => inside_left || inside_right,
+            CsgOperation::Intersection => inside_left && inside_right,
+            CsgOperation::Difference => inside_left && !inside_right,
+        }
+    }
+}
+
+/// Constructive solid geometry node combining two closed hittables
+pub struct Csg {
+    pub operation: CsgOperation,
+    left: Box<dyn RayHittable>,
+    right: Box<dyn RayHittable>,
+}
+
+impl Csg {
+    pub fn new(
+        operation: CsgOperation,
+        left: Box<dyn RayHittable>,
+        right: Box<dyn RayHittable>,
+    ) -> Self {
+        Csg {
+            operation,
+            left,
+            right,
+        }
+    }
+}
+
+/// Entry or exit of one of the operands
+struct CrossingEvent {
+    hit: HitRecord,
+    left: bool,
+    entering: bool,
+}
+
+impl Csg {
+    /// Whether the query range starts inside the combined object, and its boundaries within
+    /// the range in order, with front_face set on entries. The sweep is lazy, so taking the
+    /// first boundary stops it there.
+    fn boundaries(&self, query: RayQuery) -> (bool, impl Iterator<Item = HitRecord>) {
+        // Merge the crossings of both operands within the range
+        let mut events: SmallVec<[CrossingEvent; 8]> = SmallVec::new();
+        let (mut inside_left, mut inside_right) = (false, false);
+        for (operand, left) in [(&self.left, true), (&self.right, false)] {
+            for interval in operand.intersect_intervals(query) {
+                match interval.enter {
+                    Some(hit) => events.push(CrossingEvent {
+                        hit,
+                        left,
+                        entering: true,
+                    }),
+                    // Entered before the range
+                    None if left => inside_left = true,
+                    None => inside_right = true,
+                }
+                if let Some(hit) = interval.exit {
+                    events.push(CrossingEvent {
+                        hit,
+                        left,
+                        entering: false,
+                    });
+                }
+            }
+        }
+        events.sort_by(|a, b| a.hit.t.total_cmp(&b.hit.t));
+
+        // Sweep, emitting a boundary whenever the combined inside state changes
+        let operation = self.operation;
+        let starts_inside = operation.inside(inside_left, inside_right);
+        let boundaries = events.into_iter().filter_map(move |event| {
+            let was_inside = operation.inside(inside_left, inside_right);
+            if event.left {
+                inside_left = event.entering;
+            } else {
+                inside_right = event.entering;
+            }
+            let is_inside = operation.inside(inside_left, inside_right);
+            if was_inside == is_inside {
+                return None;
+            }
+
+            // The normal already faces the ray, only the side changes for subtracted surfaces
+            let mut hit = event.hit;
+            hit.front_face = is_inside;
+            Some(hit)
+        });
+        (starts_inside, boundaries)
+    }
+}
+
+impl RayHittable for Csg {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        self.boundaries(query).1.next()
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        let left = self.left.compute_bounds(hittable_index);
+        let right = self.right.compute_bounds(hittable_index);
+        match self.operation {
+            CsgOperation::Union => HittableBounds::new(
+                left.min().min(right.min()),
+                left.max().max(right.max()),
+                hittable_index,
+            ),
+            CsgOperation::Intersection => {
+                let min = left.min().max(right.min());
+                HittableBounds::new(min, left.max().min(right.max()).max(min), hittable_index)
+            }
+            CsgOperation::Difference => left,
+        }
+    }
+
+    fn intersect_intervals(&self, query: RayQuery) -> HitIntervals {
+        let (mut inside, boundaries) = self.boundaries(query);
+        let mut intervals = HitIntervals::new();
+        let mut enter: Option<HitRecord> = None;
+        for hit in boundaries {
+            inside = hit.front_face;
+            if inside {
+                enter = Some(hit);
+            } else {
+                intervals.push(HitInterval {
+                    enter: enter.take(),
+                    exit: Some(hit),
+                });
+            }
+        }
+        if inside {
+            intervals.push(HitInterval { enter, exit: None });
+        }
+        intervals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::*;
+
+    /// Boolean operations on two overlapping spheres should give the analytic intervals
+    #[test]
+    fn test_csg_spheres() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let csg = |operation: CsgOperation| {
+            Csg::new(
+                operation,
+                Box::new(Sphere::new(Point3::ZERO, 1.0, &material)),
+                Box::new(Sphere::new(Point3::new(1.0, 0.0, 0.0), 1.0, &material)),
+            )
+        };
+        let spans = |csg: &Csg, ray: Ray| {
+            let query = RayQuery {
+                ray,
+                t_min: -TRACE_INFINITY,
+                t_max: TRACE_INFINITY,
+            };
+            csg.intersect_intervals(query)
+                .iter()
+                .map(|interval| {
+                    let (enter, exit) = (interval.enter.as_ref(), interval.exit.as_ref());
+                    (enter.unwrap().t, exit.unwrap().t)
+                })
+                .collect::<Vec<_>>()
+        };
+        let assert_spans = |actual: Vec<(f32, f32)>, expected: &[(f32, f32)]| {
+            assert_eq!(actual.len(), expected.len());
+            for (a, e) in actual.iter().zip(expected) {
+                assert!((a.0 - e.0).abs() < 1e-4 && (a.1 - e.1).abs() < 1e-4);
+            }
+        };
+
+        // Along the axis the spheres span x in -1..1 and 0..2
+        let ray = Ray::new(Point3::new(-5.0, 0.0, 0.0), Vec3::X, 0.0);
+        let union = csg(CsgOperation::Union);
+        let intersection = csg(CsgOperation::Intersection);
+        let difference = csg(CsgOperation::Difference);
+        assert_spans(spans(&union, ray), &[(4.0, 7.0)]);
+        assert_spans(spans(&intersection, ray), &[(5.0, 6.0)]);
+        assert_spans(spans(&difference, ray), &[(4.0, 5.0)]);
+
+        // The difference is left through the surface of the subtracted sphere
+        let exit = difference.intersect(RayQuery {
+            ray,
+            t_min: 4.5,
+            t_max: TRACE_INFINITY,
+        });
+        let exit = exit.unwrap();
+        assert!((exit.t - 5.0).abs() < 1e-4);
+        assert!(!exit.front_face && (exit.normal + Vec3::X).length() < 1e-4);
+
+        // Off the axis the spheres no longer overlap, x in -0.436..0.436 and 0.564..1.436
+        let half = f32::sqrt(1.0 - 0.81);
+        let ray = Ray::new(Point3::new(-5.0, 0.9, 0.0), Vec3::X, 0.0);
+        let separate = [(5.0 - half, 5.0 + half), (6.0 - half, 6.0 + half)];
+        assert_spans(spans(&union, ray), &separate);
+        assert_spans(spans(&intersection, ray), &[]);
+        assert_spans(spans(&difference, ray), &separate[..1]);
+
+        // Closest hit from inside the union is its far boundary
+        let query = RayQuery {
+            ray: Ray::new(Point3::ZERO, Vec3::X, 0.0),
+            t_min: TRACE_EPSILON,
+            t_max: TRACE_INFINITY,
+        };
+        let hit = union.intersect(query).unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-4 && !hit.front_face);
+
+        // A shortened range inside the outer sphere, which it never crosses, still knows the
+        // ray is inside and stops at the hollow
+        let hollow = Csg::new(
+            CsgOperation::Difference,
+            Box::new(Sphere::new(Point3::ZERO, 3.0, &material)),
+            Box::new(Sphere::new(Point3::ZERO, 1.0, &material)),
+        );
+        let query = RayQuery {
+            ray: Ray::new(Point3::new(-2.0, 0.0, 0.0), Vec3::X, 0.0),
+            t_min: TRACE_EPSILON,
+            t_max: 2.0,
+        };
+        let hit = hollow.intersect(query).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-4 && !hit.front_face);
+        let intervals = hollow.intersect_intervals(query);
+        assert_eq!(intervals.len(), 1);
+        assert!(intervals[0].enter.is_none() && intervals[0].exit.is_some());
+        let query = RayQuery {
+            t_max: 0.5,
+            ..query
+        };
+        assert!(hollow.intersect(query).is_none());
+        let intervals = hollow.intersect_intervals(query);
+        assert_eq!(intervals.len(), 1);
+        assert!(intervals[0].enter.is_none() && intervals[0].exit.is_none());
+    }
+}
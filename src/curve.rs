@@ -0,0 +1,251 @@
+use crate::material::*;
+use crate::object::*;
+use crate::shared::*;
+
+/// Cross section of a curve
+#[derive(Copy, Clone, PartialEq)]
+pub enum CurveType {
+    // Flat strip which always faces the ray, as used for hair
+    Ribbon,
+    // Round tube
+    Tube,
+}
+
+/// Cubic Bézier segment with a width varying linearly along it, intersected by recursive
+/// subdivision in ray space as in pbrt
+pub struct Curve {
+    pub control_points: [Point3; 4],
+    pub width0: f32,
+    pub width1: f32,
+    pub curve_type: CurveType,
+    pub material: Arc<dyn Material>,
+}
+
+/// Closest leaf hit during subdivision, in ray space units
+struct CurveHit {
+    u: f32,
+    // Depth of the hit surface and of the curve center along the ray
+    depth: f32,
+    center_depth: f32,
+}
+
+fn bezier_point(cp: &[Vec3; 4], u: f32) -> Vec3 {
+    let a = cp[0].lerp(cp[1], u);
+    let b = cp[1].lerp(cp[2], u);
+    let c = cp[2].lerp(cp[3], u);
+    a.lerp(b, u).lerp(b.lerp(c, u), u)
+}
+
+fn bezier_derivative(cp: &[Vec3; 4], u: f32) -> Vec3 {
+    let a = (cp[1] - cp[0]).lerp(cp[2] - cp[1], u);
+    let b = (cp[2] - cp[1]).lerp(cp[3] - cp[2], u);
+    3.0 * a.lerp(b, u)
+}
+
+/// Split the segment at u = 0.5 with de Casteljau's algorithm
+fn bezier_split(cp: &[Vec3; 4]) -> ([Vec3; 4], [Vec3; 4]) {
+    let a = cp[0].lerp(cp[1], 0.5);
+    let b = cp[1].lerp(cp[2], 0.5);
+    let c = cp[2].lerp(cp[3], 0.5);
+    let ab = a.lerp(b, 0.5);
+    let bc = b.lerp(c, 0.5);
+    let middle = ab.lerp(bc, 0.5);
+    ([cp[0], a, ab, middle], [middle, bc, c, cp[3]])
+}
+
+impl Curve {
+    pub fn new(
+        control_points: [Point3; 4],
+        width0: f32,
+        width1: f32,
+        curve_type: CurveType,
+        material: &Arc<dyn Material>,
+    ) -> Self {
+        Curve {
+            control_points,
+            width0,
+            width1,
+            curve_type,
+            material: material.clone(),
+        }
+    }
+
+    fn width(&self, u: f32) -> f32 {
+        self.width0 + (self.width1 - self.width0) * u
+    }
+
+    /// Find the closest hit in ray space, where the ray starts at the origin along +z
+    fn recursive_intersect(
+        &self,
+        cp: &[Vec3; 4],
+        u_range: (f32, f32),
+        depth: u32,
+        z_min: f32,
+        closest: &mut Option<CurveHit>,
+    ) {
+        let z_max = closest.as_ref().map_or(f32::MAX, |hit| hit.depth);
+
+        // The control points bound the segment, padded by the widest point
+        let half_width = 0.5 * self.width(u_range.0).max(self.width(u_range.1));
+        let min = cp[0].min(cp[1]).min(cp[2]).min(cp[3]) - Vec3::splat(half_width);
+        let max = cp[0].max(cp[1]).max(cp[2]).max(cp[3]) + Vec3::splat(half_width);
+        if min.x > 0.0 || max.x < 0.0 || min.y > 0.0 || max.y < 0.0 {
+            return;
+        }
+        if max.z < z_min || min.z > z_max {
+            return;
+        }
+
+        if depth > 0 {
+            let (left, right) = bezier_split(cp);
+            let u_middle = 0.5 * (u_range.0 + u_range.1);
+            self.recursive_intersect(&left, (u_range.0, u_middle), depth - 1, z_min, closest);
+            self.recursive_intersect(&right, (u_middle, u_range.1), depth - 1, z_min, closest);
+            return;
+        }
+
+        // The ray must pass between the planes perpendicular to the segment ends
+        let edge = (cp[1].y - cp[0].y) * -cp[0].y + cp[0].x * (cp[0].x - cp[1].x);
+        if edge < 0.0 {
+            return;
+        }
+        let edge = (cp[2].y - cp[3].y) * -cp[3].y + cp[3].x * (cp[3].x - cp[2].x);
+        if edge < 0.0 {
+            return;
+        }
+
+        // Closest point to the ray on the nearly straight segment
+        let segment = Vec2::new(cp[3].x - cp[0].x, cp[3].y - cp[0].y);
+        let denom = segment.length_squared();
+        if denom == 0.0 {
+            return;
+        }
+        let w = (Vec2::new(-cp[0].x, -cp[0].y).dot(segment) / denom).clamp(0.0, 1.0);
+        let u = u_range.0 + (u_range.1 - u_range.0) * w;
+        let half_width = 0.5 * self.width(u);
+
+        let center = bezier_point(cp, w);
+        let distance_sq = center.x * center.x + center.y * center.y;
+        if distance_sq > half_width * half_width {
+            return;
+        }
+        let depth = match self.curve_type {
+            CurveType::Ribbon => center.z,
+            CurveType::Tube => center.z - (half_width * half_width - distance_sq).sqrt(),
+        };
+        if depth < z_min || depth > z_max {
+            return;
+        }
+        *closest = Some(CurveHit {
+            u,
+            depth,
+            center_depth: center.z,
+        });
+    }
+}
+
+impl RayHittable for Curve {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let r = query.ray;
+        let length = r.direction.length();
+        let direction = r.direction / length;
+
+        // Project the control points into a frame with the ray along +z
+        let x_axis = direction.any_orthonormal_vector();
+        let y_axis = direction.cross(x_axis);
+        let cp = self.control_points.map(|p| {
+            let d = p - r.origin;
+            Vec3::new(d.dot(x_axis), d.dot(y_axis), d.dot(direction))
+        });
+
+        // Subdivide until the segments are nearly straight compared to the width
+        let curvature = (cp[0] - 2.0 * cp[1] + cp[2])
+            .abs()
+            .max((cp[1] - 2.0 * cp[2] + cp[3]).abs())
+            .max_element();
+        let epsilon = 0.05 * self.width0.max(self.width1);
+        let depth = if curvature > 0.0 {
+            let levels = f32::log2(std::f32::consts::SQRT_2 * 6.0 * curvature / (8.0 * epsilon));
+            (0.5 * levels).round().clamp(0.0, 10.0) as u32
+        } else {
+            0
+        };
+
+        let mut closest = None;
+        self.recursive_intersect(&cp, (0.0, 1.0), depth, query.t_min * length, &mut closest);
+        let hit = closest?;
+        let t = hit.depth / length;
+        if t > query.t_max {
+            return None;
+        }
+
+        // Frame with the fiber along the tangent, facing back along the ray
+        let center = bezier_point(&self.control_points, hit.u);
+        let dpdu = bezier_derivative(&self.control_points, hit.u);
+        let tangent = dpdu.normalize_or(x_axis);
+        let facing = (-direction + direction.dot(tangent) * tangent).normalize_or(-direction);
+        let side = facing.cross(tangent);
+
+        // Signed offset of the ray across the width, -1 to 1
+        let offset = r.at(hit.center_depth / length) - center;
+        let h = (offset.dot(side) / (0.5 * self.width(hit.u))).clamp(-1.0, 1.0);
+
+        let point = r.at(t);
+        let outward_normal = match self.curve_type {
+            CurveType::Ribbon => facing,
+            CurveType::Tube => {
+                let radial = point - center;
+                (radial - radial.dot(tangent) * tangent).normalize_or(facing)
+            }
+        };
+
+        let mut record = HitRecord::new(r, t, outward_normal, self.material.clone());
+        record.uv = Vec2::new(hit.u, 0.5 + 0.5 * h);
+        record.dpdu = dpdu;
+        record.dpdv = self.width(hit.u) * side;
+        Some(record)
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        let cp = &self.control_points;
+        let half_width = Vec3::splat(0.5 * self.width0.max(self.width1));
+        HittableBounds::new(
+            cp[0].min(cp[1]).min(cp[2]).min(cp[3]) - half_width,
+            cp[0].max(cp[1]).max(cp[2]).max(cp[3]) + half_width,
+            hittable_index,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A curved tube should be hit on its surface with the normal pointing away from the axis
+    #[test]
+    fn test_curve_tube() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let control_points = [
+            Point3::new(-1.0, 0.0, 0.0),
+            Point3::new(-0.3, 0.5, 0.0),
+            Point3::new(0.3, 0.5, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+        ];
+        let tube = Curve::new(control_points, 0.2, 0.2, CurveType::Tube, &material);
+        let ribbon = Curve::new(control_points, 0.2, 0.2, CurveType::Ribbon, &material);
+
+        // The top of the arch is at y = 0.375
+        for (curve, expected_z) in [(&tube, 0.1), (&ribbon, 0.0)] {
+            let query = RayQuery {
+                ray: Ray::new(Point3::new(0.0, 0.375, 2.0), Vec3::new(0.0, 0.0, -1.0), 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let hit = curve.intersect(query).unwrap();
+            assert!((hit.point.z - expected_z).abs() < 1e-3);
+            assert!((hit.normal - Vec3::Z).length() < 1e-3);
+            assert!((hit.uv - Vec2::new(0.5, 0.5)).length() < 1e-3);
+            assert!(hit.dpdu.normalize().dot(Vec3::X) > 0.999);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use crate::object::*;
+use crate::shared::*;
+
+/// Number of SAH bins along the split axis
+const BIN_COUNT: usize = 12;
+/// Nodes with this many primitives or fewer become leaves
+const MAX_LEAF_SIZE: usize = 4;
+/// Below this depth nodes are split at the median, which bounds the tree depth
+const MAX_SAH_DEPTH: usize = 64;
+/// Traversal stack size, enough for MAX_SAH_DEPTH plus a balanced tree below it
+const STACK_SIZE: usize = 128;
+
+/// Node in depth first order, the first child directly follows its parent
+#[derive(Copy, Clone)]
+struct FlatNode {
+    min: Point3,
+    max: Point3,
+    // First index for leaves, second child for interior nodes
+    offset: u32,
+    // Number of primitives, zero for interior nodes
+    count: u16,
+    // Split axis, decides which child is visited first
+    axis: u8,
+}
+
+impl FlatNode {
+    fn hit(&self, origin: Point3, inv_direction: Vec3, t_min: f32, t_max: f32) -> bool {
+        let t0 = (self.min - origin) * inv_direction;
+        let t1 = (self.max - origin) * inv_direction;
+        let t_enter = t0.min(t1).max_element().max(t_min);
+        let t_exit = t0.max(t1).min_element().min(t_max);
+        t_enter <= t_exit
+    }
+}
+
+/// Primitive bounds used while building
+#[derive(Copy, Clone)]
+struct BuildPrimitive {
+    min: Point3,
+    max: Point3,
+    centroid: Point3,
+    index: usize,
+}
+
+/// Bounding volume hierarchy over glam types, built with binned SAH and traversed
+/// front to back from a flat node array
+#[derive(Default)]
+pub struct FlatBvh {
+    nodes: Vec<FlatNode>,
+    // Hittable indices referenced by the leaves
+    indices: Vec<usize>,
+}
+
+impl FlatBvh {
+    pub fn build(bounds: &[HittableBounds]) -> Self {
+        let mut primitives: Vec<BuildPrimitive> = bounds
+            .iter()
+            .map(|b| BuildPrimitive {
+                min: b.min(),
+                max: b.max(),
+                centroid: 0.5 * (b.min() + b.max()),
+                index: b.hittable_index,
+            })
+            .collect();
+
+        let mut bvh = FlatBvh {
+            nodes: Vec::with_capacity(2 * primitives.len()),
+            indices: Vec::with_capacity(primitives.len()),
+        };
+        if !primitives.is_empty() {
+            bvh.build_node(&mut primitives, 0);
+        }
+        bvh
+    }
+
+    fn build_node(&mut self, primitives: &mut [BuildPrimitive], depth: usize) {
+        let (mut min, mut max) = (Vec3::MAX, Vec3::MIN);
+        let (mut centroid_min, mut centroid_max) = (Vec3::MAX, Vec3::MIN);
+        for p in primitives.iter() {
+            min = min.min(p.min);
+            max = max.max(p.max);
+            centroid_min = centroid_min.min(p.centroid);
+            centroid_max = centroid_max.max(p.centroid);
+        }
+        // Pad so rays lying in the plane of a face, such as axis aligned rays along an edge,
+        // still enter the box instead of producing NaN in the slab test
+        let padding = 1e-5 * (Vec3::ONE + min.abs().max(max.abs()));
+
+        let node_index = self.nodes.len();
+        self.nodes.push(FlatNode {
+            min: min - padding,
+            max: max + padding,
+            offset: self.indices.len() as u32,
+            count: 0,
+            axis: 0,
+        });
+
+        if primitives.len() <= MAX_LEAF_SIZE {
+            self.nodes[node_index].count = primitives.len() as u16;
+            self.indices.extend(primitives.iter().map(|p| p.index));
+            return;
+        }
+
+        let axis = (centroid_max - centroid_min).max_dimension();
+        let split = if depth < MAX_SAH_DEPTH {
+            Self::split_sah(primitives, axis, centroid_min[axis], centroid_max[axis])
+        } else {
+            None
+        };
+        let split = split.unwrap_or_else(|| {
+            // Fall back to the median, which also handles identical centroids
+            let middle = primitives.len() / 2;
+            primitives.select_nth_unstable_by(middle, |a, b| {
+                a.centroid[axis].total_cmp(&b.centroid[axis])
+            });
+            middle
+        });
+
+        let (left, right) = primitives.split_at_mut(split);
+        self.build_node(left, depth + 1);
+        self.nodes[node_index].offset = self.nodes.len() as u32;
+        self.nodes[node_index].axis = axis as u8;
+        self.build_node(right, depth + 1);
+    }
+
+    /// Partition at the bin boundary with the lowest surface area cost, None if degenerate
+    fn split_sah(
+        primitives: &mut [BuildPrimitive],
+        axis: usize,
+        centroid_min: f32,
+        centroid_max: f32,
+    ) -> Option<usize> {
+        let extent = centroid_max - centroid_min;
+        if extent <= 0.0 {
+            return None;
+        }
+        let bin_of = |p: &BuildPrimitive| {
+            let bin = (BIN_COUNT as f32 * (p.centroid[axis] - centroid_min) / extent) as usize;
+            bin.min(BIN_COUNT - 1)
+        };
+
+        let mut counts = [0usize; BIN_COUNT];
+        let mut bin_bounds = [(Vec3::MAX, Vec3::MIN); BIN_COUNT];
+        for p in primitives.iter() {
+            let bin = bin_of(p);
+            counts[bin] += 1;
+            bin_bounds[bin] = (bin_bounds[bin].0.min(p.min), bin_bounds[bin].1.max(p.max));
+        }
+
+        let area = |(min, max): (Vec3, Vec3)| {
+            let d = (max - min).max(Vec3::ZERO);
+            d.x * d.y + d.y * d.z + d.z * d.x
+        };
+
+        // Sweep from the right to get the cost of everything after each boundary
+        let mut right_costs = [0.0; BIN_COUNT];
+        let (mut bounds, mut count) = ((Vec3::MAX, Vec3::MIN), 0);
+        for bin in (1..BIN_COUNT).rev() {
+            bounds = (
+                bounds.0.min(bin_bounds[bin].0),
+                bounds.1.max(bin_bounds[bin].1),
+            );
+            count += counts[bin];
+            right_costs[bin] = count as f32 * area(bounds);
+        }
+
+        // Sweep from the left and pick the cheapest boundary
+        let mut best: Option<(usize, f32)> = None;
+        let (mut bounds, mut count) = ((Vec3::MAX, Vec3::MIN), 0);
+        for bin in 0..BIN_COUNT - 1 {
+            bounds = (
+                bounds.0.min(bin_bounds[bin].0),
+                bounds.1.max(bin_bounds[bin].1),
+            );
+            count += counts[bin];
+            if count == 0 || count == primitives.len() {
+                continue;
+            }
+            let cost = count as f32 * area(bounds) + right_costs[bin + 1];
+            if best.is_none_or(|(_, best_cost)| cost < best_cost) {
+                best = Some((bin, cost));
+            }
+        }
+        let (best_bin, _) = best?;
+
+        // Partition in place around the chosen boundary
+        let mut split = 0;
+        for i in 0..primitives.len() {
+            if bin_of(&primitives[i]) <= best_bin {
+                primitives.swap(i, split);
+                split += 1;
+            }
+        }
+        Some(split)
+    }
+
+    /// Closest hit along the query. intersect_primitive is called with a hittable index and
+    /// the query shortened to the closest hit so far.
+    pub fn intersect<F>(&self, query: RayQuery, mut intersect_primitive: F) -> Option<HitRecord>
+    where
+        F: FnMut(usize, RayQuery) -> Option<HitRecord>,
+    {
+        let mut closest_hit_option = None;
+        self.traverse(query, |index, query| {
+            if let Some(hit) = intersect_primitive(index, *query) {
+                // Shorten the ray, which culls nodes further away
+                query.t_max = hit.t;
+                closest_hit_option = Some(hit);
+            }
+            false
+        });
+        closest_hit_option
+    }
+
+    /// Whether occludes_primitive returns true for any hittable, stopping at the first one
+    pub fn any_hit<F>(&self, query: RayQuery, mut occludes_primitive: F) -> bool
+    where
+        F: FnMut(usize, RayQuery) -> bool,
+    {
+        self.traverse(query, |index, query| occludes_primitive(index, *query))
+    }
+
+    /// Visit the primitives of all leaves hit by the ray front to back, until visit returns true.
+    /// The visitor may shorten the query to cull the remaining nodes.
+    fn traverse<F>(&self, mut query: RayQuery, mut visit: F) -> bool
+    where
+        F: FnMut(usize, &mut RayQuery) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let origin = query.ray.origin;
+        let inv_direction = query.ray.direction.recip();
+        let direction_negative = inv_direction.cmplt(Vec3::ZERO);
+
+        let mut stack = [0u32; STACK_SIZE];
+        let mut stack_size = 0;
+        let mut node_index = 0;
+        loop {
+            let node = &self.nodes[node_index];
+            if node.hit(origin, inv_direction, query.t_min, query.t_max) {
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    for &index in &self.indices[first..first + node.count as usize] {
+                        if visit(index, &mut query) {
+                            return true;
+                        }
+                    }
+                } else {
+                    // Visit the near child first and keep the far one for later
+                    let (near, far) = if direction_negative.test(node.axis as usize) {
+                        (node.offset as usize, node_index + 1)
+                    } else {
+                        (node_index + 1, node.offset as usize)
+                    };
+                    stack[stack_size] = far as u32;
+                    stack_size += 1;
+                    node_index = near;
+                    continue;
+                }
+            }
+            if stack_size == 0 {
+                return false;
+            }
+            stack_size -= 1;
+            node_index = stack[stack_size] as usize;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::*;
+
+    /// The BVH should find the same closest hits as testing every sphere
+    #[test]
+    fn test_flat_bvh_matches_brute_force() {
+        let mut rng = RayRng::new(0);
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let spheres: Vec<Sphere> = (0..500)
+            .map(|_| {
+                let center = Point3::new(
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                    rng.gen_range(-10.0..10.0),
+                );
+                Sphere::new(center, rng.gen_range(0.05..1.0), &material)
+            })
+            .collect();
+        let bounds: Vec<HittableBounds> = spheres
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s.compute_bounds(i))
+            .collect();
+        let bvh = FlatBvh::build(&bounds);
+
+        for _ in 0..1000 {
+            let query = RayQuery {
+                ray: Ray::new(Point3::ZERO, random_unit_vector(&mut rng), 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let expected = spheres
+                .iter()
+                .filter_map(|s| s.intersect(query))
+                .min_by(|a, b| a.t.total_cmp(&b.t))
+                .map(|hit| hit.t);
+            let found = bvh
+                .intersect(query, |index, query| spheres[index].intersect(query))
+                .map(|hit| hit.t);
+            assert_eq!(found, expected);
+        }
+    }
+}
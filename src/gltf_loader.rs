@@ -0,0 +1,585 @@
+use crate::camera::*;
+use crate::image::*;
+use crate::material::*;
+use crate::microfacet::*;
+use crate::normal_map::*;
+use crate::object::*;
+use crate::scene::*;
+use crate::shared::*;
+use crate::texture::*;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Error while loading a glTF file
+#[derive(Debug)]
+pub enum GltfError {
+    Import(PathBuf, gltf::Error),
+    Format(PathBuf, String),
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GltfError::Import(path, err) => write!(f, "{}: {}", path.display(), err),
+            GltfError::Format(path, message) => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {}
+
+/// Convert decoded image data, 16 bit samples are in native byte order
+fn convert_image(data: gltf::image::Data) -> Result<Image, String> {
+    use gltf::image::Format;
+    let (channels, bit_depth) = match data.format {
+        Format::R8 => (1, 8),
+        Format::R8G8 => (2, 8),
+        Format::R8G8B8 => (3, 8),
+        Format::R8G8B8A8 => (4, 8),
+        Format::R16 => (1, 16),
+        Format::R16G16 => (2, 16),
+        Format::R16G16B16 => (3, 16),
+        Format::R16G16B16A16 => (4, 16),
+        other => return Err(format!("unsupported image format {:?}", other)),
+    };
+    let mut pixels = data.pixels;
+    if bit_depth == 16 {
+        for sample in pixels.chunks_exact_mut(2) {
+            let value = u16::from_ne_bytes([sample[0], sample[1]]);
+            sample.copy_from_slice(&value.to_be_bytes());
+        }
+    }
+    Ok(Image::new(
+        data.width as usize,
+        data.height as usize,
+        channels,
+        bit_depth,
+        pixels,
+    ))
+}
+
+/// Glue between the glTF document and the scene being filled
+struct Loader<'a> {
+    path: &'a Path,
+    buffers: Vec<gltf::buffer::Data>,
+    scene: &'a mut Scene,
+    aspect_ratio: f32,
+    camera: Option<Camera>,
+    // Decoded textures by texture index and whether they are sRGB, None if unreadable
+    textures: HashMap<(usize, bool), Option<Arc<dyn Texture>>>,
+}
+
+impl Loader<'_> {
+    /// Image texture of a glTF texture, decoded once. Unreadable ones are skipped.
+    fn load_texture(&mut self, texture: gltf::Texture, srgb: bool) -> Option<Arc<dyn Texture>> {
+        let key = (texture.index(), srgb);
+        if let Some(loaded) = self.textures.get(&key) {
+            return loaded.clone();
+        }
+
+        let source = texture.source().source();
+        let image = gltf::image::Data::from_source(source, self.path.parent(), &self.buffers)
+            .map_err(|e| e.to_string())
+            .and_then(convert_image);
+        let loaded = match image {
+            Ok(image) => {
+                let wrap = match texture.sampler().wrap_s() {
+                    gltf::texture::WrappingMode::ClampToEdge => WrapMode::Clamp,
+                    _ => WrapMode::Repeat,
+                };
+                let texture: Arc<dyn Texture> =
+                    Arc::new(ImageTexture::from_image(&image, srgb, wrap));
+                Some(texture)
+            }
+            Err(message) => {
+                println!(
+                    "Warning: skipping texture {} of {}: {}",
+                    texture.index(),
+                    self.path.display(),
+                    message
+                );
+                None
+            }
+        };
+        self.textures.insert(key, loaded.clone());
+        loaded
+    }
+
+    /// Convert a material, with its normal texture bending the shading normal if it has one
+    fn convert_material(
+        &mut self,
+        material: &gltf::Material,
+        has_colors: bool,
+    ) -> Arc<dyn Material> {
+        let surface = self.convert_surface(material, has_colors);
+        // Normal textures hold data rather than colors
+        let normal_map = material
+            .normal_texture()
+            .filter(|info| info.tex_coord() == 0)
+            .and_then(|info| {
+                let texture = self.load_texture(info.texture(), false)?;
+                Some(NormalMap::Tangent {
+                    texture,
+                    scale: info.scale(),
+                })
+            });
+        match normal_map {
+            Some(map) => Arc::new(NormalMapped::new(surface, map)),
+            None => surface,
+        }
+    }
+
+    /// Map a metallic-roughness material onto the closest available material.
+    /// The base color texture only applies to diffuse materials.
+    fn convert_surface(
+        &mut self,
+        material: &gltf::Material,
+        has_colors: bool,
+    ) -> Arc<dyn Material> {
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, _] = pbr.base_color_factor();
+        let base_color = Color::new(r, g, b);
+
+        let transmission = material
+            .transmission()
+            .map(|t| t.transmission_factor())
+            .unwrap_or(0.0);
+        if transmission > 0.5 {
+            return Arc::new(RoughDielectric::new(
+                material.ior().unwrap_or(1.5),
+                Ggx::from_roughness(pbr.roughness_factor()),
+            ));
+        }
+        if pbr.metallic_factor() > 0.5 {
+            return Arc::new(Conductor::from_color(
+                base_color,
+                Ggx::from_roughness(pbr.roughness_factor()),
+            ));
+        }
+
+        // Only the first UV set is loaded
+        let albedo: Arc<dyn Texture> = match pbr
+            .base_color_texture()
+            .filter(|info| info.tex_coord() == 0)
+            .and_then(|info| self.load_texture(info.texture(), true))
+        {
+            Some(texture) if base_color == Color::ONE => texture,
+            Some(texture) => Arc::new(ScaledTexture {
+                texture,
+                scale: base_color,
+            }),
+            None => Arc::new(SolidColor { color: base_color }),
+        };
+        if has_colors {
+            // COLOR_0 multiplies the base color
+            Arc::new(VertexColorLambertian::textured(albedo))
+        } else {
+            Arc::new(Lambertian::textured(albedo))
+        }
+    }
+
+    fn visit_node(&mut self, node: gltf::Node, parent_transform: Mat4) -> Result<(), GltfError> {
+        let local_transform = Mat4::from_cols_array_2d(&node.transform().matrix());
+        let transform = parent_transform * local_transform;
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                self.add_primitive(&primitive, transform)?;
+            }
+        }
+
+        // Only the first perspective camera is used
+        if let (None, Some(camera)) = (&self.camera, node.camera()) {
+            if let gltf::camera::Projection::Perspective(perspective) = camera.projection() {
+                let lookfrom = transform.transform_point3(Point3::ZERO);
+                let forward = transform.transform_vector3(-Vec3::Z).normalize();
+                let vup = transform.transform_vector3(Vec3::Y).normalize();
+                self.camera = Some(Camera::new(
+                    lookfrom,
+                    lookfrom + forward,
+                    vup,
+                    perspective.yfov().to_degrees(),
+                    // The framebuffer decides the aspect, the file's would stretch the render
+                    self.aspect_ratio,
+                    0.0,
+                    1.0,
+                ));
+            }
+        }
+
+        for child in node.children() {
+            self.visit_node(child, transform)?;
+        }
+        Ok(())
+    }
+
+    fn add_primitive(
+        &mut self,
+        primitive: &gltf::Primitive,
+        transform: Mat4,
+    ) -> Result<(), GltfError> {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            println!("Warning: skipping non-triangle primitive");
+            return Ok(());
+        }
+
+        let buffers = &self.buffers;
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let positions: Vec<Point3> = reader
+            .read_positions()
+            .ok_or_else(|| {
+                GltfError::Format(self.path.to_path_buf(), String::from("missing POSITION"))
+            })?
+            .map(|p| transform.transform_point3(Vec3::from(p)))
+            .collect();
+
+        // Normals use the inverse transpose, negative scale flips the winding
+        let normal_matrix = Mat3::from_mat4(transform).inverse().transpose();
+        let flip_winding = transform.determinant() < 0.0;
+
+        let normals: Vec<Vec3> = reader
+            .read_normals()
+            .map(|normals| {
+                normals
+                    .map(|n| (normal_matrix * Vec3::from(n)).normalize_or_zero())
+                    .collect()
+            })
+            .unwrap_or_default();
+        // glTF puts v = 0 at the top of the image, textures here at the bottom
+        let uvs: Vec<Vec2> = reader
+            .read_tex_coords(0)
+            .map(|uvs| uvs.into_f32().map(|[u, v]| Vec2::new(u, 1.0 - v)).collect())
+            .unwrap_or_default();
+        let colors: Vec<Color> = reader
+            .read_colors(0)
+            .map(|colors| colors.into_rgb_f32().map(Color::from).collect())
+            .unwrap_or_default();
+
+        let flat_indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        if let Some(i) = flat_indices
+            .iter()
+            .find(|i| **i as usize >= positions.len())
+        {
+            return Err(GltfError::Format(
+                self.path.to_path_buf(),
+                format!("vertex index {} out of range", i),
+            ));
+        }
+        if !flat_indices.len().is_multiple_of(3) {
+            return Err(GltfError::Format(
+                self.path.to_path_buf(),
+                format!("{} indices do not make whole triangles", flat_indices.len()),
+            ));
+        }
+        let indices: Vec<[u32; 3]> = flat_indices
+            .chunks_exact(3)
+            .map(|t| {
+                if flip_winding {
+                    [t[0], t[2], t[1]]
+                } else {
+                    [t[0], t[1], t[2]]
+                }
+            })
+            .collect();
+
+        let material = self.convert_material(&primitive.material(), !colors.is_empty());
+        let mesh =
+            TriangleMesh::new(positions, normals, uvs, indices, &material).with_colors(colors);
+        self.scene.add_mesh(Arc::new(mesh));
+        Ok(())
+    }
+}
+
+/// Load the default scene of a .gltf or .glb file into the scene.
+/// Returns the first perspective camera with the given aspect_ratio.
+pub fn load_gltf(
+    path: &Path,
+    scene: &mut Scene,
+    aspect_ratio: f32,
+) -> Result<Option<Camera>, GltfError> {
+    let import_error = |e| GltfError::Import(path.to_path_buf(), e);
+    // Only the buffers are loaded up front, images are decoded when a material uses them
+    let gltf::Gltf { document, blob } = gltf::Gltf::open(path).map_err(import_error)?;
+    let buffers = gltf::import_buffers(&document, path.parent(), blob).map_err(import_error)?;
+
+    let mut loader = Loader {
+        path,
+        buffers,
+        scene,
+        aspect_ratio,
+        camera: None,
+        textures: HashMap::new(),
+    };
+
+    if let Some(gltf_scene) = document.default_scene().or(document.scenes().next()) {
+        for node in gltf_scene.nodes() {
+            loader.visit_node(node, Mat4::IDENTITY)?;
+        }
+    }
+
+    Ok(loader.camera)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut encoded = String::new();
+        for chunk in bytes.chunks(3) {
+            let b = [
+                chunk[0],
+                *chunk.get(1).unwrap_or(&0),
+                *chunk.get(2).unwrap_or(&0),
+            ];
+            let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    encoded.push(ALPHABET[(n >> (18 - 6 * i)) & 63] as char);
+                } else {
+                    encoded.push('=');
+                }
+            }
+        }
+        encoded
+    }
+
+    /// Node transforms compose down the hierarchy, the camera follows its node with the
+    /// framebuffer aspect, and materials map by their metallic and transmission factors
+    #[test]
+    fn test_gltf_scene() {
+        let positions = [-1.0f32, -1.0, 0.0, 1.0, -1.0, 0.0, 0.0, 1.0, 0.0];
+        let bytes: Vec<u8> = positions.iter().flat_map(|f| f.to_le_bytes()).collect();
+        let json = format!(
+            r#"{{
+            "asset": {{ "version": "2.0" }},
+            "extensionsUsed": ["KHR_materials_transmission"],
+            "scene": 0,
+            "scenes": [{{ "nodes": [0] }}],
+            "nodes": [
+                {{ "translation": [0, 0, -5], "children": [1, 2, 3, 4] }},
+                {{ "mesh": 0, "translation": [-3, 0, 0] }},
+                {{ "mesh": 1, "scale": [2, 2, 2] }},
+                {{ "mesh": 2, "translation": [3, 0, 0] }},
+                {{ "camera": 0, "translation": [0, 0, 5] }}
+            ],
+            "cameras": [{{
+                "type": "perspective",
+                "perspective": {{ "yfov": 0.5, "znear": 0.1, "aspectRatio": 1.0 }}
+            }}],
+            "meshes": [
+                {{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "material": 0 }}] }},
+                {{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "material": 1 }}] }},
+                {{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "material": 2 }}] }}
+            ],
+            "materials": [
+                {{ "pbrMetallicRoughness": {{
+                    "baseColorFactor": [0.2, 0.4, 0.6, 1], "metallicFactor": 0 }} }},
+                {{ "pbrMetallicRoughness": {{
+                    "baseColorFactor": [0.9, 0.9, 0.9, 1], "roughnessFactor": 0 }} }},
+                {{ "pbrMetallicRoughness": {{ "metallicFactor": 0, "roughnessFactor": 0 }},
+                   "extensions": {{ "KHR_materials_transmission": {{
+                       "transmissionFactor": 1 }} }} }}
+            ],
+            "accessors": [{{
+                "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                "min": [-1, -1, 0], "max": [1, 1, 0]
+            }}],
+            "bufferViews": [{{ "buffer": 0, "byteLength": 36 }}],
+            "buffers": [{{
+                "byteLength": 36,
+                "uri": "data:application/octet-stream;base64,{}"
+            }}]
+        }}"#,
+            base64(&bytes)
+        );
+        let path = std::env::temp_dir().join("one_weekend_test_scene.gltf");
+        std::fs::write(&path, json).unwrap();
+        let mut scene = Scene::new();
+        let camera = load_gltf(&path, &mut scene, 2.0).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        scene.build_bvh();
+
+        let mut rng = RayRng::new(0);
+        let trace = |origin: Point3| {
+            let query = RayQuery {
+                ray: Ray::new(origin, Vec3::new(0.0, 0.0, -1.0), 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            scene.intersect(query).map(|hit| (query.ray, hit))
+        };
+
+        // Scaled by two under the parent, the unscaled triangle ends at y = 1
+        let (_, hit) = trace(Point3::new(0.0, 1.5, 0.0)).unwrap();
+        assert!((hit.point - Point3::new(0.0, 1.5, -5.0)).length() < 1e-5);
+        assert!(trace(Point3::new(-3.0, 1.5, 0.0)).is_none());
+
+        // At the world origin looking down -z, half of yfov above the center and twice as wide
+        let center = camera.get_ray(&mut rng, 0.5, 0.5);
+        let top = camera.get_ray(&mut rng, 0.5, 1.0);
+        let right = camera.get_ray(&mut rng, 1.0, 0.5);
+        assert!(center.origin.length() < 1e-5);
+        assert!(center.direction.normalize().dot(-Vec3::Z) > 0.9999);
+        assert!((center.direction.angle_between(top.direction) - 0.25).abs() < 1e-4);
+        let tan_half_width = right.direction.x / -right.direction.z;
+        assert!((tan_half_width - 2.0 * f32::tan(0.25)).abs() < 1e-4);
+
+        // Diffuse keeps the base color, metal mirrors and glass lets some rays through
+        let (ray, hit) = trace(Point3::new(-3.0, 0.0, 0.0)).unwrap();
+        let scatter = hit.material.scatter(&mut rng, &ray, &hit).unwrap();
+        assert_eq!(scatter.attenuation, Color::new(0.2, 0.4, 0.6));
+
+        let (ray, hit) = trace(Point3::ZERO).unwrap();
+        let scatter = hit.material.scatter(&mut rng, &ray, &hit).unwrap();
+        assert!((scatter.scattered_ray.direction - Vec3::Z).length() < 1e-5);
+        assert!((scatter.attenuation - Color::splat(0.9)).length() < 1e-3);
+
+        let (ray, hit) = trace(Point3::new(3.0, 0.0, 0.0)).unwrap();
+        let transmitted = (0..100)
+            .filter_map(|_| hit.material.scatter(&mut rng, &ray, &hit))
+            .filter(|scatter| scatter.scattered_ray.direction.z < 0.0)
+            .count();
+        assert!(transmitted > 80);
+    }
+
+    /// The base color texture is flipped to v up and multiplied by the base color factor, and
+    /// the scaled normal texture tilts the shading normal along u
+    #[test]
+    fn test_gltf_textures() {
+        let encode = |height: u32, data: &[u8]| {
+            let mut png_bytes = Vec::new();
+            let mut encoder = png::Encoder::new(&mut png_bytes, 1, height);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder
+                .write_header()
+                .unwrap()
+                .write_image_data(data)
+                .unwrap();
+            png_bytes
+        };
+        // One column, red at the top and green at the bottom
+        let base_color = encode(2, &[255, 0, 0, 0, 255, 0]);
+        // Tilted 30 degrees towards +x
+        let normal = encode(1, &[191, 128, 238]);
+
+        // glTF v runs from the top of the image at y = 1 to the bottom at y = -1
+        let positions = [-1.0f32, -1.0, 0.0, 1.0, -1.0, 0.0, 0.0, 1.0, 0.0];
+        let uvs = [0.0f32, 1.0, 1.0, 1.0, 0.5, 0.0];
+        let bytes: Vec<u8> = positions
+            .iter()
+            .chain(uvs.iter())
+            .flat_map(|f| f.to_le_bytes())
+            .collect();
+        let json = format!(
+            r#"{{
+            "asset": {{ "version": "2.0" }},
+            "scenes": [{{ "nodes": [0] }}],
+            "nodes": [{{ "mesh": 0 }}],
+            "meshes": [{{ "primitives": [{{
+                "attributes": {{ "POSITION": 0, "TEXCOORD_0": 1 }}, "material": 0
+            }}] }}],
+            "materials": [{{ "pbrMetallicRoughness": {{
+                "baseColorFactor": [0.5, 1, 1, 1], "metallicFactor": 0,
+                "baseColorTexture": {{ "index": 0 }}
+            }}, "normalTexture": {{ "index": 1, "scale": 0.5 }} }}],
+            "textures": [{{ "source": 0 }}, {{ "source": 1 }}],
+            "images": [
+                {{ "uri": "data:image/png;base64,{}" }},
+                {{ "uri": "data:image/png;base64,{}" }}
+            ],
+            "accessors": [
+                {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                   "min": [-1, -1, 0], "max": [1, 1, 0] }},
+                {{ "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC2" }}
+            ],
+            "bufferViews": [
+                {{ "buffer": 0, "byteLength": 36 }},
+                {{ "buffer": 0, "byteOffset": 36, "byteLength": 24 }}
+            ],
+            "buffers": [{{
+                "byteLength": 60,
+                "uri": "data:application/octet-stream;base64,{}"
+            }}]
+        }}"#,
+            base64(&base_color),
+            base64(&normal),
+            base64(&bytes)
+        );
+        let path = std::env::temp_dir().join("one_weekend_test_texture.gltf");
+        std::fs::write(&path, json).unwrap();
+        let mut scene = Scene::new();
+        load_gltf(&path, &mut scene, 1.0).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        scene.build_bvh();
+
+        let mut rng = RayRng::new(0);
+        for (y, expected) in [(0.5, Color::new(0.5, 0.0, 0.0)), (-0.5, Color::Y)] {
+            let query = RayQuery {
+                ray: Ray::new(Point3::new(0.0, y, 1.0), -Vec3::Z, 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let hit = scene.intersect(query).unwrap();
+            let scatter = hit.material.scatter(&mut rng, &query.ray, &hit).unwrap();
+            assert!((scatter.attenuation - expected).length() < 1e-5);
+            let shading_normal = hit.material.shading_normal(&query.ray, &hit).unwrap();
+            let expected = Vec3::new(0.5 * 0.5, 0.0, 0.75_f32.sqrt()).normalize();
+            assert!((shading_normal - expected).length() < 1e-2);
+        }
+    }
+
+    /// Indices which don't make whole triangles are an error rather than dropped
+    #[test]
+    fn test_gltf_partial_triangle() {
+        let positions = [-1.0f32, -1.0, 0.0, 1.0, -1.0, 0.0, 0.0, 1.0, 0.0];
+        let indices = [0u16, 1, 2, 0];
+        let bytes: Vec<u8> = positions
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .chain(indices.iter().flat_map(|i| i.to_le_bytes()))
+            .collect();
+        let json = format!(
+            r#"{{
+            "asset": {{ "version": "2.0" }},
+            "scenes": [{{ "nodes": [0] }}],
+            "nodes": [{{ "mesh": 0 }}],
+            "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "indices": 1 }}] }}],
+            "accessors": [
+                {{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                   "min": [-1, -1, 0], "max": [1, 1, 0] }},
+                {{ "bufferView": 1, "componentType": 5123, "count": 4, "type": "SCALAR" }}
+            ],
+            "bufferViews": [
+                {{ "buffer": 0, "byteLength": 36 }},
+                {{ "buffer": 0, "byteOffset": 36, "byteLength": 8 }}
+            ],
+            "buffers": [{{
+                "byteLength": 44,
+                "uri": "data:application/octet-stream;base64,{}"
+            }}]
+        }}"#,
+            base64(&bytes)
+        );
+        let path = std::env::temp_dir().join(format!(
+            "one_weekend_test_partial_{}.gltf",
+            std::process::id()
+        ));
+        std::fs::write(&path, json).unwrap();
+        let result = load_gltf(&path, &mut Scene::new(), 1.0);
+        std::fs::remove_file(&path).unwrap();
+        let Err(err) = result else {
+            panic!("partial triangle accepted");
+        };
+        assert!(err
+            .to_string()
+            .contains("4 indices do not make whole triangles"));
+    }
+}
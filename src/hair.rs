@@ -0,0 +1,326 @@
+use crate::material::*;
+use crate::object::*;
+use crate::shared::*;
+
+use std::f32::consts::PI;
+
+/// Number of explicit lobes: R, TT and TRT, higher orders are lumped together
+const P_MAX: usize = 3;
+
+/// Absorption coefficients of eumelanin and pheomelanin
+const EUMELANIN_SIGMA_A: Color = Color::new(0.419, 0.697, 1.37);
+const PHEOMELANIN_SIGMA_A: Color = Color::new(0.187, 0.4, 1.05);
+
+/// Modified Bessel function of the first kind
+fn bessel_i0(x: f32) -> f32 {
+    let mut value = 0.0;
+    let mut x2i = 1.0;
+    let mut factorial = 1.0;
+    let mut four_i = 1.0;
+    for i in 0..10 {
+        if i > 1 {
+            factorial *= i as f32;
+        }
+        value += x2i / (four_i * factorial * factorial);
+        x2i *= x * x;
+        four_i *= 4.0;
+    }
+    value
+}
+
+fn log_bessel_i0(x: f32) -> f32 {
+    if x > 12.0 {
+        x + 0.5 * (-f32::ln(2.0 * PI) + f32::ln(1.0 / x) + 1.0 / (8.0 * x))
+    } else {
+        bessel_i0(x).ln()
+    }
+}
+
+/// Longitudinal scattering function
+fn longitudinal(
+    cos_theta_i: f32,
+    cos_theta_o: f32,
+    sin_theta_i: f32,
+    sin_theta_o: f32,
+    v: f32,
+) -> f32 {
+    let a = cos_theta_i * cos_theta_o / v;
+    let b = sin_theta_i * sin_theta_o / v;
+    if v <= 0.1 {
+        // Log space to avoid overflow for smooth fibers
+        f32::exp(log_bessel_i0(a) - b - 1.0 / v + std::f32::consts::LN_2 + f32::ln(1.0 / (2.0 * v)))
+    } else {
+        f32::exp(-b) * bessel_i0(a) / (f32::sinh(1.0 / v) * 2.0 * v)
+    }
+}
+
+fn logistic(x: f32, s: f32) -> f32 {
+    let x = x.abs();
+    f32::exp(-x / s) / (s * (1.0 + f32::exp(-x / s)).powi(2))
+}
+
+fn logistic_cdf(x: f32, s: f32) -> f32 {
+    1.0 / (1.0 + f32::exp(-x / s))
+}
+
+fn trimmed_logistic(x: f32, s: f32) -> f32 {
+    logistic(x, s) / (logistic_cdf(PI, s) - logistic_cdf(-PI, s))
+}
+
+fn sample_trimmed_logistic(u: f32, s: f32) -> f32 {
+    let k = logistic_cdf(PI, s) - logistic_cdf(-PI, s);
+    let x = -s * f32::ln(1.0 / (u * k + logistic_cdf(-PI, s)) - 1.0);
+    x.clamp(-PI, PI)
+}
+
+/// Azimuthal exit angle of lobe p
+fn exit_azimuth(p: usize, gamma_o: f32, gamma_t: f32) -> f32 {
+    2.0 * p as f32 * gamma_t - 2.0 * gamma_o + p as f32 * PI
+}
+
+/// Azimuthal scattering function
+fn azimuthal(phi: f32, p: usize, s: f32, gamma_o: f32, gamma_t: f32) -> f32 {
+    let mut dphi = phi - exit_azimuth(p, gamma_o, gamma_t);
+    while dphi > PI {
+        dphi -= 2.0 * PI;
+    }
+    while dphi < -PI {
+        dphi += 2.0 * PI;
+    }
+    trimmed_logistic(dphi, s)
+}
+
+/// Hair fiber scattering after Chiang et al. 2016, with R, TT and TRT lobes and a residual term.
+/// Expects hits with the fiber direction in dpdu and the offset across the fiber in uv.y,
+/// as produced by Curve.
+pub struct HairMaterial {
+    // Absorption inside the fiber per unit of diameter
+    pub sigma_a: Color,
+    // Longitudinal and azimuthal roughness in 0..1
+    pub beta_m: f32,
+    pub beta_n: f32,
+    // Tilt of the cuticle scales in degrees
+    pub alpha: f32,
+    pub eta: f32,
+}
+
+/// Lobe variances and scale tilt terms for one set of parameters
+struct HairLobes {
+    v: [f32; P_MAX + 1],
+    s: f32,
+    sin_2k_alpha: [f32; 3],
+    cos_2k_alpha: [f32; 3],
+}
+
+impl HairMaterial {
+    pub fn new(sigma_a: Color, beta_m: f32, beta_n: f32, alpha: f32) -> Self {
+        HairMaterial {
+            sigma_a,
+            beta_m,
+            beta_n,
+            alpha,
+            eta: 1.55,
+        }
+    }
+
+    /// Absorption from eumelanin (brown to black) and pheomelanin (red) concentrations
+    pub fn from_melanin(eumelanin: f32, pheomelanin: f32, beta_m: f32, beta_n: f32) -> Self {
+        let sigma_a = eumelanin * EUMELANIN_SIGMA_A + pheomelanin * PHEOMELANIN_SIGMA_A;
+        HairMaterial::new(sigma_a, beta_m, beta_n, 2.0)
+    }
+
+    /// Absorption which gives roughly the requested multiple scattering color
+    pub fn from_color(color: Color, beta_m: f32, beta_n: f32) -> Self {
+        let b = beta_n;
+        let denom = 5.969 - 0.215 * b + 2.532 * b.powi(2) - 10.73 * b.powi(3)
+            + 5.574 * b.powi(4)
+            + 0.245 * b.powi(5);
+        let sigma_a = color
+            .max(Color::splat(1e-4))
+            .map(|c| (c.ln() / denom).powi(2));
+        HairMaterial::new(sigma_a, beta_m, beta_n, 2.0)
+    }
+
+    fn lobes(&self) -> HairLobes {
+        let beta_m = self.beta_m;
+        let v0 = (0.726 * beta_m + 0.812 * beta_m.powi(2) + 3.7 * beta_m.powi(20)).powi(2);
+        let beta_n = self.beta_n;
+        let s = f32::sqrt(PI / 8.0)
+            * (0.265 * beta_n + 1.194 * beta_n.powi(2) + 5.372 * beta_n.powi(22));
+
+        let mut sin_2k_alpha = [degrees_to_radians(self.alpha).sin(), 0.0, 0.0];
+        let mut cos_2k_alpha = [f32::sqrt(1.0 - sin_2k_alpha[0].powi(2)), 0.0, 0.0];
+        for i in 1..3 {
+            sin_2k_alpha[i] = 2.0 * cos_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+            cos_2k_alpha[i] = cos_2k_alpha[i - 1].powi(2) - sin_2k_alpha[i - 1].powi(2);
+        }
+
+        HairLobes {
+            v: [v0, 0.25 * v0, 4.0 * v0, 4.0 * v0],
+            s,
+            sin_2k_alpha,
+            cos_2k_alpha,
+        }
+    }
+
+    /// Attenuation of each lobe for light entering at offset h
+    fn attenuation(&self, cos_theta_o: f32, h: f32, transmittance: Color) -> [Color; P_MAX + 1] {
+        let cos_gamma_o = f32::sqrt((1.0 - h * h).max(0.0));
+        let f = fresnel_dielectric(cos_theta_o * cos_gamma_o, self.eta);
+        let mut ap = [Color::ZERO; P_MAX + 1];
+        ap[0] = Color::splat(f);
+        ap[1] = (1.0 - f).powi(2) * transmittance;
+        for p in 2..P_MAX {
+            ap[p] = ap[p - 1] * transmittance * f;
+        }
+        ap[P_MAX] = ap[P_MAX - 1] * f * transmittance / (Color::ONE - transmittance * f);
+        ap
+    }
+
+    /// Refracted angles inside the fiber and the transmittance of one pass through it
+    fn refraction(&self, sin_theta_o: f32, cos_theta_o: f32, h: f32) -> (f32, Color) {
+        let sin_theta_t = sin_theta_o / self.eta;
+        let cos_theta_t = f32::sqrt((1.0 - sin_theta_t * sin_theta_t).max(0.0));
+        let etap = f32::sqrt(self.eta * self.eta - sin_theta_o * sin_theta_o) / cos_theta_o;
+        let sin_gamma_t = (h / etap).clamp(-1.0, 1.0);
+        let cos_gamma_t = f32::sqrt(1.0 - sin_gamma_t * sin_gamma_t);
+        let transmittance = (-self.sigma_a * (2.0 * cos_gamma_t / cos_theta_t)).exp();
+        (sin_gamma_t.asin(), transmittance)
+    }
+
+    /// Outgoing elevation rotated by the scale tilt for lobe p
+    fn tilted(lobes: &HairLobes, p: usize, sin_theta_o: f32, cos_theta_o: f32) -> (f32, f32) {
+        let (sin_a, cos_a) = match p {
+            0 => (-lobes.sin_2k_alpha[1], lobes.cos_2k_alpha[1]),
+            1 => (lobes.sin_2k_alpha[0], lobes.cos_2k_alpha[0]),
+            2 => (lobes.sin_2k_alpha[2], lobes.cos_2k_alpha[2]),
+            _ => (0.0, 1.0),
+        };
+        let sin_theta_op = sin_theta_o * cos_a + cos_theta_o * sin_a;
+        let cos_theta_op = cos_theta_o * cos_a - sin_theta_o * sin_a;
+        (sin_theta_op, cos_theta_op.abs())
+    }
+
+    /// Sample an incident direction in the local fiber frame, with x along the fiber and z
+    /// facing the outgoing direction. Returns the direction and the weight f * cos / pdf.
+    fn sample(&self, rng: &mut RayRng, wo: Vec3, h: f32) -> Option<(Vec3, Color)> {
+        let lobes = self.lobes();
+        let sin_theta_o = wo.x;
+        let cos_theta_o = f32::sqrt((1.0 - sin_theta_o * sin_theta_o).max(0.0));
+        let phi_o = wo.z.atan2(wo.y);
+        let gamma_o = h.asin();
+        let (gamma_t, transmittance) = self.refraction(sin_theta_o, cos_theta_o, h);
+        let ap = self.attenuation(cos_theta_o, h, transmittance);
+
+        // Choose a lobe by its share of the attenuation
+        let luminance = |c: Color| c.dot(Color::new(0.2126, 0.7152, 0.0722));
+        let ap_sum: f32 = ap.iter().map(|a| luminance(*a)).sum();
+        if ap_sum <= 0.0 {
+            return None;
+        }
+        let ap_pdf = ap.map(|a| luminance(a) / ap_sum);
+        let mut u = rng.gen_range(0.0..1.0);
+        let mut p = 0;
+        while p < P_MAX && u >= ap_pdf[p] {
+            u -= ap_pdf[p];
+            p += 1;
+        }
+
+        // Sample the longitudinal lobe around the tilted mirror direction
+        let (sin_theta_op, cos_theta_op) = Self::tilted(&lobes, p, sin_theta_o, cos_theta_o);
+        let u = rng.gen_range(0.0..1.0).max(1e-5);
+        let v = lobes.v[p];
+        let cos_theta = 1.0 + v * f32::ln(u + (1.0 - u) * f32::exp(-2.0 / v));
+        let sin_theta = f32::sqrt((1.0 - cos_theta * cos_theta).max(0.0));
+        let cos_phi = f32::cos(2.0 * PI * rng.gen_range(0.0..1.0));
+        let sin_theta_i = -cos_theta * sin_theta_op + sin_theta * cos_phi * cos_theta_op;
+        let cos_theta_i = f32::sqrt((1.0 - sin_theta_i * sin_theta_i).max(0.0));
+
+        // Sample the azimuthal lobe
+        let dphi = if p < P_MAX {
+            exit_azimuth(p, gamma_o, gamma_t)
+                + sample_trimmed_logistic(rng.gen_range(0.0..1.0), lobes.s)
+        } else {
+            rng.gen_range(0.0..2.0 * PI)
+        };
+        let phi_i = phi_o + dphi;
+        let wi = Vec3::new(
+            sin_theta_i,
+            cos_theta_i * phi_i.cos(),
+            cos_theta_i * phi_i.sin(),
+        );
+
+        // Evaluate the scattering function and pdf of all lobes for this direction
+        let mut f_cos = Color::ZERO;
+        let mut pdf = 0.0;
+        for p in 0..P_MAX {
+            let (sin_theta_op, cos_theta_op) = Self::tilted(&lobes, p, sin_theta_o, cos_theta_o);
+            let mp = longitudinal(
+                cos_theta_i,
+                cos_theta_op,
+                sin_theta_i,
+                sin_theta_op,
+                lobes.v[p],
+            );
+            let np = azimuthal(dphi, p, lobes.s, gamma_o, gamma_t);
+            f_cos += mp * np * ap[p];
+            pdf += mp * np * ap_pdf[p];
+        }
+        let mp = longitudinal(
+            cos_theta_i,
+            cos_theta_o,
+            sin_theta_i,
+            sin_theta_o,
+            lobes.v[P_MAX],
+        );
+        f_cos += mp * ap[P_MAX] / (2.0 * PI);
+        pdf += mp * ap_pdf[P_MAX] / (2.0 * PI);
+
+        (pdf > 0.0).then(|| (wi, f_cos / pdf))
+    }
+}
+
+impl Material for HairMaterial {
+    fn scatter(&self, rng: &mut RayRng, ray: &Ray, hit: &HitRecord) -> Option<ScatterResult> {
+        // Frame with the fiber along x and z facing the incoming ray
+        let wo_world = -ray.direction.normalize();
+        let x = hit.dpdu.normalize_or(hit.normal.any_orthonormal_vector());
+        let z = (wo_world - wo_world.dot(x) * x).normalize_or(hit.normal);
+        let y = z.cross(x);
+        let wo = Vec3::new(wo_world.dot(x), wo_world.dot(y), wo_world.dot(z));
+        let h = (2.0 * hit.uv.y - 1.0).clamp(-1.0, 1.0);
+
+        let (wi, attenuation) = self.sample(rng, wo, h)?;
+        let direction = wi.x * x + wi.y * y + wi.z * z;
+        Some(ScatterResult {
+            attenuation,
+            scattered_ray: Ray::new(hit.point, direction, ray.time),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Without absorption the fiber should neither gain nor lose energy
+    #[test]
+    fn test_hair_white_furnace() {
+        let mut rng = RayRng::new(0);
+        for (beta_m, beta_n) in [(0.2, 0.3), (0.5, 0.5), (0.8, 0.9)] {
+            let hair = HairMaterial::new(Color::ZERO, beta_m, beta_n, 2.0);
+            let count = 20000;
+            let mut total = 0.0;
+            for _ in 0..count {
+                let wo = random_unit_vector(&mut rng);
+                let wo = Vec3::new(wo.x, 0.0, wo.y.hypot(wo.z)).normalize();
+                let h = rng.gen_range(-1.0..1.0);
+                if let Some((_, weight)) = hair.sample(&mut rng, wo, h) {
+                    total += weight.y;
+                }
+            }
+            let average = total / count as f32;
+            assert!((average - 1.0).abs() < 0.05, "average {}", average);
+        }
+    }
+}
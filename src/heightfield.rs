@@ -0,0 +1,263 @@
+use crate::image::*;
+use crate::material::*;
+use crate::object::*;
+use crate::shared::*;
+
+use std::path::Path;
+
+/// Terrain from a regular grid of heights, traversed cell by cell along the ray.
+/// Each cell is split into two triangles with interpolated vertex normals.
+pub struct Heightfield {
+    // Heights in 0..1, row major with width samples along x
+    heights: Vec<f32>,
+    normals: Vec<Vec3>,
+    width: usize,
+    depth: usize,
+    // World space corner and extent, heights are scaled by size.y
+    pub min: Point3,
+    pub size: Vec3,
+    pub material: Arc<dyn Material>,
+}
+
+impl Heightfield {
+    pub fn new(
+        heights: Vec<f32>,
+        width: usize,
+        depth: usize,
+        min: Point3,
+        size: Vec3,
+        material: &Arc<dyn Material>,
+    ) -> Self {
+        assert!(width >= 2 && depth >= 2);
+        assert_eq!(heights.len(), width * depth);
+        let mut heightfield = Heightfield {
+            heights,
+            normals: Vec::new(),
+            width,
+            depth,
+            min,
+            size,
+            material: material.clone(),
+        };
+        heightfield.normals = (0..depth)
+            .flat_map(|z| (0..width).map(move |x| (x, z)))
+            .map(|(x, z)| heightfield.vertex_normal(x, z))
+            .collect();
+        heightfield
+    }
+
+    /// Load heights from an 8 or 16 bit grayscale PNG, black is min.y and white is min.y + size.y
+    pub fn load(
+        path: &Path,
+        min: Point3,
+        size: Vec3,
+        material: &Arc<dyn Material>,
+    ) -> Result<Self, ImageError> {
+        let image = Image::load_grayscale_png(path)?;
+        let (width, depth) = (image.width, image.height);
+        if width < 2 || depth < 2 {
+            return Err(ImageError::Format(
+                path.to_path_buf(),
+                format!("image too small: {}x{}", width, depth),
+            ));
+        }
+        Ok(Heightfield::new(
+            image.channel(0),
+            width,
+            depth,
+            min,
+            size,
+            material,
+        ))
+    }
+
+    fn cell_size(&self) -> Vec2 {
+        Vec2::new(
+            self.size.x / (self.width - 1) as f32,
+            self.size.z / (self.depth - 1) as f32,
+        )
+    }
+
+    fn vertex(&self, x: usize, z: usize) -> Point3 {
+        let cell = self.cell_size();
+        self.min
+            + Vec3::new(
+                x as f32 * cell.x,
+                self.heights[z * self.width + x] * self.size.y,
+                z as f32 * cell.y,
+            )
+    }
+
+    /// Normal from central differences of the neighbouring heights
+    fn vertex_normal(&self, x: usize, z: usize) -> Vec3 {
+        let (x0, x1) = (x.saturating_sub(1), (x + 1).min(self.width - 1));
+        let (z0, z1) = (z.saturating_sub(1), (z + 1).min(self.depth - 1));
+        let dx = self.vertex(x1, z) - self.vertex(x0, z);
+        let dz = self.vertex(x, z1) - self.vertex(x, z0);
+        dz.cross(dx).normalize_or(Vec3::Y)
+    }
+
+    /// Parametric range of the ray inside the bounding box
+    fn clip(&self, query: &RayQuery) -> Option<(f32, f32)> {
+        let inv_direction = query.ray.direction.recip();
+        let t0 = (self.min - query.ray.origin) * inv_direction;
+        let t1 = (self.min + self.size - query.ray.origin) * inv_direction;
+        let t_enter = t0.min(t1).max_element().max(query.t_min);
+        let t_exit = t0.max(t1).min_element().min(query.t_max);
+        (t_enter <= t_exit).then_some((t_enter, t_exit))
+    }
+
+    /// Closest hit with the two triangles of a cell
+    fn intersect_cell(&self, x: usize, z: usize, mut query: RayQuery) -> Option<HitRecord> {
+        let corners = [(x, z), (x + 1, z), (x + 1, z + 1), (x, z + 1)];
+        let mut closest_hit_option = None;
+        for (k, [a, b, c]) in [[0, 3, 2], [0, 2, 1]].into_iter().enumerate() {
+            let (ia, ib, ic) = (corners[a], corners[b], corners[c]);
+            let (p0, p1, p2) = (
+                self.vertex(ia.0, ia.1),
+                self.vertex(ib.0, ib.1),
+                self.vertex(ic.0, ic.1),
+            );
+            let Some((t, bary)) = intersect_triangle(query, p0, p1, p2) else {
+                continue;
+            };
+            query.t_max = t;
+
+            let geometric_normal = (p1 - p0).cross(p2 - p0).normalize();
+            let mut record = HitRecord::new(query.ray, t, geometric_normal, self.material.clone());
+            let normal_at = |(x, z): (usize, usize)| self.normals[z * self.width + x];
+            let n = bary.x * normal_at(ia) + bary.y * normal_at(ib) + bary.z * normal_at(ic);
+            record.shading_normal = if record.front_face {
+                n.normalize()
+            } else {
+                -n.normalize()
+            };
+            record.primitive_id = (2 * (z * (self.width - 1) + x) + k) as u32;
+
+            // UVs span the grid, the derivatives follow the slope of the triangle
+            record.uv = Vec2::new(
+                (record.point.x - self.min.x) / self.size.x,
+                (record.point.z - self.min.z) / self.size.z,
+            );
+            let slope = -geometric_normal / geometric_normal.y;
+            record.dpdu = self.size.x * Vec3::new(1.0, slope.x, 0.0);
+            record.dpdv = self.size.z * Vec3::new(0.0, slope.z, 1.0);
+            closest_hit_option = Some(record);
+        }
+        closest_hit_option
+    }
+}
+
+impl RayHittable for Heightfield {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let (t_enter, t_exit) = self.clip(&query)?;
+        let r = query.ray;
+        let cell = self.cell_size();
+
+        // Starting cell in grid coordinates
+        let start = r.at(t_enter) - self.min;
+        let mut x = ((start.x / cell.x) as isize).clamp(0, self.width as isize - 2);
+        let mut z = ((start.z / cell.y) as isize).clamp(0, self.depth as isize - 2);
+
+        // 2D DDA over the cells, as in Amanatides and Woo
+        let step_x = if r.direction.x < 0.0 { -1 } else { 1 };
+        let step_z = if r.direction.z < 0.0 { -1 } else { 1 };
+        let t_delta_x = (cell.x / r.direction.x).abs();
+        let t_delta_z = (cell.y / r.direction.z).abs();
+        let boundary_x = self.min.x + (x + (step_x > 0) as isize) as f32 * cell.x;
+        let boundary_z = self.min.z + (z + (step_z > 0) as isize) as f32 * cell.y;
+        let mut t_next_x = if r.direction.x != 0.0 {
+            (boundary_x - r.origin.x) / r.direction.x
+        } else {
+            TRACE_INFINITY
+        };
+        let mut t_next_z = if r.direction.z != 0.0 {
+            (boundary_z - r.origin.z) / r.direction.z
+        } else {
+            TRACE_INFINITY
+        };
+
+        let mut t = t_enter;
+        loop {
+            let t_cell_exit = t_next_x.min(t_next_z).min(t_exit);
+
+            // Skip cells where the ray stays above all four corners
+            let (xu, zu) = (x as usize, z as usize);
+            let cell_max = self.heights[zu * self.width + xu]
+                .max(self.heights[zu * self.width + xu + 1])
+                .max(self.heights[(zu + 1) * self.width + xu])
+                .max(self.heights[(zu + 1) * self.width + xu + 1]);
+            let ray_min = r.at(t).y.min(r.at(t_cell_exit).y);
+            if ray_min <= self.min.y + cell_max * self.size.y {
+                if let Some(hit) = self.intersect_cell(xu, zu, query) {
+                    return Some(hit);
+                }
+            }
+
+            if t_cell_exit >= t_exit {
+                return None;
+            }
+            if t_next_x < t_next_z {
+                x += step_x;
+                t = t_next_x;
+                t_next_x += t_delta_x;
+            } else {
+                z += step_z;
+                t = t_next_z;
+                t_next_z += t_delta_z;
+            }
+            if x < 0 || z < 0 || x > self.width as isize - 2 || z > self.depth as isize - 2 {
+                return None;
+            }
+        }
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        HittableBounds::new(self.min, self.min + self.size, hittable_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    /// Heights loaded from a 16 bit PNG should be hit at the right elevation
+    #[test]
+    fn test_heightfield_png() {
+        let path = std::env::temp_dir().join("one_weekend_test_heightfield.png");
+        {
+            let file = File::create(&path).unwrap();
+            let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), 3, 3);
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder.set_depth(png::BitDepth::Sixteen);
+            let heights: [u16; 9] = [0, 0, 0, 0, 65535, 0, 0, 0, 0];
+            let data: Vec<u8> = heights.iter().flat_map(|h| h.to_be_bytes()).collect();
+            encoder
+                .write_header()
+                .unwrap()
+                .write_image_data(&data)
+                .unwrap();
+        }
+
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let size = Vec3::new(2.0, 1.0, 2.0);
+        let heightfield = Heightfield::load(&path, Point3::ZERO, size, &material).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Straight down onto the peak in the middle and slanted onto the flat corner
+        for (origin, expected) in [
+            (Point3::new(1.0, 5.0, 1.0), Point3::new(1.0, 1.0, 1.0)),
+            (Point3::new(-1.0, 2.0, 3.0), Point3::new(0.25, 0.0, 1.5)),
+        ] {
+            let query = RayQuery {
+                ray: Ray::new(origin, expected - origin, 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let hit = heightfield.intersect(query).unwrap();
+            assert!((hit.point - expected).length() < 1e-4);
+            assert!(hit.front_face);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// Error while loading an image
+#[derive(Debug)]
+pub enum ImageError {
+    Io(PathBuf, std::io::Error),
+    Decode(PathBuf, png::DecodingError),
+    Format(PathBuf, String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            ImageError::Decode(path, err) => write!(f, "{}: {}", path.display(), err),
+            ImageError::Format(path, message) => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// Decoded image with 8 or 16 bits per sample
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    // Interleaved samples per pixel, one for gray up to four for RGBA
+    pub channels: usize,
+    // Bits per sample, 8 or 16
+    pub bit_depth: usize,
+    // Row major from the top row, 16 bit samples are big endian
+    data: Vec<u8>,
+}
+
+impl Image {
+    /// Wrap samples decoded elsewhere
+    pub fn new(
+        width: usize,
+        height: usize,
+        channels: usize,
+        bit_depth: usize,
+        data: Vec<u8>,
+    ) -> Self {
+        assert!(width > 0 && height > 0 && (1..=4).contains(&channels));
+        assert!(bit_depth == 8 || bit_depth == 16);
+        assert_eq!(data.len(), width * height * channels * bit_depth / 8);
+        Image {
+            width,
+            height,
+            channels,
+            bit_depth,
+            data,
+        }
+    }
+
+    /// Load an 8 or 16 bit PNG, palettes and lower bit depths are expanded to 8 bits
+    pub fn load_png(path: &Path) -> Result<Self, ImageError> {
+        let file = File::open(path).map_err(|e| ImageError::Io(path.to_path_buf(), e))?;
+        let mut decoder = png::Decoder::new(BufReader::new(file));
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder
+            .read_info()
+            .map_err(|e| ImageError::Decode(path.to_path_buf(), e))?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buffer)
+            .map_err(|e| ImageError::Decode(path.to_path_buf(), e))?;
+
+        let format_error = |message: String| ImageError::Format(path.to_path_buf(), message);
+        let bit_depth = match info.bit_depth {
+            png::BitDepth::Eight => 8,
+            png::BitDepth::Sixteen => 16,
+            other => return Err(format_error(format!("unsupported bit depth {:?}", other))),
+        };
+        if info.width == 0 || info.height == 0 {
+            return Err(format_error(String::from("empty image")));
+        }
+        buffer.truncate(info.buffer_size());
+        Ok(Image::new(
+            info.width as usize,
+            info.height as usize,
+            info.color_type.samples(),
+            bit_depth,
+            buffer,
+        ))
+    }
+
+    /// Load a PNG which has to be grayscale, such as a height map
+    pub fn load_grayscale_png(path: &Path) -> Result<Self, ImageError> {
+        let image = Image::load_png(path)?;
+        if image.channels > 2 {
+            return Err(ImageError::Format(
+                path.to_path_buf(),
+                format!("expected grayscale, got {} channels", image.channels),
+            ));
+        }
+        Ok(image)
+    }
+
+    /// Sample in 0..1 of one channel of the pixel at index
+    pub fn sample(&self, index: usize, channel: usize) -> f32 {
+        let i = index * self.channels + channel;
+        if self.bit_depth == 8 {
+            self.data[i] as f32 / 255.0
+        } else {
+            u16::from_be_bytes([self.data[2 * i], self.data[2 * i + 1]]) as f32 / 65535.0
+        }
+    }
+
+    /// One channel of every pixel in 0..1, row major from the top row
+    pub fn channel(&self, channel: usize) -> Vec<f32> {
+        (0..self.width * self.height)
+            .map(|index| self.sample(index, channel))
+            .collect()
+    }
+}
@@ -1,9 +1,25 @@
 mod camera;
+mod csg;
+mod curve;
+mod flat_bvh;
+mod gltf_loader;
+mod hair;
+mod heightfield;
+mod image;
 mod material;
+mod microfacet;
+mod noise;
+mod normal_map;
+mod obj_loader;
 mod object;
+mod ply_loader;
 mod render;
 mod scene;
+mod sdf;
 mod shared;
+mod subdivision;
+mod texture;
+mod volume;
 
 use std::fs::File;
 use std::io::BufWriter;
@@ -11,10 +27,21 @@ use std::path::Path;
 use std::thread;
 
 use camera::*;
+use csg::*;
+use curve::*;
+use hair::*;
+use heightfield::*;
 use material::*;
+use microfacet::*;
+use noise::*;
+use normal_map::*;
 use object::*;
 use scene::*;
+use sdf::*;
 use shared::*;
+use subdivision::*;
+use texture::*;
+use volume::*;
 
 use crossbeam_channel::unbounded;
 
@@ -26,9 +53,110 @@ const SAMPLES_PER_PIXEL: u32 = 256;
 
 /// Generate the ray tracing in one weekend scene
 fn one_weekend_scene() -> Scene {
+    random_spheres_scene(false)
+}
+
+/// Generate the Cornell box from the next week, lit only by the ceiling light
+fn cornell_box_scene() -> Scene {
+    let mut scene = Scene::new();
+    scene.background = Background::Solid(Color::ZERO);
+
+    let red: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.65, 0.05, 0.05)));
+    let white: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.73, 0.73, 0.73)));
+    let green: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.12, 0.45, 0.15)));
+    let light: Arc<dyn Material> = Arc::new(DiffuseLight::new(Color::new(15.0, 15.0, 15.0)));
+
+    // Walls facing into the box, the light faces down
+    let walls = [
+        (
+            Point3::new(555.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 555.0),
+            Vec3::new(0.0, 555.0, 0.0),
+            &green,
+        ),
+        (
+            Point3::ZERO,
+            Vec3::new(0.0, 555.0, 0.0),
+            Vec3::new(0.0, 0.0, 555.0),
+            &red,
+        ),
+        (
+            Point3::ZERO,
+            Vec3::new(0.0, 0.0, 555.0),
+            Vec3::new(555.0, 0.0, 0.0),
+            &white,
+        ),
+        (
+            Point3::new(555.0, 555.0, 555.0),
+            Vec3::new(-555.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -555.0),
+            &white,
+        ),
+        (
+            Point3::new(0.0, 0.0, 555.0),
+            Vec3::new(0.0, 555.0, 0.0),
+            Vec3::new(555.0, 0.0, 0.0),
+            &white,
+        ),
+        (
+            Point3::new(343.0, 554.0, 332.0),
+            Vec3::new(-130.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -105.0),
+            &light,
+        ),
+    ];
+    for (q, u, v, material) in walls {
+        scene.objects.push(Box::new(Quad::new(q, u, v, material)));
+    }
+
+    // Two boxes turned around their corner
+    let boxes = [
+        (
+            Point3::new(165.0, 330.0, 165.0),
+            15.0,
+            Vec3::new(265.0, 0.0, 295.0),
+        ),
+        (
+            Point3::new(165.0, 165.0, 165.0),
+            -18.0,
+            Vec3::new(130.0, 0.0, 65.0),
+        ),
+    ];
+    for (size, angle, offset) in boxes {
+        let cuboid: Arc<dyn RayHittable> = Arc::new(AaBox::new(Point3::ZERO, size, &white));
+        let rotation = Quat::from_rotation_y(degrees_to_radians(angle));
+        scene.add_instance(cuboid, Mat4::from_rotation_translation(rotation, offset));
+    }
+
+    scene
+}
+
+/// Generate the bouncing spheres scene from the next week, rendered with shutter 0..1
+fn bouncing_spheres_scene() -> Scene {
+    random_spheres_scene(true)
+}
+
+/// Generate the random spheres scene, optionally with diffuse spheres bouncing up
+fn random_spheres_scene(bouncing: bool) -> Scene {
     let mut rng = RayRng::new(0);
     let mut scene = Scene::new();
 
+    // The next week puts the bouncing spheres on a checkered floor
+    let ground_material: Arc<dyn Material> = if bouncing {
+        Arc::new(Lambertian::textured(Arc::new(CheckerTexture::new(
+            0.32,
+            Color::new(0.2, 0.3, 0.1),
+            Color::new(0.9, 0.9, 0.9),
+        ))))
+    } else {
+        Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)))
+    };
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &ground_material,
+    )));
+
     let mut spheres: Vec<(Point3, f32)> = Vec::new();
     let mut add_sphere =
         |spheres: &mut Vec<(Point3, f32)>, c: Point3, r: f32, mat: &Arc<dyn Material>| {
@@ -36,32 +164,19 @@ fn one_weekend_scene() -> Scene {
             spheres.push((c, r));
         };
 
+    let mut moving_spheres: Vec<Box<dyn RayHittable>> = Vec::new();
+
     let sphere_intersects = |spheres: &Vec<(Point3, f32)>, c: Point3, r: f32| {
         spheres.iter().any(|s| (s.0 - c).length() < (s.1 + r))
     };
 
-    let ground_material: Arc<dyn Material> = Arc::new(Lambertian {
-        albedo: Color::new(0.5, 0.5, 0.5),
-    });
-    add_sphere(
-        &mut spheres,
-        Point3::new(0.0, -1000.0, -1.0),
-        1000.0,
-        &ground_material,
-    );
-
     let material1: Arc<dyn Material> = Arc::new(Dielectric { ir: 1.5 });
     add_sphere(&mut spheres, Point3::new(0.0, 1.0, 0.0), 1.0, &material1);
 
-    let material2: Arc<dyn Material> = Arc::new(Lambertian {
-        albedo: Color::new(0.4, 0.2, 0.1),
-    });
+    let material2: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.4, 0.2, 0.1)));
     add_sphere(&mut spheres, Point3::new(-4.0, 1.0, 0.0), 1.0, &material2);
 
-    let material3: Arc<dyn Material> = Arc::new(Metal {
-        albedo: Color::new(0.7, 0.6, 0.5),
-        fuzz: 0.0,
-    });
+    let material3: Arc<dyn Material> = Arc::new(Metal::new(Color::new(0.7, 0.6, 0.5), 0.0));
     add_sphere(&mut spheres, Point3::new(4.0, 1.0, 0.0), 1.0, &material3);
 
     for a in -11..11 {
@@ -84,13 +199,26 @@ fn one_weekend_scene() -> Scene {
                 if choose_mat < 0.7 {
                     // diffuse
                     let albedo = color_random(&mut rng);
-                    let sphere_material: Arc<dyn Material> = Arc::new(Lambertian { albedo });
-                    add_sphere(&mut spheres, center, 0.2, &sphere_material);
+                    let sphere_material: Arc<dyn Material> = Arc::new(Lambertian::new(albedo));
+                    if bouncing {
+                        let center1 = center + Vec3::new(0.0, rng.gen_range(0.0..0.5), 0.0);
+                        moving_spheres.push(Box::new(MovingSphere::new(
+                            center,
+                            center1,
+                            0.0,
+                            1.0,
+                            0.2,
+                            &sphere_material,
+                        )));
+                        spheres.push((center, 0.2));
+                    } else {
+                        add_sphere(&mut spheres, center, 0.2, &sphere_material);
+                    }
                 } else if choose_mat < 0.95 {
                     // metal
                     let albedo = color_random_range(&mut rng, 0.5..1.0);
                     let fuzz = rng.gen_range(0.0..0.5);
-                    let sphere_material: Arc<dyn Material> = Arc::new(Metal { albedo, fuzz });
+                    let sphere_material: Arc<dyn Material> = Arc::new(Metal::new(albedo, fuzz));
                     add_sphere(&mut spheres, center, 0.2, &sphere_material);
                 } else {
                     // glass
@@ -101,46 +229,751 @@ fn one_weekend_scene() -> Scene {
         }
     }
 
+    scene.objects.append(&mut moving_spheres);
     scene
 }
 
-struct BufferPacket {
-    pixels: Vec<(u32, u32, ColorDisplay)>,
+/// Generate a scene showing the different primitives on a ground plane
+fn shapes_scene() -> Scene {
+    let mut scene = Scene::new();
+
+    let ground_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &ground_material,
+    )));
+
+    let red: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.7, 0.1, 0.1)));
+    let green: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.1, 0.6, 0.2)));
+    let blue: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.1, 0.2, 0.7)));
+    let metal: Arc<dyn Material> = Arc::new(Metal::new(Color::new(0.8, 0.8, 0.8), 0.05));
+
+    scene.objects.push(Box::new(AaBox::new(
+        Point3::new(-3.5, 0.0, -0.5),
+        Point3::new(-2.5, 1.0, 0.5),
+        &red,
+    )));
+    scene.objects.push(Box::new(Quad::new(
+        Point3::new(-1.5, 0.0, 0.0),
+        Vec3::new(1.0, 0.0, -0.3),
+        Vec3::new(0.0, 1.5, 0.0),
+        &green,
+    )));
+    scene.objects.push(Box::new(Disk::new(
+        Point3::new(1.0, 0.75, 0.0),
+        Vec3::new(0.3, 0.2, 1.0),
+        0.7,
+        &blue,
+    )));
+    scene.objects.push(Box::new(Sphere::new(
+        Point3::new(3.0, 0.75, 0.0),
+        0.75,
+        &metal,
+    )));
+
+    // Quadrics in the second row
+    let glass: Arc<dyn Material> = Arc::new(Dielectric { ir: 1.5 });
+    let gold: Arc<dyn Material> = Arc::new(Metal::new(Color::new(0.9, 0.7, 0.3), 0.2));
+    scene.objects.push(Box::new(Cylinder::new(
+        Point3::new(-3.0, 0.0, -3.0),
+        Point3::new(-3.0, 1.5, -3.0),
+        0.5,
+        true,
+        &blue,
+    )));
+    scene.objects.push(Box::new(Cone::new(
+        Point3::new(-1.0, 0.0, -3.0),
+        0.6,
+        Point3::new(-1.0, 1.5, -3.0),
+        0.0,
+        true,
+        &red,
+    )));
+    scene.objects.push(Box::new(Capsule::new(
+        Point3::new(0.6, 0.4, -3.0),
+        Point3::new(1.6, 1.2, -3.4),
+        0.4,
+        &glass,
+    )));
+    scene.objects.push(Box::new(Torus::new(
+        Point3::new(3.0, 0.75, -3.0),
+        Vec3::new(0.0, 0.5, 1.0),
+        0.55,
+        0.2,
+        &gold,
+    )));
+
+    // Constructive solid geometry in the third row
+    let lens = Csg::new(
+        CsgOperation::Intersection,
+        Box::new(Sphere::new(Point3::new(-2.5, 0.9, -6.0), 1.0, &glass)),
+        Box::new(Sphere::new(Point3::new(-2.5, 0.9, -7.4), 1.0, &glass)),
+    );
+    scene.objects.push(Box::new(lens));
+
+    let cut_away = Csg::new(
+        CsgOperation::Difference,
+        Box::new(Sphere::new(Point3::new(0.0, 0.8, -6.5), 0.8, &red)),
+        Box::new(Csg::new(
+            CsgOperation::Union,
+            Box::new(AaBox::new(
+                Point3::new(0.0, 0.8, -6.5),
+                Point3::new(1.0, 2.0, -5.0),
+                &gold,
+            )),
+            Box::new(Cylinder::new(
+                Point3::new(-1.0, 0.8, -6.5),
+                Point3::new(0.0, 0.8, -6.5),
+                0.3,
+                true,
+                &gold,
+            )),
+        )),
+    );
+    scene.objects.push(Box::new(cut_away));
+
+    let shell = Csg::new(
+        CsgOperation::Difference,
+        Box::new(Csg::new(
+            CsgOperation::Difference,
+            Box::new(Sphere::new(Point3::new(2.5, 0.8, -6.5), 0.8, &blue)),
+            Box::new(Sphere::new(Point3::new(2.5, 0.8, -6.5), 0.7, &green)),
+        )),
+        Box::new(AaBox::new(
+            Point3::new(1.5, 1.0, -7.5),
+            Point3::new(3.5, 2.0, -5.5),
+            &green,
+        )),
+    );
+    scene.objects.push(Box::new(shell));
+
+    scene
 }
 
-fn main() {
-    let mut window = Window::new(
-        "Ray tracing in one weekend - ESC to exit",
-        WIDTH,
-        HEIGHT,
-        WindowOptions::default(),
+/// Generate a scene of signed distance field objects on a ground plane
+fn sdf_scene() -> Scene {
+    let mut scene = Scene::new();
+
+    let ground_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &ground_material,
+    )));
+
+    // Sphere melting into a torus
+    let blob_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.7, 0.2, 0.1)));
+    let blob = Sdf::sphere(0.6)
+        .translate(Vec3::new(0.0, 0.9, 0.0))
+        .smooth_union(
+            Sdf::torus(0.8, 0.2).translate(Vec3::new(0.0, 0.2, 0.0)),
+            0.3,
+        );
+    scene.objects.push(Box::new(SdfObject::new(
+        blob.translate(Vec3::new(-3.0, 0.0, 0.0)),
+        Point3::new(-4.1, 0.0, -1.1),
+        Point3::new(-1.9, 1.6, 1.1),
+        &blob_material,
+    )));
+
+    // Twisted column on a plinth
+    let column_material: Arc<dyn Material> = Arc::new(Metal::new(Color::new(0.9, 0.7, 0.3), 0.1));
+    let column = Sdf::cuboid(Vec3::new(0.4, 1.0, 0.4))
+        .twist(1.2)
+        .union(Sdf::cuboid(Vec3::new(0.55, 0.1, 0.55)).translate(Vec3::new(0.0, -0.9, 0.0)));
+    scene.objects.push(Box::new(SdfObject::new(
+        column.translate(Vec3::new(-1.0, 1.0, 0.0)),
+        Point3::new(-1.6, 0.0, -0.6),
+        Point3::new(-0.4, 2.0, 0.6),
+        &column_material,
+    )));
+
+    // Grid of small spheres from a single repeated one
+    let grid_material: Arc<dyn Material> = Arc::new(Dielectric { ir: 1.5 });
+    let grid = Sdf::sphere(0.15).repeat(Vec3::new(0.5, 0.0, 0.5));
+    scene.objects.push(Box::new(SdfObject::new(
+        grid.translate(Vec3::new(1.0, 0.15, 0.0)),
+        Point3::new(0.0, 0.0, -1.0),
+        Point3::new(2.0, 0.3, 1.0),
+        &grid_material,
+    )));
+
+    // Mandelbulb fractal
+    let fractal_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.2, 0.4, 0.7)));
+    scene.objects.push(Box::new(SdfObject::new(
+        Sdf::mandelbulb(8.0, 12).translate(Vec3::new(3.5, 1.2, 0.0)),
+        Point3::new(2.3, 0.0, -1.2),
+        Point3::new(4.7, 2.4, 1.2),
+        &fractal_material,
+    )));
+
+    scene
+}
+
+/// Generate rolling hills from a procedural heightfield with a lake
+fn terrain_scene() -> Scene {
+    let mut scene = Scene::new();
+
+    let resolution = 512;
+    let heights = (0..resolution * resolution)
+        .map(|i| {
+            let x = (i % resolution) as f32 / resolution as f32 * 6.0;
+            let z = (i / resolution) as f32 / resolution as f32 * 6.0;
+            let hills = (x * 1.3).sin() * (z * 0.9).cos() + 0.5 * (x * 0.7 + z * 1.9).sin();
+            let detail = 0.1 * (x * 7.0).sin() * (z * 5.0).sin();
+            (0.5 + 0.3 * hills + detail).clamp(0.0, 1.0)
+        })
+        .collect();
+    let ground_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.4, 0.5, 0.3)));
+    scene.objects.push(Box::new(Heightfield::new(
+        heights,
+        resolution,
+        resolution,
+        Point3::new(-10.0, 0.0, -10.0),
+        Vec3::new(20.0, 3.0, 20.0),
+        &ground_material,
+    )));
+
+    let water_material: Arc<dyn Material> = Arc::new(Metal::new(Color::new(0.5, 0.6, 0.7), 0.02));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::new(0.0, 0.9, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        &water_material,
+    )));
+
+    scene
+}
+
+/// Build a torus around the y axis as a triangle mesh with smooth normals
+fn torus_mesh(
+    major_radius: f32,
+    minor_radius: f32,
+    segments: u32,
+    sides: u32,
+    material: &Arc<dyn Material>,
+) -> TriangleMesh {
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    for i in 0..segments {
+        let u = i as f32 / segments as f32;
+        let (sin_u, cos_u) = (u * 2.0 * std::f32::consts::PI).sin_cos();
+        for j in 0..sides {
+            let v = j as f32 / sides as f32;
+            let (sin_v, cos_v) = (v * 2.0 * std::f32::consts::PI).sin_cos();
+            let normal = Vec3::new(cos_u * cos_v, sin_v, sin_u * cos_v);
+            let ring = Point3::new(cos_u * major_radius, 0.0, sin_u * major_radius);
+            positions.push(ring + minor_radius * normal);
+            normals.push(normal);
+            uvs.push(Vec2::new(u, v));
+        }
+    }
+
+    let vertex = |i: u32, j: u32| (i % segments) * sides + j % sides;
+    let mut indices = Vec::new();
+    for i in 0..segments {
+        for j in 0..sides {
+            let (a, b) = (vertex(i, j), vertex(i + 1, j));
+            let (c, d) = (vertex(i + 1, j + 1), vertex(i, j + 1));
+            indices.push([a, d, c]);
+            indices.push([a, c, b]);
+        }
+    }
+    TriangleMesh::new(positions, normals, uvs, indices, material)
+}
+
+/// Generate a field of instances sharing one torus mesh
+fn instances_scene() -> Scene {
+    let mut rng = RayRng::new(0);
+    let mut scene = Scene::new();
+
+    let ground_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &ground_material,
+    )));
+
+    let material: Arc<dyn Material> = Arc::new(Metal::new(Color::new(0.9, 0.6, 0.3), 0.1));
+    let torus: Arc<dyn RayHittable> = Arc::new(torus_mesh(0.3, 0.1, 64, 32, &material));
+    for a in -20..20 {
+        for b in -20..20 {
+            let scale = rng.gen_range(0.5..1.2);
+            let rotation = Quat::from_euler(
+                EulerRot::XYZ,
+                rng.gen_range(0.0..std::f32::consts::PI),
+                rng.gen_range(0.0..std::f32::consts::PI),
+                0.0,
+            );
+            let position = Point3::new(a as f32, 0.4 * scale, b as f32);
+            scene.add_instance(
+                torus.clone(),
+                Mat4::from_scale_rotation_translation(Vec3::splat(scale), rotation, position),
+            );
+        }
+    }
+
+    scene
+}
+
+/// Generate a scene with fog and smoke volumes around solid objects
+fn fog_scene() -> Scene {
+    let mut scene = Scene::new();
+
+    let ground_material: Arc<dyn Material> =
+        Arc::new(Lambertian::new(Color::new(0.48, 0.83, 0.53)));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &ground_material,
+    )));
+
+    // Unused boundary material, the medium replaces it
+    let boundary_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+
+    // Dark smoke box
+    scene.objects.push(Box::new(ConstantMedium::new(
+        Box::new(AaBox::new(
+            Point3::new(-3.0, 0.0, -1.0),
+            Point3::new(-1.0, 2.5, 1.0),
+            &boundary_material,
+        )),
+        1.5,
+        Color::new(0.1, 0.1, 0.1),
+        PhaseFunction::Isotropic,
+    )));
+
+    // Forward scattering fog sphere
+    scene.objects.push(Box::new(ConstantMedium::new(
+        Box::new(Sphere::new(
+            Point3::new(1.5, 1.2, 0.0),
+            1.2,
+            &boundary_material,
+        )),
+        0.8,
+        Color::new(0.9, 0.9, 0.95),
+        PhaseFunction::HenyeyGreenstein(0.6),
+    )));
+
+    // Solid spheres outside the volumes, media don't nest
+    let glass: Arc<dyn Material> = Arc::new(Dielectric { ir: 1.5 });
+    scene.objects.push(Box::new(Sphere::new(
+        Point3::new(0.0, 0.5, 2.5),
+        0.5,
+        &glass,
+    )));
+    let metal: Arc<dyn Material> = Arc::new(Metal::new(Color::new(0.8, 0.8, 0.9), 0.0));
+    scene.objects.push(Box::new(Sphere::new(
+        Point3::new(4.0, 1.0, -1.0),
+        1.0,
+        &metal,
+    )));
+
+    scene
+}
+
+/// Generate a cloud from a procedural density field above a ground plane
+fn cloud_scene() -> Scene {
+    let mut scene = Scene::new();
+
+    let ground_material: Arc<dyn Material> =
+        Arc::new(Lambertian::new(Color::new(0.48, 0.83, 0.53)));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &ground_material,
+    )));
+
+    // Overlapping puffs with some sine detail, fading out towards their edges
+    let puffs = [
+        (Point3::new(-1.6, 2.0, 0.0), 1.2),
+        (Point3::new(0.0, 2.4, 0.2), 1.5),
+        (Point3::new(1.6, 2.0, -0.2), 1.1),
+        (Point3::new(0.6, 1.9, 0.9), 1.0),
+    ];
+    let density = |p: Point3| {
+        let coverage: f32 = puffs
+            .iter()
+            .map(|(center, radius)| (1.0 - (p - *center).length() / radius).max(0.0))
+            .sum();
+        let detail = 0.25 * (3.1 * p.x).sin() * (4.3 * p.y + 1.0).sin() * (3.7 * p.z).sin();
+        12.0 * (coverage + detail - 0.15).max(0.0)
+    };
+    let (min, max) = (Point3::new(-3.0, 0.8, -1.8), Point3::new(3.0, 4.0, 1.8));
+    let resolution = [96, 48, 64];
+    let cloud = GridMedium::from_fn(
+        min,
+        max,
+        resolution,
+        density,
+        Color::ONE,
+        PhaseFunction::HenyeyGreenstein(0.5),
+    );
+
+    // Darker and warmer towards the bottom of the cloud
+    let albedo = (0..resolution[0] * resolution[1] * resolution[2])
+        .map(|i| {
+            let height = (i / resolution[0] % resolution[1]) as f32 / resolution[1] as f32;
+            Color::new(0.9, 0.88, 0.85).lerp(Color::new(0.99, 0.99, 1.0), height)
+        })
+        .collect();
+    let cloud = cloud.with_albedo_grid(albedo);
+
+    // Unused boundary material, the medium replaces it
+    let boundary_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+    scene.objects.push(Box::new(ConstantMedium::with_medium(
+        Box::new(AaBox::new(min, max, &boundary_material)),
+        Arc::new(cloud),
+    )));
+
+    scene
+}
+
+/// Furry ball of hair strands on a patch of ribbon grass
+fn hair_scene() -> Scene {
+    let mut rng = RayRng::new(0);
+    let mut scene = Scene::new();
+
+    let ground_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.4, 0.3, 0.2)));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &ground_material,
+    )));
+
+    // Strands start on the ball and bend down under gravity
+    let center = Point3::new(0.0, 1.0, 0.0);
+    let radius = 0.7;
+    let ball_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.15, 0.08, 0.04)));
+    scene
+        .objects
+        .push(Box::new(Sphere::new(center, radius, &ball_material)));
+    // Mostly auburn strands from melanin with some streaks of a requested color
+    let auburn: Arc<dyn Material> = Arc::new(HairMaterial::from_melanin(0.3, 0.8, 0.25, 0.3));
+    let blond: Arc<dyn Material> = Arc::new(HairMaterial::from_color(
+        Color::new(0.8, 0.6, 0.3),
+        0.25,
+        0.3,
+    ));
+    for i in 0..4000 {
+        let hair_material = if i % 5 == 0 { &blond } else { &auburn };
+        let normal = random_unit_vector(&mut rng);
+        let root = center + radius * normal;
+        let length = rng.gen_range(0.25..0.4);
+        let droop = Vec3::new(0.0, -0.5 * length, 0.0);
+        let control_points = [
+            root,
+            root + length / 3.0 * normal,
+            root + 2.0 * length / 3.0 * normal + 0.5 * droop,
+            root + length * normal + droop,
+        ];
+        scene.objects.push(Box::new(Curve::new(
+            control_points,
+            0.01,
+            0.002,
+            CurveType::Tube,
+            hair_material,
+        )));
+    }
+
+    // Blades of grass as flat ribbons leaning in random directions
+    let grass_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.2, 0.5, 0.1)));
+    for _ in 0..3000 {
+        let root = Point3::new(rng.gen_range(-3.0..3.0), 0.0, rng.gen_range(-2.0..2.0));
+        if (root - Point3::new(center.x, 0.0, center.z)).length() < 0.5 {
+            continue;
+        }
+        let height = rng.gen_range(0.2..0.5);
+        let lean = Vec3::new(rng.gen_range(-0.2..0.2), 0.0, rng.gen_range(-0.2..0.2));
+        let control_points = [
+            root,
+            root + Vec3::new(0.0, height / 3.0, 0.0),
+            root + Vec3::new(0.0, 2.0 * height / 3.0, 0.0) + 0.5 * lean,
+            root + Vec3::new(0.0, height, 0.0) + lean,
+        ];
+        scene.objects.push(Box::new(Curve::new(
+            control_points,
+            0.03,
+            0.0,
+            CurveType::Ribbon,
+            &grass_material,
+        )));
+    }
+
+    scene
+}
+
+/// Compare a low poly torus with its subdivided and displaced versions
+fn subdivision_scene() -> Scene {
+    let mut scene = Scene::new();
+
+    let ground_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &ground_material,
+    )));
+
+    let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.8, 0.4, 0.2)));
+    let coarse = torus_mesh(1.0, 0.45, 8, 5, &material);
+    let smooth = MeshRefinement {
+        subdivision_levels: 3,
+        ..Default::default()
+    };
+    let ripples = MeshRefinement {
+        subdivision_levels: 4,
+        displacement: Some(Displacement::function(|p, _| {
+            (9.0 * p.x).sin() * (9.0 * p.y).sin() * (9.0 * p.z).sin()
+        })),
+        displacement_scale: 0.04,
+    };
+    let smooth: Arc<dyn RayHittable> = Arc::new(smooth.apply(&coarse));
+    let ripples: Arc<dyn RayHittable> = Arc::new(ripples.apply(&coarse));
+    let coarse: Arc<dyn RayHittable> = Arc::new(coarse);
+
+    // Stand the tori up facing the camera, from left to right the original, the subdivided
+    // and the displaced one
+    let rotation = Quat::from_rotation_x(1.2);
+    for (mesh, x) in [(coarse, -3.2), (smooth, 0.0), (ripples, 3.2)] {
+        scene.add_instance(
+            mesh,
+            Mat4::from_rotation_translation(rotation, Point3::new(x, 1.5, 0.0)),
+        );
+    }
+
+    scene
+}
+
+/// Procedural noise textures on a row of spheres, like the Perlin spheres of the next week
+fn noise_scene() -> Scene {
+    let mut scene = Scene::new();
+    let mut rng = RayRng::new(0);
+    let perlin = Arc::new(Perlin::new(&mut rng));
+    let worley = Arc::new(Worley::new(&mut rng));
+
+    // Stone slabs which need no image, with the joints bumped down
+    let joints = |low: Color, high: Color| -> Arc<dyn Texture> {
+        Arc::new(WorleyTexture {
+            worley: worley.clone(),
+            scale: 0.8,
+            pattern: WorleyPattern::Edges,
+            low,
+            high,
+        })
+    };
+    let stone: Arc<dyn Material> = Arc::new(Lambertian::textured(joints(
+        Color::new(0.1, 0.1, 0.1),
+        Color::new(0.55, 0.5, 0.45),
+    )));
+    let ground_material: Arc<dyn Material> = Arc::new(NormalMapped::new(
+        stone,
+        NormalMap::Bump {
+            height: Arc::new(ChannelAverage {
+                texture: joints(Color::ZERO, Color::ONE),
+            }),
+            scale: 0.03,
+        },
+    ));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &ground_material,
+    )));
+
+    let white = Color::new(0.9, 0.9, 0.9);
+    let textures: [Arc<dyn Texture>; 4] = [
+        Arc::new(NoiseTexture {
+            perlin: perlin.clone(),
+            scale: 4.0,
+            low: Color::ZERO,
+            high: white,
+        }),
+        Arc::new(TurbulenceTexture {
+            perlin: perlin.clone(),
+            scale: 2.0,
+            octaves: 7,
+            low: Color::new(0.2, 0.1, 0.5),
+            high: Color::new(0.9, 0.8, 0.3),
+        }),
+        Arc::new(MarbleTexture::new(&perlin, 4.0, Color::ZERO, white)),
+        Arc::new(WoodTexture::new(
+            &perlin,
+            2.5,
+            Color::new(0.6, 0.4, 0.2),
+            Color::new(0.3, 0.15, 0.05),
+        )),
+    ];
+    for (i, texture) in textures.into_iter().enumerate() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::textured(texture));
+        let center = Point3::new(3.2 * i as f32 - 4.8, 1.4, 0.0);
+        scene
+            .objects
+            .push(Box::new(Sphere::new(center, 1.4, &material)));
+    }
+
+    // Worley distance as a diffuse albedo and Voronoi cells tinting a metal, polished at the
+    // cell centers and fuzzier towards the edges
+    let bubbles: Arc<dyn Material> = Arc::new(Lambertian::textured(Arc::new(WorleyTexture {
+        worley: worley.clone(),
+        scale: 3.0,
+        pattern: WorleyPattern::Distance,
+        low: Color::new(0.05, 0.2, 0.4),
+        high: Color::new(0.8, 0.9, 0.9),
+    })));
+    let cells = Metal::textured(
+        Arc::new(WorleyTexture {
+            worley: worley.clone(),
+            scale: 3.0,
+            pattern: WorleyPattern::Cells,
+            low: Color::new(0.8, 0.5, 0.3),
+            high: Color::new(0.7, 0.7, 0.8),
+        }),
+        0.1,
     )
-    .unwrap_or_else(|e| {
-        panic!("{}", e);
-    });
+    .with_fuzz(Arc::new(ChannelAverage {
+        texture: Arc::new(WorleyTexture {
+            worley,
+            scale: 3.0,
+            pattern: WorleyPattern::Distance,
+            low: Color::splat(0.02),
+            high: Color::splat(0.3),
+        }),
+    }));
+    let cells: Arc<dyn Material> = Arc::new(cells);
+    for (x, material) in [(-3.2, bubbles), (3.2, cells)] {
+        scene.objects.push(Box::new(Sphere::new(
+            Point3::new(x, 0.8, 4.0),
+            0.8,
+            &material,
+        )));
+    }
 
-    // Limit to max ~30 fps update rate
-    window.set_target_fps(30);
+    scene
+}
 
-    // Create render buffer which holds all useful structs for rendering
-    let mut buffer_display: Vec<ColorDisplay> = vec![0; WIDTH * HEIGHT];
+/// Measured conductors with GGX roughness, smooth in front and rough at the back, where
+/// turbulence varies the roughness
+fn metals_scene() -> Scene {
+    let mut scene = Scene::new();
+    let mut rng = RayRng::new(0);
+    let perlin = Arc::new(Perlin::new(&mut rng));
 
-    // Create the scene
-    let mut scene = one_weekend_scene();
+    let ground_material: Arc<dyn Material> = Arc::new(Lambertian::textured(Arc::new(
+        CheckerTexture::new(1.0, Color::new(0.1, 0.1, 0.1), Color::new(0.8, 0.8, 0.8)),
+    )));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &ground_material,
+    )));
 
-    // Build the BVH
-    scene.build_bvh();
+    let presets = [
+        ConductorPreset::Gold,
+        ConductorPreset::Silver,
+        ConductorPreset::Copper,
+        ConductorPreset::Aluminium,
+        ConductorPreset::Iron,
+    ];
+    for (i, preset) in presets.into_iter().enumerate() {
+        let x = 2.4 * i as f32 - 4.8;
+        let smooth = Conductor::new(preset, Ggx::from_fuzz(0.1));
+        let rough =
+            Conductor::new(preset, Ggx::from_fuzz(0.6)).with_roughness(Arc::new(ChannelAverage {
+                texture: Arc::new(TurbulenceTexture {
+                    perlin: perlin.clone(),
+                    scale: 3.0,
+                    octaves: 5,
+                    low: Color::splat(0.35),
+                    high: Color::splat(0.75),
+                }),
+            }));
+        for (z, material) in [(1.5, smooth), (-1.5, rough)] {
+            let material: Arc<dyn Material> = Arc::new(material);
+            scene.objects.push(Box::new(Sphere::new(
+                Point3::new(x, 1.0, z),
+                1.0,
+                &material,
+            )));
+        }
+    }
 
-    // Set up the camera
-    let aspect_ratio = (WIDTH as f32) / (HEIGHT as f32);
+    scene
+}
+
+/// Smooth and frosted glass in front of a checkered wall, rougher to the right
+fn glass_scene() -> Scene {
+    let mut scene = Scene::new();
+
+    let checker: Arc<dyn Material> = Arc::new(Lambertian::textured(Arc::new(CheckerTexture::new(
+        0.5,
+        Color::new(0.1, 0.1, 0.1),
+        Color::new(0.8, 0.8, 0.8),
+    ))));
+    scene.objects.push(Box::new(Plane::new(
+        Point3::ZERO,
+        Vec3::new(0.0, 1.0, 0.0),
+        &checker,
+    )));
+    scene.objects.push(Box::new(Quad::new(
+        Point3::new(-8.0, 0.0, -3.0),
+        Vec3::new(16.0, 0.0, 0.0),
+        Vec3::new(0.0, 6.0, 0.0),
+        &checker,
+    )));
+
+    let smooth: Arc<dyn Material> = Arc::new(Dielectric { ir: 1.5 });
+    let frosted = |roughness: f32| -> Arc<dyn Material> {
+        Arc::new(RoughDielectric::new(1.5, Ggx::from_roughness(roughness)))
+    };
+    for (x, material) in [(-3.3, smooth), (0.0, frosted(0.2)), (3.3, frosted(0.5))] {
+        scene.objects.push(Box::new(Sphere::new(
+            Point3::new(x, 1.4, 0.0),
+            1.4,
+            &material,
+        )));
+    }
+
+    // Unevenly sandblasted acrylic slab
+    let mut rng = RayRng::new(0);
+    let acrylic: Arc<dyn Material> = Arc::new(
+        RoughDielectric::new(1.49, Ggx::from_roughness(0.35)).with_roughness(Arc::new(
+            ChannelAverage {
+                texture: Arc::new(NoiseTexture {
+                    perlin: Arc::new(Perlin::new(&mut rng)),
+                    scale: 2.0,
+                    low: Color::splat(0.15),
+                    high: Color::splat(0.55),
+                }),
+            },
+        )),
+    );
+    scene.objects.push(Box::new(AaBox::new(
+        Point3::new(-2.0, 0.0, 2.2),
+        Point3::new(2.0, 0.8, 2.5),
+        &acrylic,
+    )));
+
+    scene
+}
+
+/// Camera looking into the open side of the Cornell box
+fn cornell_box_camera(aspect_ratio: f32) -> Camera {
+    let lookfrom = Point3::new(278.0, 278.0, -800.0);
+    let lookat = Point3::new(278.0, 278.0, 0.0);
+    let vup = Vec3::new(0.0, 1.0, 0.0);
+    Camera::new(lookfrom, lookat, vup, 40.0, aspect_ratio, 0.0, 800.0)
+}
 
+/// Camera for the one weekend scene
+fn one_weekend_camera(aspect_ratio: f32) -> Camera {
     let lookfrom = Point3::new(13.0, 2.0, 3.0);
     let lookat = Point3::new(0.0, 0.0, 0.0);
     let vup = Vec3::new(0.0, 1.0, 0.0);
     let dist_to_focus = 10.0;
     let aperture = 0.1;
 
-    let cam = Camera::new(
+    Camera::new(
         lookfrom,
         lookat,
         vup,
@@ -148,14 +981,359 @@ fn main() {
         aspect_ratio,
         aperture,
         dist_to_focus,
-    );
+    )
+}
+
+/// Camera which fits the whole scene in view, for loaded models
+fn framing_camera(scene: &Scene, aspect_ratio: f32) -> Camera {
+    let (min, max) = scene
+        .bounding_box()
+        .unwrap_or((Point3::splat(-1.0), Point3::splat(1.0)));
+    let center = 0.5 * (min + max);
+    let radius = 0.5 * (max - min).length();
+
+    let vfov = 40.0;
+    let distance = radius / f32::sin(degrees_to_radians(vfov / 2.0));
+    let lookfrom = center + distance * Vec3::new(1.0, 0.5, 1.0).normalize();
+    let vup = Vec3::new(0.0, 1.0, 0.0);
+
+    Camera::new(lookfrom, center, vup, vfov, aspect_ratio, 0.0, distance)
+}
+
+/// Create a scene with its camera from a preset name or a model file.
+/// Meshes loaded from model files are refined first.
+fn create_scene(name: &str, aspect_ratio: f32, refinement: &MeshRefinement) -> (Scene, Camera) {
+    let path = Path::new(name);
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+
+    match extension.as_deref() {
+        Some("obj") => {
+            let mut scene = Scene::new();
+            scene.refinement = refinement.clone();
+            obj_loader::load_obj(path, &mut scene).unwrap_or_else(|e| {
+                panic!("{}", e);
+            });
+            scene.build_bvh();
+            let camera = framing_camera(&scene, aspect_ratio);
+            (scene, camera)
+        }
+        Some("ply") => {
+            let mut scene = Scene::new();
+            scene.refinement = refinement.clone();
+            ply_loader::load_ply(path, &mut scene, None).unwrap_or_else(|e| {
+                panic!("{}", e);
+            });
+            scene.build_bvh();
+            let camera = framing_camera(&scene, aspect_ratio);
+            (scene, camera)
+        }
+        Some("png") => {
+            let mut scene = Scene::new();
+            let ground_material: Arc<dyn Material> =
+                Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+            let heightfield = Heightfield::load(
+                path,
+                Point3::new(-10.0, 0.0, -10.0),
+                Vec3::new(20.0, 3.0, 20.0),
+                &ground_material,
+            )
+            .unwrap_or_else(|e| {
+                panic!("{}", e);
+            });
+            scene.objects.push(Box::new(heightfield));
+            scene.build_bvh();
+            let camera = framing_camera(&scene, aspect_ratio);
+            (scene, camera)
+        }
+        Some("vol") => {
+            let mut scene = Scene::new();
+            let ground_material: Arc<dyn Material> =
+                Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+            let medium =
+                GridMedium::load_vol(path, 1.0, Color::splat(0.9), PhaseFunction::Isotropic)
+                    .unwrap_or_else(|e| {
+                        panic!("{}", e);
+                    });
+            scene.objects.push(Box::new(Plane::new(
+                medium.min,
+                Vec3::new(0.0, 1.0, 0.0),
+                &ground_material,
+            )));
+            let boundary_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+            scene.objects.push(Box::new(ConstantMedium::with_medium(
+                Box::new(AaBox::new(medium.min, medium.max, &boundary_material)),
+                Arc::new(medium),
+            )));
+            scene.build_bvh();
+            let camera = framing_camera(&scene, aspect_ratio);
+            (scene, camera)
+        }
+        Some("gltf") | Some("glb") => {
+            let mut scene = Scene::new();
+            scene.refinement = refinement.clone();
+            let camera =
+                gltf_loader::load_gltf(path, &mut scene, aspect_ratio).unwrap_or_else(|e| {
+                    panic!("{}", e);
+                });
+            scene.build_bvh();
+            let camera = camera.unwrap_or_else(|| framing_camera(&scene, aspect_ratio));
+            (scene, camera)
+        }
+        _ => match name {
+            "weekend" => {
+                let mut scene = one_weekend_scene();
+                scene.build_bvh();
+                (scene, one_weekend_camera(aspect_ratio))
+            }
+            "cornell" => {
+                let mut scene = cornell_box_scene();
+                scene.build_bvh();
+                (scene, cornell_box_camera(aspect_ratio))
+            }
+            "shapes" => {
+                let mut scene = shapes_scene();
+                scene.build_bvh();
+                let camera = framing_camera(&scene, aspect_ratio);
+                (scene, camera)
+            }
+            "sdf" => {
+                let mut scene = sdf_scene();
+                scene.build_bvh();
+                let camera = framing_camera(&scene, aspect_ratio);
+                (scene, camera)
+            }
+            "terrain" => {
+                let mut scene = terrain_scene();
+                scene.build_bvh();
+                let lookfrom = Point3::new(0.0, 6.0, 14.0);
+                let lookat = Point3::new(0.0, 1.0, 0.0);
+                let vup = Vec3::new(0.0, 1.0, 0.0);
+                let camera = Camera::new(lookfrom, lookat, vup, 50.0, aspect_ratio, 0.0, 14.0);
+                (scene, camera)
+            }
+            "instances" => {
+                let mut scene = instances_scene();
+                scene.build_bvh();
+                let lookfrom = Point3::new(6.0, 3.0, 8.0);
+                let lookat = Point3::new(0.0, 0.0, 0.0);
+                let vup = Vec3::new(0.0, 1.0, 0.0);
+                let camera = Camera::new(lookfrom, lookat, vup, 40.0, aspect_ratio, 0.0, 10.0);
+                (scene, camera)
+            }
+            "cloud" => {
+                let mut scene = cloud_scene();
+                scene.build_bvh();
+                let lookfrom = Point3::new(0.0, 2.0, 12.0);
+                let lookat = Point3::new(0.0, 2.2, 0.0);
+                let vup = Vec3::new(0.0, 1.0, 0.0);
+                let camera = Camera::new(lookfrom, lookat, vup, 35.0, aspect_ratio, 0.0, 12.0);
+                (scene, camera)
+            }
+            "fog" => {
+                let mut scene = fog_scene();
+                scene.build_bvh();
+                let lookfrom = Point3::new(0.0, 3.0, 12.0);
+                let lookat = Point3::new(0.0, 1.0, 0.0);
+                let vup = Vec3::new(0.0, 1.0, 0.0);
+                let camera = Camera::new(lookfrom, lookat, vup, 30.0, aspect_ratio, 0.0, 10.0);
+                (scene, camera)
+            }
+            "hair" => {
+                let mut scene = hair_scene();
+                scene.build_bvh();
+                let lookfrom = Point3::new(0.0, 1.6, 5.0);
+                let lookat = Point3::new(0.0, 0.8, 0.0);
+                let vup = Vec3::new(0.0, 1.0, 0.0);
+                let camera = Camera::new(lookfrom, lookat, vup, 35.0, aspect_ratio, 0.0, 5.0);
+                (scene, camera)
+            }
+            "subdivision" => {
+                let mut scene = subdivision_scene();
+                scene.build_bvh();
+                let lookfrom = Point3::new(0.0, 3.0, 12.0);
+                let lookat = Point3::new(0.0, 1.2, 0.0);
+                let vup = Vec3::new(0.0, 1.0, 0.0);
+                let camera = Camera::new(lookfrom, lookat, vup, 35.0, aspect_ratio, 0.0, 12.0);
+                (scene, camera)
+            }
+            "noise" => {
+                let mut scene = noise_scene();
+                scene.build_bvh();
+                let lookfrom = Point3::new(0.0, 3.0, 16.0);
+                let lookat = Point3::new(0.0, 1.2, 0.0);
+                let vup = Vec3::new(0.0, 1.0, 0.0);
+                let camera = Camera::new(lookfrom, lookat, vup, 35.0, aspect_ratio, 0.0, 16.0);
+                (scene, camera)
+            }
+            "metals" => {
+                let mut scene = metals_scene();
+                scene.build_bvh();
+                let lookfrom = Point3::new(0.0, 5.0, 14.0);
+                let lookat = Point3::new(0.0, 0.8, 0.0);
+                let vup = Vec3::new(0.0, 1.0, 0.0);
+                let camera = Camera::new(lookfrom, lookat, vup, 35.0, aspect_ratio, 0.0, 14.0);
+                (scene, camera)
+            }
+            "glass" => {
+                let mut scene = glass_scene();
+                scene.build_bvh();
+                let lookfrom = Point3::new(0.0, 3.0, 13.0);
+                let lookat = Point3::new(0.0, 1.2, 0.0);
+                let vup = Vec3::new(0.0, 1.0, 0.0);
+                let camera = Camera::new(lookfrom, lookat, vup, 40.0, aspect_ratio, 0.0, 13.0);
+                (scene, camera)
+            }
+            "bouncing" => {
+                let mut scene = bouncing_spheres_scene();
+                scene.build_bvh();
+                let camera = one_weekend_camera(aspect_ratio).with_shutter(0.0, 1.0);
+                (scene, camera)
+            }
+            _ => panic!("Unknown scene '{}'", name),
+        },
+    }
+}
+
+/// Command line options: [--scene <preset or model file>] [--bvh <flat|crate>] [--bench]
+/// [--pass <beauty|object|primitive>] [--subdivide <levels>] [--displace <image.png> <scale>]
+/// [output.png]
+struct Options {
+    scene: String,
+    backend: BvhBackend,
+    bench: bool,
+    pass: render::RenderPass,
+    refinement: MeshRefinement,
+    output: Option<String>,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        scene: String::from("weekend"),
+        backend: BvhBackend::Flat,
+        bench: false,
+        pass: render::RenderPass::Beauty,
+        refinement: MeshRefinement::default(),
+        output: None,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scene" => {
+                options.scene = args.next().expect("--scene requires a value");
+            }
+            "--bvh" => {
+                options.backend = match args.next().as_deref() {
+                    Some("flat") => BvhBackend::Flat,
+                    Some("crate") => BvhBackend::BvhCrate,
+                    _ => panic!("--bvh requires flat or crate"),
+                };
+            }
+            "--bench" => options.bench = true,
+            "--pass" => {
+                options.pass = match args.next().as_deref() {
+                    Some("beauty") => render::RenderPass::Beauty,
+                    Some("object") => render::RenderPass::ObjectId,
+                    Some("primitive") => render::RenderPass::PrimitiveId,
+                    _ => panic!("--pass requires beauty, object or primitive"),
+                };
+            }
+            "--subdivide" => {
+                options.refinement.subdivision_levels = args
+                    .next()
+                    .and_then(|levels| levels.parse().ok())
+                    .expect("--subdivide requires a number of levels");
+            }
+            "--displace" => {
+                let path = args.next().expect("--displace requires an image");
+                let displacement = Displacement::load_image(Path::new(&path)).unwrap_or_else(|e| {
+                    panic!("{}", e);
+                });
+                options.refinement.displacement = Some(displacement);
+                options.refinement.displacement_scale = args
+                    .next()
+                    .and_then(|scale| scale.parse().ok())
+                    .expect("--displace requires a scale after the image");
+            }
+            _ => options.output = Some(arg),
+        }
+    }
+    options
+}
+
+/// Render the scene headless with each BVH backend and compare their speed
+fn run_benchmark(scene_name: &str, refinement: &MeshRefinement) {
+    let (width, height, spp) = (WIDTH as u32 / 4, HEIGHT as u32 / 4, 16);
+    let aspect_ratio = (width as f32) / (height as f32);
+
+    for backend in [BvhBackend::BvhCrate, BvhBackend::Flat] {
+        let (mut scene, cam) = create_scene(scene_name, aspect_ratio, refinement);
+        scene.backend = backend;
+        let time_start = std::time::Instant::now();
+        scene.build_bvh();
+        let build_time = time_start.elapsed();
+
+        // Moving an instance rebuilds only the top level, time it by moving one in place
+        let time_start = std::time::Instant::now();
+        if let Some(instance) = scene.instances.first() {
+            scene.set_instance_transform(0, instance.transform());
+        }
+        let move_time = time_start.elapsed();
+
+        let render_worker = render::Renderer::new(width, height, spp, scene, cam);
+        let (channel_send, _channel_receive) = unbounded();
+        let mrays_sec = render_worker.render_frame(channel_send);
+        println!(
+            "{:?}: build {}ms, instance move {}ms, {:.3} MRays/sec",
+            backend,
+            build_time.as_millis(),
+            move_time.as_millis(),
+            mrays_sec
+        );
+    }
+}
+
+struct BufferPacket {
+    pixels: Vec<(u32, u32, ColorDisplay)>,
+}
+
+fn main() {
+    let options = parse_args();
+    if options.bench {
+        run_benchmark(&options.scene, &options.refinement);
+        return;
+    }
+
+    let mut window = Window::new(
+        "Ray tracing in one weekend - ESC to exit",
+        WIDTH,
+        HEIGHT,
+        WindowOptions::default(),
+    )
+    .unwrap_or_else(|e| {
+        panic!("{}", e);
+    });
+
+    // Limit to max ~30 fps update rate
+    window.set_target_fps(30);
+
+    // Create render buffer which holds all useful structs for rendering
+    let mut buffer_display: Vec<ColorDisplay> = vec![0; WIDTH * HEIGHT];
+
+    // Create the scene with its BVH and camera
+    let aspect_ratio = (WIDTH as f32) / (HEIGHT as f32);
+    let (mut scene, cam) = create_scene(&options.scene, aspect_ratio, &options.refinement);
+    if scene.backend != options.backend {
+        scene.backend = options.backend;
+        scene.build_bvh();
+    }
 
     // Create channels
     let (channel_send, channel_receive) = unbounded();
 
     // Create renderer
     let render_worker =
-        render::Renderer::new(WIDTH as u32, HEIGHT as u32, SAMPLES_PER_PIXEL, scene, cam);
+        render::Renderer::new(WIDTH as u32, HEIGHT as u32, SAMPLES_PER_PIXEL, scene, cam)
+            .with_pass(options.pass);
 
     // Kick off renderer in a thread so we can use the main thread to update the window
     thread::spawn(move || {
@@ -179,10 +1357,9 @@ fn main() {
         }
     }
 
-    // If we get one argument, assume it's our output png filename
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 {
-        let path = Path::new(&args[1]);
+    // If we get an output png filename, save the image
+    if let Some(output) = &options.output {
+        let path = Path::new(output);
         let file = File::create(path).unwrap();
         let w = &mut BufWriter::new(file);
 
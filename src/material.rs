@@ -1,5 +1,7 @@
 use crate::object::*;
 use crate::shared::*;
+use crate::texture::*;
+use crate::volume::*;
 
 /// Result of Material::scatter
 pub struct ScatterResult {
@@ -10,42 +12,141 @@ pub struct ScatterResult {
 /// A material which can scatter rays
 pub trait Material: Send + Sync {
     fn scatter(&self, rng: &mut RayRng, ray: &Ray, hit: &HitRecord) -> Option<ScatterResult>;
+    // Light emitted from the surface towards the incoming ray
+    fn emitted(&self, _hit: &HitRecord) -> Color {
+        Color::ZERO
+    }
+    // Medium on the inside of the surface, entered through front faces
+    fn medium(&self) -> Option<&dyn Medium> {
+        None
+    }
+    // Replacement for the shading normal of the hit, applied by the renderer before scattering
+    fn shading_normal(&self, _ray: &Ray, _hit: &HitRecord) -> Option<Vec3> {
+        None
+    }
+}
+
+/// Cosine weighted direction around the shading normal
+fn diffuse_ray(rng: &mut RayRng, ray: &Ray, hit: &HitRecord) -> Ray {
+    let mut scatter_direction = (hit.shading_normal + random_unit_vector(rng)).normalize();
+    if scatter_direction.near_zero() {
+        scatter_direction = hit.shading_normal;
+    }
+    Ray::new(hit.point, scatter_direction, ray.time)
 }
 
 pub struct Lambertian {
-    pub albedo: Color,
+    pub albedo: Arc<dyn Texture>,
+}
+
+impl Lambertian {
+    pub fn new(albedo: Color) -> Self {
+        Lambertian::textured(Arc::new(SolidColor { color: albedo }))
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>) -> Self {
+        Lambertian { albedo }
+    }
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, rng: &mut RayRng, _ray: &Ray, hit: &HitRecord) -> Option<ScatterResult> {
-        let mut scatter_direction = (hit.normal + random_unit_vector(rng)).normalize();
-        if scatter_direction.near_zero() {
-            scatter_direction = hit.normal;
-        }
+    fn scatter(&self, rng: &mut RayRng, ray: &Ray, hit: &HitRecord) -> Option<ScatterResult> {
+        Some(ScatterResult {
+            attenuation: self.albedo.value(hit.uv, hit.point),
+            scattered_ray: diffuse_ray(rng, ray, hit),
+        })
+    }
+}
+
+/// Lambertian with its albedo tinted by the interpolated vertex color
+pub struct VertexColorLambertian {
+    pub albedo: Arc<dyn Texture>,
+}
 
-        let scattered = Ray::new(hit.point, scatter_direction);
+impl VertexColorLambertian {
+    pub fn new(albedo: Color) -> Self {
+        VertexColorLambertian::textured(Arc::new(SolidColor { color: albedo }))
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>) -> Self {
+        VertexColorLambertian { albedo }
+    }
+}
+
+impl Material for VertexColorLambertian {
+    fn scatter(&self, rng: &mut RayRng, ray: &Ray, hit: &HitRecord) -> Option<ScatterResult> {
         Some(ScatterResult {
-            attenuation: self.albedo,
-            scattered_ray: scattered,
+            attenuation: self.albedo.value(hit.uv, hit.point) * hit.vertex_color,
+            scattered_ray: diffuse_ray(rng, ray, hit),
         })
     }
 }
 
+/// Light source which emits from its front faces and absorbs everything
+pub struct DiffuseLight {
+    pub emit: Arc<dyn Texture>,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Color) -> Self {
+        DiffuseLight::textured(Arc::new(SolidColor { color: emit }))
+    }
+
+    pub fn textured(emit: Arc<dyn Texture>) -> Self {
+        DiffuseLight { emit }
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _rng: &mut RayRng, _ray: &Ray, _hit: &HitRecord) -> Option<ScatterResult> {
+        None
+    }
+
+    fn emitted(&self, hit: &HitRecord) -> Color {
+        if hit.front_face {
+            self.emit.value(hit.uv, hit.point)
+        } else {
+            Color::ZERO
+        }
+    }
+}
+
 pub struct Metal {
-    pub albedo: Color,
-    pub fuzz: f32,
+    pub albedo: Arc<dyn Texture>,
+    pub fuzz: Arc<dyn ScalarTexture>,
+}
+
+impl Metal {
+    pub fn new(albedo: Color, fuzz: f32) -> Self {
+        Metal::textured(Arc::new(SolidColor { color: albedo }), fuzz)
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>, fuzz: f32) -> Self {
+        Metal {
+            albedo,
+            fuzz: Arc::new(SolidScalar { value: fuzz }),
+        }
+    }
+
+    /// Fuzz which varies over the surface
+    pub fn with_fuzz(mut self, fuzz: Arc<dyn ScalarTexture>) -> Self {
+        self.fuzz = fuzz;
+        self
+    }
 }
 
 impl Material for Metal {
     fn scatter(&self, rng: &mut RayRng, ray: &Ray, hit: &HitRecord) -> Option<ScatterResult> {
-        let reflected = vec_reflect(ray.direction.normalize(), hit.normal);
+        let reflected = vec_reflect(ray.direction.normalize(), hit.shading_normal);
+        let fuzz = self.fuzz.value(hit.uv, hit.point);
 
         let scattered = Ray::new(
             hit.point,
-            (reflected + self.fuzz * random_in_unit_sphere(rng)).normalize(),
+            (reflected + fuzz * random_in_unit_sphere(rng)).normalize(),
+            ray.time,
         );
         Some(ScatterResult {
-            attenuation: self.albedo,
+            attenuation: self.albedo.value(hit.uv, hit.point),
             scattered_ray: scattered,
         })
     }
@@ -65,18 +166,19 @@ impl Material for Dielectric {
         };
 
         let unit_direction = ray.direction.normalize();
-        let cos_theta = f32::min((-unit_direction).dot(hit.normal), 1.0);
+        let cos_theta = f32::min((-unit_direction).dot(hit.shading_normal), 1.0);
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        let direction: Vec3;
-        if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.gen_range(0.0..1.0) {
-            direction = vec_reflect(unit_direction, hit.normal);
+        let direction = if cannot_refract
+            || reflectance(cos_theta, refraction_ratio) > rng.gen_range(0.0..1.0)
+        {
+            vec_reflect(unit_direction, hit.shading_normal)
         } else {
-            direction = vec_refract(unit_direction, hit.normal, refraction_ratio);
-        }
+            vec_refract(unit_direction, hit.shading_normal, refraction_ratio)
+        };
 
-        let scattered_ray = Ray::new(hit.point, direction.normalize());
+        let scattered_ray = Ray::new(hit.point, direction.normalize(), ray.time);
         Some(ScatterResult {
             attenuation,
             scattered_ray,
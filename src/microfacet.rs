@@ -0,0 +1,431 @@
+use crate::material::*;
+use crate::object::*;
+use crate::shared::*;
+use crate::texture::*;
+
+// Below this alpha surfaces are treated as perfectly smooth
+const SMOOTH_ALPHA: f32 = 1e-3;
+
+/// Orthonormal basis around the shading normal, which is z in local coordinates
+struct ShadingFrame {
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+}
+
+impl ShadingFrame {
+    fn new(hit: &HitRecord) -> Self {
+        let z = hit.shading_normal;
+        let (x, y) = z.any_orthonormal_pair();
+        ShadingFrame { x, y, z }
+    }
+
+    fn to_local(&self, v: Vec3) -> Vec3 {
+        Vec3::new(v.dot(self.x), v.dot(self.y), v.dot(self.z))
+    }
+
+    fn to_world(&self, v: Vec3) -> Vec3 {
+        v.x * self.x + v.y * self.y + v.z * self.z
+    }
+}
+
+/// Isotropic GGX (Trowbridge-Reitz) distribution of microfacet normals, in local coordinates
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Ggx {
+    pub alpha: f32,
+}
+
+impl Ggx {
+    /// Roughly the same blur as the fuzz of Metal, which spreads reflections about twice as
+    /// much as the normals
+    pub fn from_fuzz(fuzz: f32) -> Self {
+        Ggx {
+            alpha: 0.5 * fuzz.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Perceptual roughness as used by glTF, alpha is its square
+    pub fn from_roughness(roughness: f32) -> Self {
+        let roughness = roughness.clamp(0.0, 1.0);
+        Ggx {
+            alpha: roughness * roughness,
+        }
+    }
+
+    pub fn is_smooth(&self) -> bool {
+        self.alpha < SMOOTH_ALPHA
+    }
+
+    /// Distribution at a hit, with alpha from the perceptual roughness texture if there is one
+    fn at(self, roughness: &Option<Arc<dyn ScalarTexture>>, hit: &HitRecord) -> Self {
+        match roughness {
+            Some(texture) => Ggx::from_roughness(texture.value(hit.uv, hit.point)),
+            None => self,
+        }
+    }
+
+    fn lambda(&self, w: Vec3) -> f32 {
+        let cos2_theta = w.z * w.z;
+        if cos2_theta == 0.0 {
+            return f32::INFINITY;
+        }
+        let tan2_theta = (1.0 - cos2_theta).max(0.0) / cos2_theta;
+        0.5 * ((1.0 + self.alpha * self.alpha * tan2_theta).sqrt() - 1.0)
+    }
+
+    /// Fraction of microfacets visible from direction w
+    pub fn g1(&self, w: Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Height correlated masking and shadowing
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Sample a microfacet normal visible from wo, Heitz 2018
+    pub fn sample_visible_normal(&self, rng: &mut RayRng, wo: Vec3) -> Vec3 {
+        // Stretch to the hemisphere configuration
+        let wh = Vec3::new(self.alpha * wo.x, self.alpha * wo.y, wo.z).normalize();
+        let length_squared = wh.x * wh.x + wh.y * wh.y;
+        let t1 = if length_squared > 0.0 {
+            Vec3::new(-wh.y, wh.x, 0.0) / length_squared.sqrt()
+        } else {
+            Vec3::X
+        };
+        let t2 = wh.cross(t1);
+
+        // Uniform disk sample, warped towards the visible part of the projected hemisphere
+        let r = rng.gen_range(0.0..1.0).sqrt();
+        let phi = 2.0 * std::f32::consts::PI * rng.gen_range(0.0..1.0);
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + wh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * wh;
+
+        // Unstretch
+        Vec3::new(self.alpha * nh.x, self.alpha * nh.y, nh.z.max(1e-6)).normalize()
+    }
+}
+
+/// Fresnel reflectance of a conductor with complex index of refraction eta + i k per channel,
+/// seen from a medium with index 1
+pub fn fresnel_conductor(cos_theta_i: f32, eta: Vec3, k: Vec3) -> Color {
+    let cos = cos_theta_i.clamp(0.0, 1.0);
+    let cos2 = cos * cos;
+    let sin2 = 1.0 - cos2;
+
+    let t0 = eta * eta - k * k - Vec3::splat(sin2);
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).powf(0.5);
+    let a = (0.5 * (a2_plus_b2 + t0)).max(Vec3::ZERO).powf(0.5);
+
+    let t1 = a2_plus_b2 + Vec3::splat(cos2);
+    let t2 = 2.0 * cos * a;
+    let r_perpendicular = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + Vec3::splat(sin2 * sin2);
+    let t4 = t2 * sin2;
+    let r_parallel = r_perpendicular * (t3 - t4) / (t3 + t4);
+    0.5 * (r_parallel + r_perpendicular)
+}
+
+/// Measured complex indices of refraction at red, green and blue wavelengths
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ConductorPreset {
+    Gold,
+    Silver,
+    Copper,
+    Aluminium,
+    Iron,
+}
+
+impl ConductorPreset {
+    /// (eta, k)
+    pub fn ior(self) -> (Vec3, Vec3) {
+        match self {
+            ConductorPreset::Gold => (
+                Vec3::new(0.18299, 0.42108, 1.3734),
+                Vec3::new(3.4242, 2.3459, 1.7704),
+            ),
+            ConductorPreset::Silver => (
+                Vec3::new(0.15943, 0.14512, 0.13547),
+                Vec3::new(3.9291, 3.19, 2.3808),
+            ),
+            ConductorPreset::Copper => (
+                Vec3::new(0.27105, 0.67693, 1.3164),
+                Vec3::new(3.6092, 2.6248, 2.2921),
+            ),
+            ConductorPreset::Aluminium => (
+                Vec3::new(1.3456, 0.96521, 0.61722),
+                Vec3::new(7.4746, 6.3995, 5.3031),
+            ),
+            ConductorPreset::Iron => (
+                Vec3::new(2.9114, 2.9497, 2.5845),
+                Vec3::new(3.0893, 2.9318, 2.767),
+            ),
+        }
+    }
+}
+
+/// Rough metal with GGX microfacets and complex Fresnel, single scattering only
+pub struct Conductor {
+    pub eta: Vec3,
+    pub k: Vec3,
+    pub distribution: Ggx,
+    // Perceptual roughness which varies over the surface, replaces the distribution when set
+    pub roughness: Option<Arc<dyn ScalarTexture>>,
+}
+
+impl Conductor {
+    pub fn new(preset: ConductorPreset, distribution: Ggx) -> Self {
+        let (eta, k) = preset.ior();
+        Conductor {
+            eta,
+            k,
+            distribution,
+            roughness: None,
+        }
+    }
+
+    /// Conductor with the given color at normal incidence, which also tints the grazing
+    /// reflections. Gulbrandsen 2014 with the edge tint set to the color.
+    pub fn from_color(color: Color, distribution: Ggx) -> Self {
+        let r = color.clamp(Vec3::ZERO, Vec3::splat(0.99));
+        let edge_tint = color.clamp(Vec3::ZERO, Vec3::ONE);
+        let sqrt_r = r.powf(0.5);
+        let n_min = (Vec3::ONE - r) / (Vec3::ONE + r);
+        let n_max = (Vec3::ONE + sqrt_r) / (Vec3::ONE - sqrt_r);
+        let eta = edge_tint * n_min + (Vec3::ONE - edge_tint) * n_max;
+        let eta_plus = eta + Vec3::ONE;
+        let eta_minus = eta - Vec3::ONE;
+        let k2 = (eta_plus * eta_plus * r - eta_minus * eta_minus) / (Vec3::ONE - r);
+        Conductor {
+            eta,
+            k: k2.max(Vec3::ZERO).powf(0.5),
+            distribution,
+            roughness: None,
+        }
+    }
+
+    /// Roughness which varies over the surface, such as from noise
+    pub fn with_roughness(mut self, roughness: Arc<dyn ScalarTexture>) -> Self {
+        self.roughness = Some(roughness);
+        self
+    }
+}
+
+impl Material for Conductor {
+    fn scatter(&self, rng: &mut RayRng, ray: &Ray, hit: &HitRecord) -> Option<ScatterResult> {
+        let frame = ShadingFrame::new(hit);
+        let wo = frame.to_local(-ray.direction.normalize());
+        if wo.z <= 0.0 {
+            return None;
+        }
+
+        let distribution = self.distribution.at(&self.roughness, hit);
+        if distribution.is_smooth() {
+            let wi = Vec3::new(-wo.x, -wo.y, wo.z);
+            return Some(ScatterResult {
+                attenuation: fresnel_conductor(wo.z, self.eta, self.k),
+                scattered_ray: Ray::new(hit.point, frame.to_world(wi), ray.time),
+            });
+        }
+
+        // With visible normal sampling the weight is F G2 / G1
+        let wm = distribution.sample_visible_normal(rng, wo);
+        let wi = vec_reflect(-wo, wm);
+        if wi.z <= 0.0 {
+            return None;
+        }
+        let masking = distribution.g(wo, wi) / distribution.g1(wo);
+        Some(ScatterResult {
+            attenuation: fresnel_conductor(wo.dot(wm), self.eta, self.k) * masking,
+            scattered_ray: Ray::new(hit.point, frame.to_world(wi), ray.time),
+        })
+    }
+}
+
+/// Frosted glass with GGX microfacets, Walter et al. 2007, and exact Fresnel
+pub struct RoughDielectric {
+    pub ir: f32,
+    pub distribution: Ggx,
+    // Perceptual roughness which varies over the surface, replaces the distribution when set
+    pub roughness: Option<Arc<dyn ScalarTexture>>,
+}
+
+impl RoughDielectric {
+    pub fn new(ir: f32, distribution: Ggx) -> Self {
+        RoughDielectric {
+            ir,
+            distribution,
+            roughness: None,
+        }
+    }
+
+    /// Roughness which varies over the surface, such as from noise
+    pub fn with_roughness(mut self, roughness: Arc<dyn ScalarTexture>) -> Self {
+        self.roughness = Some(roughness);
+        self
+    }
+}
+
+/// Direction and weight for a local wo above the surface, wi is below it when transmitted.
+/// eta is the index on the other side of the surface over the index on the side of wo.
+fn sample_rough_dielectric(
+    rng: &mut RayRng,
+    distribution: Ggx,
+    wo: Vec3,
+    eta: f32,
+) -> Option<(Vec3, f32)> {
+    let wm = if distribution.is_smooth() {
+        Vec3::Z
+    } else {
+        distribution.sample_visible_normal(rng, wo)
+    };
+
+    // Choosing between the lobes by reflectance cancels Fresnel from the weight
+    let cos_theta_o = wo.dot(wm);
+    let wi = if rng.gen_range(0.0..1.0) < fresnel_dielectric(cos_theta_o, eta) {
+        vec_reflect(-wo, wm)
+    } else {
+        vec_refract(-wo, wm, 1.0 / eta)
+    };
+    let reflected = cos_theta_o * wi.dot(wm) > 0.0;
+    if reflected != (wi.z > 0.0) || wi.z == 0.0 {
+        // Went through the macro surface on the wrong side
+        return None;
+    }
+
+    let weight = if distribution.is_smooth() {
+        1.0
+    } else {
+        distribution.g(wo, wi) / distribution.g1(wo)
+    };
+    Some((wi.normalize(), weight))
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, rng: &mut RayRng, ray: &Ray, hit: &HitRecord) -> Option<ScatterResult> {
+        let frame = ShadingFrame::new(hit);
+        let wo = frame.to_local(-ray.direction.normalize());
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let eta = if hit.front_face {
+            self.ir
+        } else {
+            1.0 / self.ir
+        };
+
+        let distribution = self.distribution.at(&self.roughness, hit);
+        let (wi, weight) = sample_rough_dielectric(rng, distribution, wo, eta)?;
+        Some(ScatterResult {
+            attenuation: Color::splat(weight),
+            scattered_ray: Ray::new(hit.point, frame.to_world(wi), ray.time),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Complex Fresnel should match the dielectric one without absorption, and sampled
+    /// reflections of a white rough conductor should keep the single scattering energy
+    #[test]
+    fn test_conductor() {
+        for cos in [0.05, 0.3, 0.7, 1.0] {
+            let conductor = fresnel_conductor(cos, Vec3::splat(1.5), Vec3::ZERO);
+            assert!((conductor.x - fresnel_dielectric(cos, 1.5)).abs() < 1e-4);
+        }
+        let (eta, k) = ConductorPreset::Gold.ior();
+        let gold = fresnel_conductor(1.0, eta, k);
+        assert!(gold.x > gold.z && gold.x > 0.9);
+        let matched = Conductor::from_color(gold, Ggx::from_fuzz(0.0));
+        assert!((fresnel_conductor(1.0, matched.eta, matched.k) - gold).length() < 1e-3);
+
+        let mut rng = RayRng::new(3);
+        let ggx = Ggx { alpha: 0.3 };
+        let wo = Vec3::Z;
+        let samples = 10000;
+        let mut energy = 0.0;
+        for _ in 0..samples {
+            let wm = ggx.sample_visible_normal(&mut rng, wo);
+            assert!(wm.z > 0.0 && (wm.length() - 1.0).abs() < 1e-4);
+            let wi = vec_reflect(-wo, wm);
+            if wi.z > 0.0 {
+                energy += ggx.g(wo, wi) / ggx.g1(wo);
+            }
+        }
+        // Numerical integration gives 0.877, the rest leaves through the surface or is masked
+        let energy = energy / samples as f32;
+        assert!((energy - 0.877).abs() < 0.01);
+    }
+
+    /// Rough glass should reflect about as much as Fresnel predicts and lose little energy,
+    /// and smooth glass should reflect everything past the critical angle from the inside
+    #[test]
+    fn test_rough_dielectric() {
+        let mut rng = RayRng::new(5);
+        let glass = Ggx { alpha: 0.1 };
+        let samples = 10000;
+        let (mut reflected, mut energy) = (0, 0.0);
+        for _ in 0..samples {
+            if let Some((wi, weight)) = sample_rough_dielectric(&mut rng, glass, Vec3::Z, 1.5) {
+                reflected += (wi.z > 0.0) as u32;
+                energy += weight;
+            }
+        }
+        let reflectance = reflected as f32 / samples as f32;
+        assert!((reflectance - fresnel_dielectric(1.0, 1.5)).abs() < 0.01);
+        assert!(energy / samples as f32 > 0.97);
+
+        let smooth = Ggx::from_roughness(0.0);
+        let grazing = Vec3::new(0.9, 0.0, 0.19_f32.sqrt());
+        for _ in 0..100 {
+            let (wi, weight) =
+                sample_rough_dielectric(&mut rng, smooth, grazing, 1.0 / 1.5).unwrap();
+            assert!((wi - Vec3::new(-0.9, 0.0, grazing.z)).length() < 1e-5 && weight == 1.0);
+        }
+    }
+    /// Roughness textures replace the distribution per hit, through the mean of the channels
+    #[test]
+    fn test_roughness_texture() {
+        let white: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let plane = Plane::new(Point3::ZERO, Vec3::Y, &white);
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), 0.0);
+        let hit = plane
+            .intersect(RayQuery {
+                ray,
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            })
+            .unwrap();
+
+        let average = |color: Color| -> Arc<dyn ScalarTexture> {
+            Arc::new(ChannelAverage {
+                texture: Arc::new(SolidColor { color }),
+            })
+        };
+        let rough = Ggx::from_fuzz(1.0);
+        let varying = Some(average(Color::new(0.2, 0.5, 0.8)));
+        assert_eq!(rough.at(&varying, &hit), Ggx::from_roughness(0.5));
+        assert_eq!(rough.at(&None, &hit), rough);
+
+        // Polished by the texture, so every sample is the mirror reflection
+        let mut rng = RayRng::new(7);
+        let mirror = Vec3::new(1.0, 1.0, 0.0).normalize();
+        let conductor =
+            Conductor::new(ConductorPreset::Silver, rough).with_roughness(average(Color::ZERO));
+        let metal = Metal::new(Color::ONE, 1.0).with_fuzz(average(Color::ZERO));
+        for _ in 0..100 {
+            let scattered = conductor
+                .scatter(&mut rng, &ray, &hit)
+                .unwrap()
+                .scattered_ray;
+            assert!((scattered.direction - mirror).length() < 1e-5);
+            let scattered = metal.scatter(&mut rng, &ray, &hit).unwrap().scattered_ray;
+            assert!((scattered.direction - mirror).length() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use crate::shared::*;
+use crate::texture::*;
+
+use glam::IVec3;
+
+const LATTICE_SIZE: usize = 256;
+
+/// Random permutations which hash integer lattice points to table indices
+struct Lattice {
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+impl Lattice {
+    fn new(rng: &mut RayRng) -> Self {
+        Lattice {
+            perm_x: Lattice::permutation(rng),
+            perm_y: Lattice::permutation(rng),
+            perm_z: Lattice::permutation(rng),
+        }
+    }
+
+    /// Fisher-Yates shuffle of 0..LATTICE_SIZE
+    fn permutation(rng: &mut RayRng) -> Vec<usize> {
+        let mut perm: Vec<usize> = (0..LATTICE_SIZE).collect();
+        for i in (1..LATTICE_SIZE).rev() {
+            let j = ((rng.gen_range(0.0..1.0) * (i + 1) as f32) as usize).min(i);
+            perm.swap(i, j);
+        }
+        perm
+    }
+
+    fn hash(&self, cell: IVec3) -> usize {
+        // The mask wraps negative coordinates too
+        let mask = LATTICE_SIZE as i32 - 1;
+        self.perm_x[(cell.x & mask) as usize]
+            ^ self.perm_y[(cell.y & mask) as usize]
+            ^ self.perm_z[(cell.z & mask) as usize]
+    }
+}
+
+/// Gradient noise with random unit gradients on the integer lattice
+pub struct Perlin {
+    lattice: Lattice,
+    gradients: Vec<Vec3>,
+}
+
+impl Perlin {
+    pub fn new(rng: &mut RayRng) -> Self {
+        let gradients = (0..LATTICE_SIZE).map(|_| random_unit_vector(rng)).collect();
+        Perlin {
+            lattice: Lattice::new(rng),
+            gradients,
+        }
+    }
+
+    /// Smooth noise in roughly -1..1, zero at lattice points
+    pub fn noise(&self, p: Point3) -> f32 {
+        let cell = p.floor();
+        let f = p - cell;
+        let cell = cell.as_ivec3();
+        // Quintic fade so the second derivative is continuous across cells
+        let w = f * f * f * (f * (f * 6.0 - 15.0) + 10.0);
+
+        let mut sum = 0.0;
+        for i in 0..2 {
+            for j in 0..2 {
+                for k in 0..2 {
+                    let corner = IVec3::new(i, j, k);
+                    let gradient = self.gradients[self.lattice.hash(cell + corner)];
+                    let weight = Vec3::select(corner.cmpeq(IVec3::ONE), w, 1.0 - w);
+                    sum += weight.x * weight.y * weight.z * gradient.dot(f - corner.as_vec3());
+                }
+            }
+        }
+        sum
+    }
+
+    /// Sum of absolute noise over octaves of doubling frequency and halving amplitude
+    pub fn turbulence(&self, p: Point3, octaves: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut p = p;
+        let mut weight = 1.0;
+        for _ in 0..octaves {
+            sum += weight * self.noise(p).abs();
+            weight *= 0.5;
+            p *= 2.0;
+        }
+        sum
+    }
+}
+
+/// Distances from a point to the nearest feature points of Worley noise
+pub struct WorleySample {
+    // Distance to the nearest and second nearest feature point
+    pub f1: f32,
+    pub f2: f32,
+    // Random value in 0..1 shared by every point of the nearest cell
+    pub cell_value: f32,
+}
+
+/// Cellular noise with one jittered feature point in every lattice cell
+pub struct Worley {
+    lattice: Lattice,
+    points: Vec<Vec3>,
+}
+
+impl Worley {
+    pub fn new(rng: &mut RayRng) -> Self {
+        let points = (0..LATTICE_SIZE)
+            .map(|_| vec3_random_range(rng, 0.0..1.0))
+            .collect();
+        Worley {
+            lattice: Lattice::new(rng),
+            points,
+        }
+    }
+
+    pub fn sample(&self, p: Point3) -> WorleySample {
+        let cell = p.floor().as_ivec3();
+        let mut result = WorleySample {
+            f1: f32::MAX,
+            f2: f32::MAX,
+            cell_value: 0.0,
+        };
+        // Feature points stay in their cell, so the nearest two are in the 3x3x3 neighborhood
+        for i in -1..=1 {
+            for j in -1..=1 {
+                for k in -1..=1 {
+                    let neighbor = cell + IVec3::new(i, j, k);
+                    let hash = self.lattice.hash(neighbor);
+                    let distance = p.distance(neighbor.as_vec3() + self.points[hash]);
+                    if distance < result.f1 {
+                        result.f2 = result.f1;
+                        result.f1 = distance;
+                        result.cell_value = hash as f32 / (LATTICE_SIZE - 1) as f32;
+                    } else if distance < result.f2 {
+                        result.f2 = distance;
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Plain noise remapped to 0..1, blending between two colors
+pub struct NoiseTexture {
+    pub perlin: Arc<Perlin>,
+    pub scale: f32,
+    pub low: Color,
+    pub high: Color,
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _uv: Vec2, p: Point3) -> Color {
+        let t = 0.5 * (1.0 + self.perlin.noise(self.scale * p));
+        self.low.lerp(self.high, t.clamp(0.0, 1.0))
+    }
+}
+
+/// Turbulence blending between two colors
+pub struct TurbulenceTexture {
+    pub perlin: Arc<Perlin>,
+    pub scale: f32,
+    pub octaves: u32,
+    pub low: Color,
+    pub high: Color,
+}
+
+impl Texture for TurbulenceTexture {
+    fn value(&self, _uv: Vec2, p: Point3) -> Color {
+        let t = self.perlin.turbulence(self.scale * p, self.octaves);
+        self.low.lerp(self.high, t.clamp(0.0, 1.0))
+    }
+}
+
+/// Bands along z whose phase is disturbed by turbulence
+pub struct MarbleTexture {
+    pub perlin: Arc<Perlin>,
+    pub scale: f32,
+    // Phase shift of the bands per unit of turbulence
+    pub distortion: f32,
+    pub vein: Color,
+    pub base: Color,
+}
+
+impl MarbleTexture {
+    pub fn new(perlin: &Arc<Perlin>, scale: f32, vein: Color, base: Color) -> Self {
+        MarbleTexture {
+            perlin: perlin.clone(),
+            scale,
+            distortion: 10.0,
+            vein,
+            base,
+        }
+    }
+}
+
+impl Texture for MarbleTexture {
+    fn value(&self, _uv: Vec2, p: Point3) -> Color {
+        let phase = self.scale * p.z + self.distortion * self.perlin.turbulence(p, 7);
+        self.vein.lerp(self.base, 0.5 * (1.0 + phase.sin()))
+    }
+}
+
+/// Growth rings around the y axis, wobbled by noise
+pub struct WoodTexture {
+    pub perlin: Arc<Perlin>,
+    // Rings per unit of radius
+    pub rings: f32,
+    // Radial displacement of the rings in ring widths
+    pub distortion: f32,
+    pub early: Color,
+    pub late: Color,
+}
+
+impl WoodTexture {
+    pub fn new(perlin: &Arc<Perlin>, rings: f32, early: Color, late: Color) -> Self {
+        WoodTexture {
+            perlin: perlin.clone(),
+            rings,
+            distortion: 0.6,
+            early,
+            late,
+        }
+    }
+}
+
+impl Texture for WoodTexture {
+    fn value(&self, _uv: Vec2, p: Point3) -> Color {
+        // Stretch the noise along the grain
+        let wobble = self
+            .perlin
+            .noise(Vec3::new(2.0 * p.x, 0.3 * p.y, 2.0 * p.z));
+        let ring = self.rings * Vec2::new(p.x, p.z).length() + self.distortion * wobble;
+        // Early wood fades slowly into a thin band of late wood
+        let t = ring.rem_euclid(1.0).powi(4);
+        self.early.lerp(self.late, t)
+    }
+}
+
+/// What a Worley texture shows of its cells
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WorleyPattern {
+    // Distance to the nearest feature point, dark at the points
+    Distance,
+    // Dark lines along the borders between cells
+    Edges,
+    // Flat random color per cell (Voronoi)
+    Cells,
+}
+
+pub struct WorleyTexture {
+    pub worley: Arc<Worley>,
+    pub scale: f32,
+    pub pattern: WorleyPattern,
+    pub low: Color,
+    pub high: Color,
+}
+
+impl Texture for WorleyTexture {
+    fn value(&self, _uv: Vec2, p: Point3) -> Color {
+        let sample = self.worley.sample(self.scale * p);
+        let t = match self.pattern {
+            WorleyPattern::Distance => sample.f1,
+            // f2 - f1 is zero on the borders and grows linearly into the cell
+            WorleyPattern::Edges => ((sample.f2 - sample.f1) * 8.0).min(1.0),
+            WorleyPattern::Cells => sample.cell_value,
+        };
+        self.low.lerp(self.high, t.clamp(0.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Noise should be zero on the lattice, continuous, bounded and repeatable for a seed
+    #[test]
+    fn test_perlin_noise() {
+        let perlin = Perlin::new(&mut RayRng::new(7));
+        let same_seed = Perlin::new(&mut RayRng::new(7));
+        let mut rng = RayRng::new(1);
+        for _ in 0..1000 {
+            let p = vec3_random_range(&mut rng, -50.0..50.0);
+            let n = perlin.noise(p);
+            assert!(n.abs() <= 1.0);
+            assert_eq!(n, same_seed.noise(p));
+            assert!((perlin.noise(p + Vec3::splat(1e-3)) - n).abs() < 1e-2);
+            assert!(perlin.noise(p.round()).abs() < 1e-6);
+        }
+
+        // Each cell's feature point is inside the cell and at distance zero from itself
+        let worley = Worley::new(&mut RayRng::new(7));
+        let cell = IVec3::new(-3, 4, 9);
+        let point = cell.as_vec3() + worley.points[worley.lattice.hash(cell)];
+        let sample = worley.sample(point);
+        assert_eq!(sample.f1, 0.0);
+        assert!(sample.f2 > 0.0);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::material::*;
+use crate::object::*;
+use crate::shared::*;
+use crate::texture::*;
+use crate::volume::*;
+
+// Step in UV space for the finite differences of bump maps
+const BUMP_DELTA: f32 = 0.0005;
+
+/// Source of the perturbed shading normal
+pub enum NormalMap {
+    // Tangent space normal map with linear colors, x along dpdu and y along dpdv (OpenGL).
+    // The scale multiplies x and y, as for glTF.
+    Tangent {
+        texture: Arc<dyn Texture>,
+        scale: f32,
+    },
+    // Height field along the normal, in world units after multiplying by the scale
+    Bump {
+        height: Arc<dyn ScalarTexture>,
+        scale: f32,
+    },
+}
+
+/// Wraps any material and perturbs the shading normal of hits before they reach it
+pub struct NormalMapped {
+    pub material: Arc<dyn Material>,
+    pub map: NormalMap,
+}
+
+impl NormalMapped {
+    pub fn new(material: Arc<dyn Material>, map: NormalMap) -> Self {
+        NormalMapped { material, map }
+    }
+
+    /// Outward facing perturbed normal, None when the surface has no usable tangents
+    fn perturbed_normal(&self, hit: &HitRecord, outward: Vec3) -> Option<Vec3> {
+        match &self.map {
+            NormalMap::Tangent { texture, scale } => {
+                // Gram-Schmidt against the shading normal, the bitangent only gives handedness
+                let tangent = (hit.dpdu - hit.dpdu.dot(outward) * outward).try_normalize()?;
+                let mut bitangent = outward.cross(tangent);
+                if bitangent.dot(hit.dpdv) < 0.0 {
+                    bitangent = -bitangent;
+                }
+                let n = (2.0 * texture.value(hit.uv, hit.point) - Vec3::ONE)
+                    * Vec3::new(*scale, *scale, 1.0);
+                (n.x * tangent + n.y * bitangent + n.z * outward).try_normalize()
+            }
+            NormalMap::Bump { height, scale } => {
+                let displacement = |du: f32, dv: f32| {
+                    let uv = hit.uv + Vec2::new(du, dv);
+                    let p = hit.point + du * hit.dpdu + dv * hit.dpdv;
+                    scale * height.value(uv, p)
+                };
+                let d = displacement(0.0, 0.0);
+                let dddu = (displacement(BUMP_DELTA, 0.0) - d) / BUMP_DELTA;
+                let dddv = (displacement(0.0, BUMP_DELTA) - d) / BUMP_DELTA;
+                let dpdu = hit.dpdu + dddu * outward;
+                let dpdv = hit.dpdv + dddv * outward;
+                let n = dpdu.cross(dpdv).try_normalize()?;
+                // The UV parameterisation may be left handed
+                Some(if n.dot(outward) < 0.0 { -n } else { n })
+            }
+        }
+    }
+}
+
+impl Material for NormalMapped {
+    // The renderer has already replaced the shading normal with the perturbed one
+    fn scatter(&self, rng: &mut RayRng, ray: &Ray, hit: &HitRecord) -> Option<ScatterResult> {
+        self.material.scatter(rng, ray, hit)
+    }
+
+    fn emitted(&self, hit: &HitRecord) -> Color {
+        self.material.emitted(hit)
+    }
+
+    fn medium(&self) -> Option<&dyn Medium> {
+        self.material.medium()
+    }
+
+    fn shading_normal(&self, ray: &Ray, hit: &HitRecord) -> Option<Vec3> {
+        let outward = if hit.front_face {
+            hit.shading_normal
+        } else {
+            -hit.shading_normal
+        };
+        let n = self.perturbed_normal(hit, outward)?;
+        let n = if hit.front_face { n } else { -n };
+        Some(ensure_valid_reflection(hit.normal, n, ray.direction))
+    }
+}
+
+/// Bend a shading normal so the mirror reflection of the ray stays above the geometric surface.
+/// Perturbed normals can face away from the ray, which would send rays into the surface.
+pub fn ensure_valid_reflection(normal: Vec3, shading_normal: Vec3, direction: Vec3) -> Vec3 {
+    let wo = -direction.normalize();
+    let reflected = vec_reflect(-wo, shading_normal);
+    // Keep the reflection a little above the surface, but not above the incoming ray
+    let threshold = f32::min(0.01, 0.9 * wo.dot(normal));
+    if reflected.dot(normal) >= threshold {
+        return shading_normal;
+    }
+
+    // Lift the reflection to the threshold and use the half vector to get it
+    let Some(along_surface) = (reflected - reflected.dot(normal) * normal).try_normalize() else {
+        return normal;
+    };
+    let lifted = threshold * normal + (1.0 - threshold * threshold).max(0.0).sqrt() * along_surface;
+    (wo + lifted).try_normalize().unwrap_or(normal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A flat normal map keeps the normal, a tilted one follows dpdu and the result never
+    /// reflects the ray into the surface
+    #[test]
+    fn test_normal_map() {
+        let white: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let plane = Plane::new(Point3::ZERO, Vec3::Y, &white);
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let hit = plane
+            .intersect(RayQuery {
+                ray,
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            })
+            .unwrap();
+
+        let map = |color: Color| {
+            NormalMapped::new(
+                white.clone(),
+                NormalMap::Tangent {
+                    texture: Arc::new(SolidColor { color }),
+                    scale: 1.0,
+                },
+            )
+        };
+        let flat = map(Color::new(0.5, 0.5, 1.0)).shading_normal(&ray, &hit);
+        assert!((flat.unwrap() - Vec3::Y).length() < 1e-5);
+
+        let tilted = map(Vec3::new(0.5, 0.0, 0.75_f32.sqrt()) * 0.5 + Vec3::splat(0.5));
+        let tilted = tilted.shading_normal(&ray, &hit).unwrap();
+        let expected = 0.75_f32.sqrt() * Vec3::Y + 0.5 * hit.dpdu.normalize();
+        assert!((tilted - expected).length() < 1e-5);
+
+        // Facing away from the ray, grazing along dpdu
+        let steep = map(Vec3::new(-0.2, 0.98, 0.02).normalize() * 0.5 + Vec3::splat(0.5));
+        for direction in [Vec3::new(0.0, -1.0, -1.0), Vec3::new(-1.0, -0.1, 0.3)] {
+            let n = ensure_valid_reflection(hit.normal, Vec3::new(0.0, 0.1, 1.0), direction);
+            assert!(vec_reflect(direction.normalize(), n).dot(hit.normal) > 0.0);
+            let n = steep.shading_normal(&Ray::new(ray.origin, direction, 0.0), &hit);
+            let reflected = vec_reflect(direction.normalize(), n.unwrap());
+            assert!(reflected.dot(hit.normal) > 0.0);
+        }
+    }
+}
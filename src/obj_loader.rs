@@ -0,0 +1,639 @@
+use crate::material::*;
+use crate::normal_map::*;
+use crate::object::*;
+use crate::scene::*;
+use crate::shared::*;
+use crate::texture::*;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Error while loading an OBJ or MTL file
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, std::io::Error),
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            ObjError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+/// Line parser which remembers where it is for error reporting
+struct LineParser<'a> {
+    path: &'a Path,
+    line: usize,
+}
+
+impl LineParser<'_> {
+    fn error(&self, message: impl Into<String>) -> ObjError {
+        ObjError::Parse {
+            path: self.path.to_path_buf(),
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn parse_f32(&self, token: Option<&str>, what: &str) -> Result<f32, ObjError> {
+        let token = token.ok_or_else(|| self.error(format!("missing {}", what)))?;
+        token
+            .parse()
+            .map_err(|_| self.error(format!("invalid {} '{}'", what, token)))
+    }
+
+    fn parse_vec3<'t>(&self, tokens: &mut impl Iterator<Item = &'t str>) -> Result<Vec3, ObjError> {
+        Ok(Vec3::new(
+            self.parse_f32(tokens.next(), "x")?,
+            self.parse_f32(tokens.next(), "y")?,
+            self.parse_f32(tokens.next(), "z")?,
+        ))
+    }
+
+    fn parse_color<'t>(
+        &self,
+        tokens: &mut impl Iterator<Item = &'t str>,
+    ) -> Result<Color, ObjError> {
+        let r = self.parse_f32(tokens.next(), "red")?;
+        // A single value means grey
+        match tokens.next() {
+            None => Ok(Color::new(r, r, r)),
+            g => Ok(Color::new(
+                r,
+                self.parse_f32(g, "green")?,
+                self.parse_f32(tokens.next(), "blue")?,
+            )),
+        }
+    }
+
+    /// Texture map statement, the image is the last token after any options
+    fn parse_map<'t>(
+        &self,
+        tokens: &mut impl Iterator<Item = &'t str>,
+        directory: &Path,
+    ) -> Result<MtlMap, ObjError> {
+        let tokens: Vec<&str> = tokens.collect();
+        let file = tokens
+            .last()
+            .ok_or_else(|| self.error("texture map without a file"))?;
+        let wrap = if tokens.windows(2).any(|option| option == ["-clamp", "on"]) {
+            WrapMode::Clamp
+        } else {
+            WrapMode::Repeat
+        };
+        let multiplier = match tokens.iter().position(|token| *token == "-bm") {
+            Some(i) => self.parse_f32(tokens.get(i + 1).copied(), "-bm")?,
+            None => 1.0,
+        };
+        Ok(MtlMap {
+            path: directory.join(file),
+            wrap,
+            multiplier,
+        })
+    }
+
+    /// Resolve a 1-based or negative (relative) OBJ index
+    fn parse_index(&self, token: &str, count: usize, what: &str) -> Result<usize, ObjError> {
+        let index: i64 = token
+            .parse()
+            .map_err(|_| self.error(format!("invalid {} index '{}'", what, token)))?;
+        let resolved = if index < 0 {
+            count as i64 + index
+        } else {
+            index - 1
+        };
+        if index == 0 || resolved < 0 || resolved >= count as i64 {
+            return Err(self.error(format!("{} index {} out of range", what, index)));
+        }
+        Ok(resolved as usize)
+    }
+}
+
+/// Texture map statement with the image relative to the MTL file
+struct MtlMap {
+    path: PathBuf,
+    wrap: WrapMode,
+    // Bump map height multiplier
+    multiplier: f32,
+}
+
+// Height of white in a bump map in model units, before the multiplier
+const BUMP_HEIGHT: f32 = 0.01;
+
+// Images by path and whether they are sRGB encoded
+type TextureCache = HashMap<(PathBuf, bool), Arc<dyn Texture>>;
+
+/// Material description parsed from an MTL file
+struct MtlMaterial {
+    diffuse: Color,
+    specular: Color,
+    diffuse_map: Option<MtlMap>,
+    specular_map: Option<MtlMap>,
+    normal_map: Option<MtlMap>,
+    bump_map: Option<MtlMap>,
+    shininess: f32,
+    ior: f32,
+    dissolve: f32,
+    illum: u32,
+}
+
+impl Default for MtlMaterial {
+    fn default() -> Self {
+        MtlMaterial {
+            diffuse: Color::new(0.8, 0.8, 0.8),
+            specular: Color::ZERO,
+            diffuse_map: None,
+            specular_map: None,
+            normal_map: None,
+            bump_map: None,
+            shininess: 0.0,
+            ior: 1.5,
+            dissolve: 1.0,
+            illum: 1,
+        }
+    }
+}
+
+impl MtlMaterial {
+    /// Map the MTL illumination model onto the closest available material
+    fn to_material(&self, textures: &mut TextureCache) -> Arc<dyn Material> {
+        let material: Arc<dyn Material> = match self.illum {
+            // Glass and refraction models, or anything see-through
+            4 | 6 | 7 | 9 => Arc::new(Dielectric { ir: self.ior }),
+            _ if self.dissolve < 1.0 => Arc::new(Dielectric { ir: self.ior }),
+            // Reflection models
+            3 | 5 | 8 => Arc::new(Metal::textured(
+                texture_or_color(&self.specular_map, self.specular, textures),
+                // Phong exponent to a rough equivalent of fuzz
+                f32::sqrt(2.0 / (self.shininess + 2.0)).min(1.0),
+            )),
+            _ => Arc::new(Lambertian::textured(texture_or_color(
+                &self.diffuse_map,
+                self.diffuse,
+                textures,
+            ))),
+        };
+
+        // Normal maps take precedence over bump maps, both hold data rather than colors
+        let normal_map = self
+            .normal_map
+            .as_ref()
+            .and_then(|map| load_map(map, false, textures))
+            .map(|texture| NormalMap::Tangent {
+                texture,
+                scale: 1.0,
+            });
+        let bump_map = || {
+            let map = self.bump_map.as_ref()?;
+            load_map(map, false, textures).map(|texture| NormalMap::Bump {
+                height: Arc::new(ChannelAverage { texture }),
+                scale: map.multiplier * BUMP_HEIGHT,
+            })
+        };
+        match normal_map.or_else(bump_map) {
+            Some(map) => Arc::new(NormalMapped::new(material, map)),
+            None => material,
+        }
+    }
+}
+
+/// Image texture of the map. Images are loaded once and shared, unreadable ones are skipped.
+fn load_map(map: &MtlMap, srgb: bool, textures: &mut TextureCache) -> Option<Arc<dyn Texture>> {
+    let key = (map.path.clone(), srgb);
+    if let Some(texture) = textures.get(&key) {
+        return Some(texture.clone());
+    }
+    match ImageTexture::load(&map.path, srgb, map.wrap) {
+        Ok(image) => {
+            let texture: Arc<dyn Texture> = Arc::new(image);
+            textures.insert(key, texture.clone());
+            Some(texture)
+        }
+        Err(err) => {
+            println!("Warning: skipping texture {}", err);
+            None
+        }
+    }
+}
+
+/// Image texture of the map, which replaces the color
+fn texture_or_color(
+    map: &Option<MtlMap>,
+    color: Color,
+    textures: &mut TextureCache,
+) -> Arc<dyn Texture> {
+    map.as_ref()
+        .and_then(|map| load_map(map, true, textures))
+        .unwrap_or_else(|| Arc::new(SolidColor { color }))
+}
+
+fn read_file(path: &Path) -> Result<String, ObjError> {
+    std::fs::read_to_string(path).map_err(|e| ObjError::Io(path.to_path_buf(), e))
+}
+
+fn load_mtl(path: &Path) -> Result<HashMap<String, Arc<dyn Material>>, ObjError> {
+    let source = read_file(path)?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let mut parsed: Vec<(String, MtlMaterial)> = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let p = LineParser {
+            path,
+            line: line_index + 1,
+        };
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+
+        if keyword == "newmtl" {
+            let name = tokens.collect::<Vec<_>>().join(" ");
+            if name.is_empty() {
+                return Err(p.error("newmtl without a name"));
+            }
+            parsed.push((name, MtlMaterial::default()));
+            continue;
+        }
+
+        let Some((_, current)) = parsed.last_mut() else {
+            return Err(p.error(format!("'{}' before newmtl", keyword)));
+        };
+        match keyword {
+            "Kd" => current.diffuse = p.parse_color(&mut tokens)?,
+            "Ks" => current.specular = p.parse_color(&mut tokens)?,
+            "map_Kd" => current.diffuse_map = Some(p.parse_map(&mut tokens, directory)?),
+            "map_Ks" => current.specular_map = Some(p.parse_map(&mut tokens, directory)?),
+            "norm" => current.normal_map = Some(p.parse_map(&mut tokens, directory)?),
+            "bump" | "map_Bump" | "map_bump" => {
+                current.bump_map = Some(p.parse_map(&mut tokens, directory)?)
+            }
+            "Ns" => current.shininess = p.parse_f32(tokens.next(), "Ns")?,
+            "Ni" => current.ior = p.parse_f32(tokens.next(), "Ni")?,
+            "d" => current.dissolve = p.parse_f32(tokens.next(), "d")?,
+            "Tr" => current.dissolve = 1.0 - p.parse_f32(tokens.next(), "Tr")?,
+            "illum" => {
+                let token = tokens.next().ok_or_else(|| p.error("missing illum"))?;
+                current.illum = token
+                    .parse()
+                    .map_err(|_| p.error(format!("invalid illum '{}'", token)))?;
+            }
+            // Ambient, emission, other texture maps and extensions are ignored
+            _ => {}
+        }
+    }
+
+    let mut textures = HashMap::new();
+    Ok(parsed
+        .into_iter()
+        .map(|(name, mtl)| (name, mtl.to_material(&mut textures)))
+        .collect())
+}
+
+/// Identifies a unique vertex within a mesh
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct VertexKey {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+    // Smoothing group, or a unique id per face for flat shading
+    smoothing: u64,
+}
+
+/// Accumulates the faces of one group/material pair
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<Point3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+    indices: Vec<[u32; 3]>,
+    has_uvs: bool,
+    vertex_map: HashMap<VertexKey, u32>,
+    // Vertices without an explicit normal accumulate face normals
+    accumulate_normal: Vec<bool>,
+}
+
+impl MeshBuilder {
+    fn vertex(&mut self, key: VertexKey, obj: &ObjData) -> u32 {
+        if let Some(index) = self.vertex_map.get(&key) {
+            return *index;
+        }
+        let index = self.positions.len() as u32;
+        self.positions.push(obj.positions[key.position]);
+        self.uvs
+            .push(key.uv.map(|i| obj.uvs[i]).unwrap_or(Vec2::ZERO));
+        self.has_uvs |= key.uv.is_some();
+        self.normals
+            .push(key.normal.map(|i| obj.normals[i]).unwrap_or(Vec3::ZERO));
+        self.accumulate_normal.push(key.normal.is_none());
+        self.vertex_map.insert(key, index);
+        index
+    }
+
+    fn triangle(&mut self, indices: [u32; 3]) {
+        let [p0, p1, p2] = indices.map(|i| self.positions[i as usize]);
+        // Area weighted face normal
+        let face_normal = (p1 - p0).cross(p2 - p0);
+        for i in indices {
+            if self.accumulate_normal[i as usize] {
+                self.normals[i as usize] += face_normal;
+            }
+        }
+        self.indices.push(indices);
+    }
+
+    fn build(mut self, material: &Arc<dyn Material>) -> TriangleMesh {
+        for n in self.normals.iter_mut() {
+            *n = n.normalize_or_zero();
+        }
+        if !self.has_uvs {
+            self.uvs.clear();
+        }
+        TriangleMesh::new(
+            self.positions,
+            self.normals,
+            self.uvs,
+            self.indices,
+            material,
+        )
+    }
+}
+
+/// Raw vertex data shared by all groups
+#[derive(Default)]
+struct ObjData {
+    positions: Vec<Point3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<Vec2>,
+}
+
+/// Load an OBJ file and its material libraries into the scene as triangle meshes
+pub fn load_obj(path: &Path, scene: &mut Scene) -> Result<(), ObjError> {
+    let source = read_file(path)?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    let default_material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::new(0.8, 0.8, 0.8)));
+
+    let mut obj = ObjData::default();
+    let mut materials: HashMap<String, Arc<dyn Material>> = HashMap::new();
+
+    // One mesh per (group, material) pair, in order of first use
+    let mut meshes: Vec<((String, String), MeshBuilder)> = Vec::new();
+    let mut mesh_lookup: HashMap<(String, String), usize> = HashMap::new();
+    let mut group = String::from("default");
+    let mut material_name = String::new();
+    let mut smoothing: u64 = 0;
+    let mut face_count: u64 = 0;
+
+    for (line_index, line) in source.lines().enumerate() {
+        let p = LineParser {
+            path,
+            line: line_index + 1,
+        };
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+
+        match keyword {
+            "v" => obj.positions.push(p.parse_vec3(&mut tokens)?),
+            "vn" => obj.normals.push(p.parse_vec3(&mut tokens)?),
+            "vt" => {
+                let u = p.parse_f32(tokens.next(), "u")?;
+                let v = match tokens.next() {
+                    Some(token) => p.parse_f32(Some(token), "v")?,
+                    None => 0.0,
+                };
+                obj.uvs.push(Vec2::new(u, v));
+            }
+            "f" => {
+                face_count += 1;
+                let face_smoothing = if smoothing == 0 {
+                    // Flat shading gets unique vertices per face
+                    u64::MAX - face_count
+                } else {
+                    smoothing
+                };
+
+                let mut keys = Vec::new();
+                for token in tokens {
+                    let mut parts = token.split('/');
+                    let position =
+                        p.parse_index(parts.next().unwrap_or(""), obj.positions.len(), "vertex")?;
+                    let uv = match parts.next() {
+                        None | Some("") => None,
+                        Some(t) => Some(p.parse_index(t, obj.uvs.len(), "texture")?),
+                    };
+                    let normal = match parts.next() {
+                        None | Some("") => None,
+                        Some(t) => Some(p.parse_index(t, obj.normals.len(), "normal")?),
+                    };
+                    if parts.next().is_some() {
+                        return Err(p.error(format!("invalid face vertex '{}'", token)));
+                    }
+                    keys.push(VertexKey {
+                        position,
+                        uv,
+                        normal,
+                        smoothing: if normal.is_some() { 0 } else { face_smoothing },
+                    });
+                }
+                if keys.len() < 3 {
+                    return Err(p.error("face with fewer than 3 vertices"));
+                }
+
+                let mesh_key = (group.clone(), material_name.clone());
+                let mesh_index = *mesh_lookup.entry(mesh_key.clone()).or_insert_with(|| {
+                    meshes.push((mesh_key, MeshBuilder::default()));
+                    meshes.len() - 1
+                });
+                let builder = &mut meshes[mesh_index].1;
+
+                // Triangulate polygons as a fan
+                let indices: Vec<u32> = keys.iter().map(|k| builder.vertex(*k, &obj)).collect();
+                for i in 1..indices.len() - 1 {
+                    builder.triangle([indices[0], indices[i], indices[i + 1]]);
+                }
+            }
+            "g" | "o" => {
+                group = tokens.collect::<Vec<_>>().join(" ");
+            }
+            "s" => {
+                let token = tokens
+                    .next()
+                    .ok_or_else(|| p.error("missing smoothing group"))?;
+                smoothing = match token {
+                    "off" => 0,
+                    _ => token
+                        .parse()
+                        .map_err(|_| p.error(format!("invalid smoothing group '{}'", token)))?,
+                };
+            }
+            "usemtl" => {
+                material_name = tokens.collect::<Vec<_>>().join(" ");
+            }
+            "mtllib" => {
+                for library in tokens {
+                    match load_mtl(&directory.join(library)) {
+                        Ok(library_materials) => materials.extend(library_materials),
+                        // Missing libraries are common in exported files
+                        Err(ObjError::Io(mtl_path, err)) => {
+                            println!("Warning: skipping {}: {}", mtl_path.display(), err)
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+            // Lines, points, free-form geometry and other statements are ignored
+            _ => {}
+        }
+    }
+
+    for ((_, name), builder) in meshes {
+        let material = match materials.get(&name) {
+            Some(material) => material,
+            None => {
+                if !name.is_empty() {
+                    println!("Warning: unknown material '{}'", name);
+                }
+                &default_material
+            }
+        };
+        scene.add_mesh(Arc::new(builder.build(material)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a file into a directory of its own for each test, so parallel tests don't race
+    fn write_temp(test: &str, name: &str, contents: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("one_weekend_{}_{}", test, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_obj_quad_and_groups() {
+        let path = write_temp(
+            "obj_groups",
+            "groups.obj",
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+             g first\nf 1 2 3 4\n\
+             g second\ns 1\nf -4 -3 -2\n",
+        );
+        let mut scene = Scene::new();
+        load_obj(&path, &mut scene).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        // One mesh for each group
+        assert_eq!(scene.objects.len(), 2);
+    }
+
+    /// Two tents, flat and in a smoothing group, and a tilted quad with explicit UVs and
+    /// normals, each with one of the materials the MTL statements map onto
+    #[test]
+    fn test_obj_materials_and_normals() {
+        write_temp(
+            "obj_materials",
+            "materials.mtl",
+            "newmtl matte\nKd 0.2 0.4 0.6\nillum 2\n\
+             newmtl mirror\nKs 0.9 0.8 0.7\nNs 10000\nillum 3\n\
+             newmtl glass\nNi 1.33\nd 0.5\nillum 2\n",
+        );
+        let path = write_temp(
+            "obj_materials",
+            "shapes.obj",
+            "mtllib materials.mtl\n\
+             v -3 0 0\nv -3 1 0\nv -2 0 1\nv -2 1 1\nv -1 0 0\nv -1 1 0\n\
+             v 0 0 0\nv 0 1 0\nv 1 0 1\nv 1 1 1\nv 2 0 0\nv 2 1 0\n\
+             v 4 0 0\nv 5 0 1\nv 5 1 1\nv 4 1 0\n\
+             vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nvn 0 0 1\n\
+             g flat\nusemtl matte\nf 1 3 4 2\nf 3 5 6 4\n\
+             g smooth\nusemtl mirror\ns 1\nf 7 9 10 8\nf 9 11 12 10\n\
+             g tilted\nusemtl glass\ns off\nf 13/1/1 14/2/1 15/3/1 16/4/1\n",
+        );
+        let mut scene = Scene::new();
+        load_obj(&path, &mut scene).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        scene.build_bvh();
+
+        let trace = |x: f32, y: f32| {
+            let ray = Ray::new(Point3::new(x, y, 5.0), -Vec3::Z, 0.0);
+            let query = RayQuery {
+                ray,
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            (ray, scene.intersect(query).unwrap())
+        };
+        let mut rng = RayRng::new(0);
+
+        // Flat shading keeps the face normal, Kd becomes a Lambertian albedo
+        let face_normal = Vec3::new(-1.0, 0.0, 1.0).normalize();
+        let (ray, hit) = trace(-2.5, 0.5);
+        assert!((hit.shading_normal - face_normal).length() < 1e-5);
+        let scatter = hit.material.scatter(&mut rng, &ray, &hit).unwrap();
+        assert!((scatter.attenuation - Color::new(0.2, 0.4, 0.6)).length() < 1e-5);
+
+        // The smoothing group averages the normals at the ridge, illum 3 is a sharp Ks metal
+        let (ray, hit) = trace(0.9, 0.5);
+        assert!(hit.shading_normal.x > -0.3 && hit.shading_normal.z > 0.9);
+        let scatter = hit.material.scatter(&mut rng, &ray, &hit).unwrap();
+        assert!((scatter.attenuation - Color::new(0.9, 0.8, 0.7)).length() < 1e-5);
+        let mirror = vec_reflect(-Vec3::Z, hit.shading_normal);
+        assert!((scatter.scattered_ray.direction - mirror).length() < 0.05);
+
+        // Face vertices pick their own UV and normal, d < 1 is glass with the Ni index
+        let (ray, hit) = trace(4.25, 0.75);
+        assert!((hit.shading_normal - Vec3::Z).length() < 1e-5);
+        assert!((hit.uv - Vec2::new(0.25, 0.75)).length() < 1e-5);
+        let samples = 10000;
+        let mut reflected = 0;
+        for _ in 0..samples {
+            let scatter = hit.material.scatter(&mut rng, &ray, &hit).unwrap();
+            assert_eq!(scatter.attenuation, Color::ONE);
+            reflected += (scatter.scattered_ray.direction.z > 0.0) as u32;
+        }
+        // Normal incidence reflectance is 0.02 for an index of 1.33, 0.04 for the default 1.5
+        let reflectance = reflected as f32 / samples as f32;
+        assert!((reflectance - 0.02).abs() < 0.006);
+    }
+
+    #[test]
+    fn test_obj_error_line_number() {
+        let path = write_temp(
+            "obj_error",
+            "error.obj",
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1 2 7\n",
+        );
+        let mut scene = Scene::new();
+        let result = load_obj(&path, &mut scene);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        match result {
+            Err(ObjError::Parse { line, .. }) => assert_eq!(line, 4),
+            _ => panic!("expected a parse error"),
+        }
+    }
+}
@@ -1,12 +1,28 @@
+use crate::flat_bvh::*;
 use crate::material::*;
 use crate::shared::*;
 
+use smallvec::SmallVec;
+
 /// Information of a ray hit
+#[derive(Clone)]
 pub struct HitRecord {
     pub point: Point3,
+    // Geometric normal, facing against the ray
     pub normal: Vec3,
+    // Interpolated normal used for shading, on the same side as the geometric normal
+    pub shading_normal: Vec3,
     pub t: f32,
     pub front_face: bool,
+    pub uv: Vec2,
+    // Surface derivatives along u and v, zero when the object has no parameterisation
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    // Interpolated vertex color, white for objects without one
+    pub vertex_color: Color,
+    // Index of the hittable in the scene and of the primitive inside it, such as a triangle
+    pub object_id: u32,
+    pub primitive_id: u32,
     pub material: Arc<dyn Material>,
 }
 
@@ -21,8 +37,15 @@ impl HitRecord {
         HitRecord {
             point: ray.at(t),
             normal,
+            shading_normal: normal,
             t,
             front_face,
+            uv: Vec2::ZERO,
+            dpdu: Vec3::ZERO,
+            dpdv: Vec3::ZERO,
+            vertex_color: Color::ONE,
+            object_id: 0,
+            primitive_id: 0,
             material,
         }
     }
@@ -36,6 +59,24 @@ pub struct HittableBounds {
     pub hittable_index: usize,
 }
 
+impl HittableBounds {
+    pub fn new(min: Point3, max: Point3, hittable_index: usize) -> Self {
+        HittableBounds {
+            aabb: Aabb::with_bounds(point_to_nalgebra(min), point_to_nalgebra(max)),
+            node_index: 0,
+            hittable_index,
+        }
+    }
+
+    pub fn min(&self) -> Point3 {
+        Point3::new(self.aabb.min.x, self.aabb.min.y, self.aabb.min.z)
+    }
+
+    pub fn max(&self) -> Point3 {
+        Point3::new(self.aabb.max.x, self.aabb.max.y, self.aabb.max.z)
+    }
+}
+
 impl Bounded<f32, 3> for HittableBounds {
     fn aabb(&self) -> Aabb<f32, 3> {
         self.aabb
@@ -52,12 +93,98 @@ impl BHShape<f32, 3> for HittableBounds {
     }
 }
 
+/// Span of a ray inside a closed object, ends outside the query range are None
+pub struct HitInterval {
+    pub enter: Option<HitRecord>,
+    pub exit: Option<HitRecord>,
+}
+
+/// Spans of a ray inside an object, stored inline for the common convex case
+pub type HitIntervals = SmallVec<[HitInterval; 2]>;
+
+/// Limit on surface crossings collected for one ray
+const MAX_INTERVAL_CROSSINGS: usize = 64;
+
 /// An object in the scene which can be hit with a ray
 pub trait RayHittable: Send + Sync {
     // Intersect ray with object
     fn intersect(&self, query: RayQuery) -> Option<HitRecord>;
     // Return bounds
     fn compute_bounds(&self, index: usize) -> HittableBounds;
+    // Unbounded objects are kept out of the BVH
+    fn is_bounded(&self) -> bool {
+        true
+    }
+    // Whether anything blocks the ray within the query range, for shadow rays.
+    // Override to skip building the HitRecord.
+    fn occludes(&self, query: RayQuery) -> bool {
+        self.intersect(query).is_some()
+    }
+    // Spans of the ray inside a closed object which overlap the query range, sorted by t.
+    // The default walks the surface crossings with repeated intersect calls, and looks past
+    // the range only when it has no crossings, to tell whether all of it is inside.
+    fn intersect_intervals(&self, query: RayQuery) -> HitIntervals {
+        let mut intervals = HitIntervals::new();
+        let mut enter: Option<HitRecord> = None;
+        let mut crossed = false;
+        let mut walk = RayQuery {
+            t_max: TRACE_INFINITY,
+            ..query
+        };
+        for _ in 0..MAX_INTERVAL_CROSSINGS {
+            let Some(hit) = self.intersect(walk) else {
+                break;
+            };
+            if hit.t > query.t_max {
+                if !crossed && !hit.front_face {
+                    intervals.push(HitInterval {
+                        enter: None,
+                        exit: None,
+                    });
+                }
+                break;
+            }
+            // At least one ulp, adding the epsilon alone rounds back to t past 2^15
+            walk.t_min = f32::max(hit.t + TRACE_EPSILON, hit.t.next_up());
+            if hit.front_face {
+                enter = Some(hit);
+            } else if enter.is_some() || !crossed {
+                // Without an entry only the first exit counts, the range started inside
+                intervals.push(HitInterval {
+                    enter: enter.take(),
+                    exit: Some(hit),
+                });
+            }
+            crossed = true;
+        }
+        if enter.is_some() {
+            intervals.push(HitInterval { enter, exit: None });
+        }
+        intervals
+    }
+}
+
+/// Shared hittables, such as meshes referenced by several instances
+impl<T: RayHittable + ?Sized> RayHittable for Arc<T> {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        self.as_ref().intersect(query)
+    }
+
+    fn compute_bounds(&self, index: usize) -> HittableBounds {
+        self.as_ref().compute_bounds(index)
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.as_ref().is_bounded()
+    }
+
+    fn occludes(&self, query: RayQuery) -> bool {
+        self.as_ref().occludes(query)
+    }
+
+    fn intersect_intervals(&self, query: RayQuery) -> HitIntervals {
+        self.as_ref().intersect_intervals(query)
+    }
 }
 
 pub struct Sphere {
@@ -80,8 +207,9 @@ impl Sphere {
     }
 }
 
-impl RayHittable for Sphere {
-    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+impl Sphere {
+    /// Nearest root of the ray/sphere equation within the query range
+    fn nearest_root(&self, query: &RayQuery) -> Option<f32> {
         let r = query.ray;
         let oc = r.origin - self.center;
         let a = r.direction.length_squared();
@@ -101,25 +229,1401 @@ impl RayHittable for Sphere {
                 return None;
             }
         }
+        Some(root)
+    }
+}
 
-        let t = root;
+impl RayHittable for Sphere {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let r = query.ray;
+        let t = self.nearest_root(&query)?;
         let point = r.at(t);
         let outward_normal = (point - self.center) * self.radius_rcp;
-        let record = HitRecord::new(r, t, outward_normal, self.material.clone());
+        let mut record = HitRecord::new(r, t, outward_normal, self.material.clone());
+
+        // Spherical coordinates with v from the bottom pole to the top
+        let n = (point - self.center) / self.radius.abs();
+        let theta = f32::acos((-n.y).clamp(-1.0, 1.0));
+        let phi = f32::atan2(-n.z, n.x) + std::f32::consts::PI;
+        record.uv = Vec2::new(
+            phi / (2.0 * std::f32::consts::PI),
+            theta / std::f32::consts::PI,
+        );
+        let radius = self.radius.abs();
+        let sin_theta = theta.sin().max(1e-6);
+        record.dpdu = 2.0 * std::f32::consts::PI * radius * Vec3::new(n.z, 0.0, -n.x);
+        record.dpdv = std::f32::consts::PI
+            * radius
+            * Vec3::new(-n.x * n.y / sin_theta, sin_theta, -n.z * n.y / sin_theta);
 
         Some(record)
     }
 
+    fn occludes(&self, query: RayQuery) -> bool {
+        self.nearest_root(&query).is_some()
+    }
+
     fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
         let half_size = Vec3::new(self.radius, self.radius, self.radius);
-        let min = point_to_nalgebra(self.center - half_size);
-        let max = point_to_nalgebra(self.center + half_size);
-        let aabb = Aabb::with_bounds(min, max);
+        HittableBounds::new(
+            self.center - half_size,
+            self.center + half_size,
+            hittable_index,
+        )
+    }
+}
 
-        HittableBounds {
-            aabb,
-            node_index: 0,
+/// Sphere moving linearly from center0 at time0 to center1 at time1, resting at the nearer
+/// center outside that interval
+pub struct MovingSphere {
+    pub center0: Point3,
+    pub center1: Point3,
+    pub time0: f32,
+    pub time1: f32,
+    sphere: Sphere,
+}
+
+impl MovingSphere {
+    pub fn new(
+        center0: Point3,
+        center1: Point3,
+        time0: f32,
+        time1: f32,
+        radius: f32,
+        material: &Arc<dyn Material>,
+    ) -> Self {
+        MovingSphere {
+            center0,
+            center1,
+            time0,
+            time1,
+            sphere: Sphere::new(center0, radius, material),
+        }
+    }
+
+    pub fn center(&self, time: f32) -> Point3 {
+        // A closed shutter interval has no motion to interpolate
+        if self.time1 == self.time0 {
+            return self.center0;
+        }
+        // Clamped so a wider shutter stays within the bounds
+        let s = (time - self.time0) / (self.time1 - self.time0);
+        self.center0.lerp(self.center1, s.clamp(0.0, 1.0))
+    }
+}
+
+impl RayHittable for MovingSphere {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        // Intersect in the frame of the sphere at the ray time
+        let offset = self.center(query.ray.time) - self.center0;
+        let moved_query = RayQuery {
+            ray: Ray::new(
+                query.ray.origin - offset,
+                query.ray.direction,
+                query.ray.time,
+            ),
+            ..query
+        };
+        let mut hit = self.sphere.intersect(moved_query)?;
+        hit.point += offset;
+        Some(hit)
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        // Cover the whole motion
+        let half_size = Vec3::splat(self.sphere.radius);
+        let min = self.center0.min(self.center1) - half_size;
+        let max = self.center0.max(self.center1) + half_size;
+        HittableBounds::new(min, max, hittable_index)
+    }
+}
+
+/// Parallelogram spanned by the edges u and v from corner q
+pub struct Quad {
+    pub q: Point3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub material: Arc<dyn Material>,
+    normal: Vec3,
+    d: f32,
+    // Maps a point on the plane to (alpha, beta) edge coordinates
+    w: Vec3,
+}
+
+impl Quad {
+    pub fn new(q: Point3, u: Vec3, v: Vec3, material: &Arc<dyn Material>) -> Self {
+        let n = u.cross(v);
+        let normal = n.normalize();
+        Quad {
+            q,
+            u,
+            v,
+            material: material.clone(),
+            normal,
+            d: normal.dot(q),
+            w: n / n.dot(n),
+        }
+    }
+}
+
+impl RayHittable for Quad {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let r = query.ray;
+        let denom = self.normal.dot(r.direction);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+
+        let t = (self.d - self.normal.dot(r.origin)) / denom;
+        if t < query.t_min || query.t_max < t {
+            return None;
+        }
+
+        let planar_hit = r.at(t) - self.q;
+        let alpha = self.w.dot(planar_hit.cross(self.v));
+        let beta = self.w.dot(self.u.cross(planar_hit));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+
+        let mut record = HitRecord::new(r, t, self.normal, self.material.clone());
+        record.uv = Vec2::new(alpha, beta);
+        record.dpdu = self.u;
+        record.dpdv = self.v;
+        Some(record)
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        let corners = [
+            self.q,
+            self.q + self.u,
+            self.q + self.v,
+            self.q + self.u + self.v,
+        ];
+        let min = corners.iter().fold(Vec3::MAX, |a, p| a.min(*p));
+        let max = corners.iter().fold(Vec3::MIN, |a, p| a.max(*p));
+        // Pad so the box is never flat
+        let pad = Vec3::splat(TRACE_EPSILON);
+        HittableBounds::new(min - pad, max + pad, hittable_index)
+    }
+}
+
+/// Flat disk around center facing along normal
+pub struct Disk {
+    pub center: Point3,
+    pub normal: Vec3,
+    pub radius: f32,
+    pub material: Arc<dyn Material>,
+    tangent: Vec3,
+    bitangent: Vec3,
+}
+
+impl Disk {
+    pub fn new(center: Point3, normal: Vec3, radius: f32, material: &Arc<dyn Material>) -> Self {
+        let normal = normal.normalize();
+        let tangent = normal.any_orthonormal_vector();
+        Disk {
+            center,
+            normal,
+            radius,
+            material: material.clone(),
+            tangent,
+            bitangent: normal.cross(tangent),
+        }
+    }
+}
+
+impl RayHittable for Disk {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let r = query.ray;
+        let denom = self.normal.dot(r.direction);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+
+        let t = self.normal.dot(self.center - r.origin) / denom;
+        if t < query.t_min || query.t_max < t {
+            return None;
+        }
+
+        let offset = r.at(t) - self.center;
+        if offset.length_squared() > self.radius * self.radius {
+            return None;
+        }
+
+        let mut record = HitRecord::new(r, t, self.normal, self.material.clone());
+        let scale = 0.5 / self.radius;
+        record.uv = Vec2::new(
+            0.5 + scale * offset.dot(self.tangent),
+            0.5 + scale * offset.dot(self.bitangent),
+        );
+        record.dpdu = self.tangent / scale;
+        record.dpdv = self.bitangent / scale;
+        Some(record)
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        // Extent of a circle along each axis
+        let n = self.normal;
+        let extent = self.radius * (Vec3::ONE - n * n).max(Vec3::ZERO).powf(0.5);
+        let pad = Vec3::splat(TRACE_EPSILON);
+        HittableBounds::new(
+            self.center - extent - pad,
+            self.center + extent + pad,
             hittable_index,
+        )
+    }
+}
+
+/// Axis aligned box made from six quads
+pub struct AaBox {
+    pub min: Point3,
+    pub max: Point3,
+    sides: Vec<Quad>,
+}
+
+impl AaBox {
+    pub fn new(a: Point3, b: Point3, material: &Arc<dyn Material>) -> Self {
+        let min = a.min(b);
+        let max = a.max(b);
+        let dx = Vec3::new(max.x - min.x, 0.0, 0.0);
+        let dy = Vec3::new(0.0, max.y - min.y, 0.0);
+        let dz = Vec3::new(0.0, 0.0, max.z - min.z);
+
+        // Edges ordered so all normals point outwards
+        let sides = vec![
+            Quad::new(Point3::new(min.x, min.y, max.z), dx, dy, material), // front
+            Quad::new(Point3::new(max.x, min.y, max.z), -dz, dy, material), // right
+            Quad::new(Point3::new(max.x, min.y, min.z), -dx, dy, material), // back
+            Quad::new(Point3::new(min.x, min.y, min.z), dz, dy, material), // left
+            Quad::new(Point3::new(min.x, max.y, max.z), dx, -dz, material), // top
+            Quad::new(Point3::new(min.x, min.y, min.z), dx, dz, material), // bottom
+        ];
+
+        AaBox { min, max, sides }
+    }
+}
+
+impl RayHittable for AaBox {
+    fn intersect(&self, mut query: RayQuery) -> Option<HitRecord> {
+        let mut closest_hit_option = None;
+        for (i, side) in self.sides.iter().enumerate() {
+            if let Some(mut hit) = side.intersect(query) {
+                query.t_max = hit.t;
+                hit.primitive_id = i as u32;
+                closest_hit_option = Some(hit);
+            }
+        }
+        closest_hit_option
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        HittableBounds::new(self.min, self.max, hittable_index)
+    }
+}
+
+/// Infinite plane through point facing along normal
+pub struct Plane {
+    pub point: Point3,
+    pub normal: Vec3,
+    pub material: Arc<dyn Material>,
+    tangent: Vec3,
+    bitangent: Vec3,
+}
+
+impl Plane {
+    pub fn new(point: Point3, normal: Vec3, material: &Arc<dyn Material>) -> Self {
+        let normal = normal.normalize();
+        let tangent = normal.any_orthonormal_vector();
+        Plane {
+            point,
+            normal,
+            material: material.clone(),
+            tangent,
+            bitangent: normal.cross(tangent),
+        }
+    }
+}
+
+impl RayHittable for Plane {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let r = query.ray;
+        let denom = self.normal.dot(r.direction);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+
+        let t = self.normal.dot(self.point - r.origin) / denom;
+        if t < query.t_min || query.t_max < t {
+            return None;
+        }
+
+        let mut record = HitRecord::new(r, t, self.normal, self.material.clone());
+        // Planar coordinates in world units
+        let offset = record.point - self.point;
+        record.uv = Vec2::new(offset.dot(self.tangent), offset.dot(self.bitangent));
+        record.dpdu = self.tangent;
+        record.dpdv = self.bitangent;
+        Some(record)
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        HittableBounds::new(Vec3::MIN, Vec3::MAX, hittable_index)
+    }
+
+    fn is_bounded(&self) -> bool {
+        false
+    }
+}
+
+/// Orthonormal frame with y along the axis of a primitive
+#[derive(Copy, Clone)]
+struct AxisFrame {
+    origin: Point3,
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+}
+
+impl AxisFrame {
+    fn new(origin: Point3, axis: Vec3) -> Self {
+        let y = axis.normalize();
+        let x = y.any_orthonormal_vector();
+        AxisFrame {
+            origin,
+            x,
+            y,
+            z: x.cross(y),
+        }
+    }
+
+    /// Ray origin and direction in the frame, the direction keeps its length
+    fn ray_to_local(&self, ray: &Ray) -> (Point3, Vec3) {
+        let o = ray.origin - self.origin;
+        let d = ray.direction;
+        (
+            Point3::new(o.dot(self.x), o.dot(self.y), o.dot(self.z)),
+            Vec3::new(d.dot(self.x), d.dot(self.y), d.dot(self.z)),
+        )
+    }
+
+    fn vector_to_world(&self, v: Vec3) -> Vec3 {
+        v.x * self.x + v.y * self.y + v.z * self.z
+    }
+
+    /// Half extent along each world axis of a circle around the axis
+    fn circle_extent(&self, radius: f32) -> Vec3 {
+        radius * (Vec3::ONE - self.y * self.y).max(Vec3::ZERO).powf(0.5)
+    }
+}
+
+/// Closest hit in the local frame of a primitive, with its outward normal, uv and the
+/// derivatives along u and v
+struct LocalHit {
+    t_min: f32,
+    t_max: f32,
+    hit: Option<(f32, Vec3, Vec2, [Vec3; 2])>,
+}
+
+impl LocalHit {
+    fn new(query: &RayQuery) -> Self {
+        LocalHit {
+            t_min: query.t_min,
+            t_max: query.t_max,
+            hit: None,
+        }
+    }
+
+    fn consider(&mut self, t: f32, outward_normal: Vec3, uv: Vec2, derivatives: [Vec3; 2]) {
+        if self.t_min <= t && t <= self.t_max {
+            self.t_max = t;
+            self.hit = Some((t, outward_normal, uv, derivatives));
+        }
+    }
+
+    fn into_record(
+        self,
+        ray: Ray,
+        frame: &AxisFrame,
+        material: &Arc<dyn Material>,
+    ) -> Option<HitRecord> {
+        let (t, normal, uv, [dpdu, dpdv]) = self.hit?;
+        let outward_normal = frame.vector_to_world(normal).normalize();
+        let mut record = HitRecord::new(ray, t, outward_normal, material.clone());
+        record.uv = uv;
+        record.dpdu = frame.vector_to_world(dpdu);
+        record.dpdv = frame.vector_to_world(dpdv);
+        Some(record)
+    }
+}
+
+/// Angle around the local y axis mapped to 0..1
+fn local_phi(p: Point3) -> f32 {
+    (f32::atan2(p.z, p.x) + std::f32::consts::PI) / (2.0 * std::f32::consts::PI)
+}
+
+/// Derivative of a point along local_phi
+fn local_dpdphi(p: Point3) -> Vec3 {
+    2.0 * std::f32::consts::PI * Vec3::new(-p.z, 0.0, p.x)
+}
+
+/// Cylinder between two points, optionally closed with caps
+pub struct Cylinder {
+    pub radius: f32,
+    pub height: f32,
+    pub capped: bool,
+    pub material: Arc<dyn Material>,
+    frame: AxisFrame,
+}
+
+impl Cylinder {
+    pub fn new(
+        p0: Point3,
+        p1: Point3,
+        radius: f32,
+        capped: bool,
+        material: &Arc<dyn Material>,
+    ) -> Self {
+        Cylinder {
+            radius,
+            height: (p1 - p0).length(),
+            capped,
+            material: material.clone(),
+            frame: AxisFrame::new(p0, p1 - p0),
+        }
+    }
+}
+
+impl RayHittable for Cylinder {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let (o, d) = self.frame.ray_to_local(&query.ray);
+        let mut local_hit = LocalHit::new(&query);
+
+        // Side, ignoring y
+        let a = d.x * d.x + d.z * d.z;
+        let half_b = o.x * d.x + o.z * d.z;
+        let c = o.x * o.x + o.z * o.z - self.radius * self.radius;
+        let discriminant = half_b * half_b - a * c;
+        if a > 0.0 && discriminant >= 0.0 {
+            let sqrtd = discriminant.sqrt();
+            for t in [(-half_b - sqrtd) / a, (-half_b + sqrtd) / a] {
+                let p = o + t * d;
+                if 0.0 <= p.y && p.y <= self.height {
+                    let uv = Vec2::new(local_phi(p), p.y / self.height);
+                    let derivatives = [local_dpdphi(p), Vec3::new(0.0, self.height, 0.0)];
+                    local_hit.consider(t, Vec3::new(p.x, 0.0, p.z), uv, derivatives);
+                }
+            }
+        }
+
+        if self.capped && d.y != 0.0 {
+            for (y, normal_y) in [(0.0, -1.0), (self.height, 1.0)] {
+                let t = (y - o.y) / d.y;
+                let p = o + t * d;
+                if p.x * p.x + p.z * p.z <= self.radius * self.radius {
+                    // v is constant on the caps
+                    let uv = Vec2::new(local_phi(p), y / self.height);
+                    let derivatives = [local_dpdphi(p), Vec3::ZERO];
+                    local_hit.consider(t, Vec3::new(0.0, normal_y, 0.0), uv, derivatives);
+                }
+            }
+        }
+
+        local_hit.into_record(query.ray, &self.frame, &self.material)
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        let p0 = self.frame.origin;
+        let p1 = p0 + self.height * self.frame.y;
+        let extent = self.frame.circle_extent(self.radius);
+        HittableBounds::new(p0.min(p1) - extent, p0.max(p1) + extent, hittable_index)
+    }
+}
+
+/// Cone or truncated cone from radius0 at p0 to radius1 at p1, optionally capped
+pub struct Cone {
+    pub radius0: f32,
+    pub radius1: f32,
+    pub height: f32,
+    pub capped: bool,
+    pub material: Arc<dyn Material>,
+    frame: AxisFrame,
+}
+
+impl Cone {
+    pub fn new(
+        p0: Point3,
+        radius0: f32,
+        p1: Point3,
+        radius1: f32,
+        capped: bool,
+        material: &Arc<dyn Material>,
+    ) -> Self {
+        Cone {
+            radius0,
+            radius1,
+            height: (p1 - p0).length(),
+            capped,
+            material: material.clone(),
+            frame: AxisFrame::new(p0, p1 - p0),
+        }
+    }
+}
+
+impl RayHittable for Cone {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let (o, d) = self.frame.ray_to_local(&query.ray);
+        let mut local_hit = LocalHit::new(&query);
+
+        // Side: x^2 + z^2 = (radius0 + k * y)^2
+        let k = (self.radius1 - self.radius0) / self.height;
+        let r_o = self.radius0 + k * o.y;
+        let a = d.x * d.x + d.z * d.z - k * k * d.y * d.y;
+        let half_b = o.x * d.x + o.z * d.z - k * d.y * r_o;
+        let c = o.x * o.x + o.z * o.z - r_o * r_o;
+
+        let (roots, count) = if a.abs() < 1e-12 {
+            // Ray parallel to the slope, one root
+            if half_b == 0.0 {
+                ([0.0; 2], 0)
+            } else {
+                ([-c / (2.0 * half_b), 0.0], 1)
+            }
+        } else {
+            let discriminant = half_b * half_b - a * c;
+            if discriminant < 0.0 {
+                ([0.0; 2], 0)
+            } else {
+                let sqrtd = discriminant.sqrt();
+                ([(-half_b - sqrtd) / a, (-half_b + sqrtd) / a], 2)
+            }
+        };
+        for &t in &roots[..count] {
+            let p = o + t * d;
+            if 0.0 <= p.y && p.y <= self.height {
+                // Gradient of the implicit surface
+                let normal = Vec3::new(p.x, -k * (self.radius0 + k * p.y), p.z);
+                let uv = Vec2::new(local_phi(p), p.y / self.height);
+                // Along the slope towards p1
+                let radial = Vec3::new(p.x, 0.0, p.z).normalize_or_zero();
+                let dpdv = self.height * (k * radial + Vec3::Y);
+                local_hit.consider(t, normal, uv, [local_dpdphi(p), dpdv]);
+            }
+        }
+
+        if self.capped && d.y != 0.0 {
+            for (y, radius, normal_y) in
+                [(0.0, self.radius0, -1.0), (self.height, self.radius1, 1.0)]
+            {
+                let t = (y - o.y) / d.y;
+                let p = o + t * d;
+                if p.x * p.x + p.z * p.z <= radius * radius {
+                    let uv = Vec2::new(local_phi(p), y / self.height);
+                    let derivatives = [local_dpdphi(p), Vec3::ZERO];
+                    local_hit.consider(t, Vec3::new(0.0, normal_y, 0.0), uv, derivatives);
+                }
+            }
+        }
+
+        local_hit.into_record(query.ray, &self.frame, &self.material)
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        let p0 = self.frame.origin;
+        let p1 = p0 + self.height * self.frame.y;
+        let extent0 = self.frame.circle_extent(self.radius0);
+        let extent1 = self.frame.circle_extent(self.radius1);
+        HittableBounds::new(
+            (p0 - extent0).min(p1 - extent1),
+            (p0 + extent0).max(p1 + extent1),
+            hittable_index,
+        )
+    }
+}
+
+/// Cylinder between two points with hemispherical ends
+pub struct Capsule {
+    pub radius: f32,
+    pub height: f32,
+    pub material: Arc<dyn Material>,
+    frame: AxisFrame,
+}
+
+impl Capsule {
+    pub fn new(p0: Point3, p1: Point3, radius: f32, material: &Arc<dyn Material>) -> Self {
+        Capsule {
+            radius,
+            height: (p1 - p0).length(),
+            material: material.clone(),
+            frame: AxisFrame::new(p0, p1 - p0),
+        }
+    }
+}
+
+impl RayHittable for Capsule {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let (o, d) = self.frame.ray_to_local(&query.ray);
+        let mut local_hit = LocalHit::new(&query);
+        let radius_sq = self.radius * self.radius;
+        let length = self.height + 2.0 * self.radius;
+        let uv = |p: Point3| Vec2::new(local_phi(p), (p.y + self.radius) / length);
+
+        // Side between the end points
+        let a = d.x * d.x + d.z * d.z;
+        let half_b = o.x * d.x + o.z * d.z;
+        let c = o.x * o.x + o.z * o.z - radius_sq;
+        let discriminant = half_b * half_b - a * c;
+        if a > 0.0 && discriminant >= 0.0 {
+            let sqrtd = discriminant.sqrt();
+            for t in [(-half_b - sqrtd) / a, (-half_b + sqrtd) / a] {
+                let p = o + t * d;
+                if 0.0 <= p.y && p.y <= self.height {
+                    let derivatives = [local_dpdphi(p), Vec3::new(0.0, length, 0.0)];
+                    local_hit.consider(t, Vec3::new(p.x, 0.0, p.z), uv(p), derivatives);
+                }
+            }
+        }
+
+        // Hemispheres beyond each end point
+        let a = d.length_squared();
+        for (center_y, below) in [(0.0, true), (self.height, false)] {
+            let oc = o - Vec3::new(0.0, center_y, 0.0);
+            let half_b = oc.dot(d);
+            let c = oc.length_squared() - radius_sq;
+            let discriminant = half_b * half_b - a * c;
+            if discriminant < 0.0 {
+                continue;
+            }
+            let sqrtd = discriminant.sqrt();
+            for t in [(-half_b - sqrtd) / a, (-half_b + sqrtd) / a] {
+                let p = o + t * d;
+                if (p.y < center_y) == below {
+                    // Follow the meridian as v moves the height
+                    let q = p - Vec3::new(0.0, center_y, 0.0);
+                    let ring_sq = (q.x * q.x + q.z * q.z).max(1e-6 * radius_sq);
+                    let dpdv = length * (Vec3::Y - Vec3::new(q.x, 0.0, q.z) * (q.y / ring_sq));
+                    local_hit.consider(t, q, uv(p), [local_dpdphi(p), dpdv]);
+                }
+            }
+        }
+
+        local_hit.into_record(query.ray, &self.frame, &self.material)
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        let p0 = self.frame.origin;
+        let p1 = p0 + self.height * self.frame.y;
+        let extent = Vec3::splat(self.radius);
+        HittableBounds::new(p0.min(p1) - extent, p0.max(p1) + extent, hittable_index)
+    }
+}
+
+/// Torus around center, with the ring in the plane perpendicular to axis
+pub struct Torus {
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    pub material: Arc<dyn Material>,
+    frame: AxisFrame,
+}
+
+impl Torus {
+    pub fn new(
+        center: Point3,
+        axis: Vec3,
+        major_radius: f32,
+        minor_radius: f32,
+        material: &Arc<dyn Material>,
+    ) -> Self {
+        Torus {
+            major_radius,
+            minor_radius,
+            material: material.clone(),
+            frame: AxisFrame::new(center, axis),
+        }
+    }
+}
+
+impl RayHittable for Torus {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let (o, d) = self.frame.ray_to_local(&query.ray);
+        let mut local_hit = LocalHit::new(&query);
+
+        // Solve for distance s along the normalized direction in double precision
+        let length = d.length() as f64;
+        let o = o.as_dvec3();
+        let d = d.as_dvec3() / length;
+        let major_sq = (self.major_radius as f64).powi(2);
+        let minor_sq = (self.minor_radius as f64).powi(2);
+
+        // (|p|^2 + R^2 - r^2)^2 - 4R^2 (|p|^2 - p.y^2) = 0 with |p|^2 = s^2 + 2fs + g
+        let f = o.dot(d);
+        let g = o.length_squared();
+        let e = g + major_sq - minor_sq;
+        let four_major_sq = 4.0 * major_sq;
+        let coefficients = [
+            e * e - four_major_sq * (g - o.y * o.y),
+            4.0 * f * e - four_major_sq * (2.0 * f - 2.0 * o.y * d.y),
+            2.0 * e + 4.0 * f * f - four_major_sq * (1.0 - d.y * d.y),
+            4.0 * f,
+            1.0,
+        ];
+
+        for &s in solve_quartic(coefficients).iter() {
+            let t = (s / length) as f32;
+            let p = (o + s * d).as_vec3();
+            let ring_length = f32::sqrt(p.x * p.x + p.z * p.z);
+            // Direction from the center of the tube
+            let ring = Vec3::new(p.x, 0.0, p.z) * (self.major_radius / ring_length);
+            let normal = p - ring;
+            let theta = (f32::atan2(normal.y, ring_length - self.major_radius)
+                + std::f32::consts::PI)
+                / (2.0 * std::f32::consts::PI);
+            // Around the tube, perpendicular to the normal in the plane of the axis
+            let radial = Vec3::new(p.x, 0.0, p.z) / ring_length;
+            let dpdv = 2.0
+                * std::f32::consts::PI
+                * ((ring_length - self.major_radius) * Vec3::Y - normal.y * radial);
+            let uv = Vec2::new(local_phi(p), theta);
+            local_hit.consider(t, normal, uv, [local_dpdphi(p), dpdv]);
+        }
+
+        local_hit.into_record(query.ray, &self.frame, &self.material)
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        let extent = self.frame.circle_extent(self.major_radius) + Vec3::splat(self.minor_radius);
+        HittableBounds::new(
+            self.frame.origin - extent,
+            self.frame.origin + extent,
+            hittable_index,
+        )
+    }
+}
+
+/// Watertight ray/triangle intersection (Woop, Benthin and Wald 2013).
+/// Returns t and the barycentric weights of p0, p1 and p2.
+pub fn intersect_triangle(
+    query: RayQuery,
+    p0: Point3,
+    p1: Point3,
+    p2: Point3,
+) -> Option<(f32, Vec3)> {
+    let r = query.ray;
+
+    // Permute the axes so the largest direction component becomes z
+    let kz = r.direction.abs().max_dimension();
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    if r.direction[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+
+    // Shear constants which map the ray direction onto +z
+    let sx = r.direction[kx] / r.direction[kz];
+    let sy = r.direction[ky] / r.direction[kz];
+    let sz = 1.0 / r.direction[kz];
+
+    // Vertices relative to the ray origin
+    let a = p0 - r.origin;
+    let b = p1 - r.origin;
+    let c = p2 - r.origin;
+
+    let ax = a[kx] - sx * a[kz];
+    let ay = a[ky] - sy * a[kz];
+    let bx = b[kx] - sx * b[kz];
+    let by = b[ky] - sy * b[kz];
+    let cx = c[kx] - sx * c[kz];
+    let cy = c[ky] - sy * c[kz];
+
+    // Scaled barycentric coordinates
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+
+    // Fall back to double precision on the edges
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+        v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+        w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+    }
+
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+
+    // Scaled hit distance
+    let az = sz * a[kz];
+    let bz = sz * b[kz];
+    let cz = sz * c[kz];
+    let t = (u * az + v * bz + w * cz) / det;
+    if t < query.t_min || query.t_max < t {
+        return None;
+    }
+
+    let det_rcp = 1.0 / det;
+    Some((t, Vec3::new(u * det_rcp, v * det_rcp, w * det_rcp)))
+}
+
+/// Indexed triangle mesh with optional per-vertex normals and UVs.
+/// Owns a bottom level BVH over its triangles, so it can be shared by many instances.
+pub struct TriangleMesh {
+    pub positions: Vec<Point3>,
+    // Per-vertex normals, empty for flat shading
+    pub normals: Vec<Vec3>,
+    // Per-vertex texture coordinates, may be empty
+    pub uvs: Vec<Vec2>,
+    // Per-vertex colors, may be empty
+    pub colors: Vec<Color>,
+    pub indices: Vec<[u32; 3]>,
+    pub material: Arc<dyn Material>,
+    // Bottom level BVH over the triangles
+    bvh: FlatBvh,
+    // Cached bounds of the whole mesh
+    min: Point3,
+    max: Point3,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Point3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<Vec2>,
+        indices: Vec<[u32; 3]>,
+        material: &Arc<dyn Material>,
+    ) -> Self {
+        assert!(normals.is_empty() || normals.len() == positions.len());
+        assert!(uvs.is_empty() || uvs.len() == positions.len());
+        let mut mesh = TriangleMesh {
+            positions,
+            normals,
+            uvs,
+            colors: Vec::new(),
+            indices,
+            material: material.clone(),
+            bvh: FlatBvh::default(),
+            min: Point3::ZERO,
+            max: Point3::ZERO,
+        };
+        mesh.build_bvh();
+        mesh
+    }
+
+    pub fn with_colors(mut self, colors: Vec<Color>) -> Self {
+        assert!(colors.is_empty() || colors.len() == self.positions.len());
+        self.colors = colors;
+        self
+    }
+
+    /// Build the bottom level BVH, needed again after changing positions or indices
+    pub fn build_bvh(&mut self) {
+        let triangle_bounds: Vec<HittableBounds> = (0..self.indices.len())
+            .map(|index| self.triangle_bounds(index))
+            .collect();
+        self.bvh = FlatBvh::build(&triangle_bounds);
+        self.min = self.positions.iter().fold(Vec3::MAX, |a, p| a.min(*p));
+        self.max = self.positions.iter().fold(Vec3::MIN, |a, p| a.max(*p));
+    }
+
+    fn vertices(&self, index: usize) -> (Point3, Point3, Point3) {
+        let [i0, i1, i2] = self.indices[index];
+        (
+            self.positions[i0 as usize],
+            self.positions[i1 as usize],
+            self.positions[i2 as usize],
+        )
+    }
+
+    fn intersect_triangle(&self, index: usize, query: RayQuery) -> Option<HitRecord> {
+        let (p0, p1, p2) = self.vertices(index);
+        let (t, bary) = intersect_triangle(query, p0, p1, p2)?;
+
+        let [i0, i1, i2] = self.indices[index].map(|i| i as usize);
+        let mut outward_normal = (p1 - p0).cross(p2 - p0).normalize_or_zero();
+
+        let shading_normal = if self.normals.is_empty() {
+            None
+        } else {
+            let n =
+                bary.x * self.normals[i0] + bary.y * self.normals[i1] + bary.z * self.normals[i2];
+            // Orient the geometric normal to agree with the shading normal
+            if n.dot(outward_normal) < 0.0 {
+                outward_normal = -outward_normal;
+            }
+            Some(n.normalize_or_zero())
+        };
+
+        let mut record = HitRecord::new(query.ray, t, outward_normal, self.material.clone());
+        record.primitive_id = index as u32;
+        if let Some(n) = shading_normal {
+            record.shading_normal = if record.front_face { n } else { -n };
+        }
+
+        // Derivatives from the UVs, or from the barycentric coordinates without them
+        let (dp1, dp2) = (p1 - p0, p2 - p0);
+        record.uv = Vec2::new(bary.y, bary.z);
+        record.dpdu = dp1;
+        record.dpdv = dp2;
+        if !self.uvs.is_empty() {
+            let (uv0, uv1, uv2) = (self.uvs[i0], self.uvs[i1], self.uvs[i2]);
+            record.uv = bary.x * uv0 + bary.y * uv1 + bary.z * uv2;
+            let (duv1, duv2) = (uv1 - uv0, uv2 - uv0);
+            let determinant = duv1.x * duv2.y - duv1.y * duv2.x;
+            if determinant.abs() > 1e-12 {
+                let inv_determinant = 1.0 / determinant;
+                record.dpdu = (duv2.y * dp1 - duv1.y * dp2) * inv_determinant;
+                record.dpdv = (duv1.x * dp2 - duv2.x * dp1) * inv_determinant;
+            }
+        }
+        if !self.colors.is_empty() {
+            record.vertex_color =
+                bary.x * self.colors[i0] + bary.y * self.colors[i1] + bary.z * self.colors[i2];
+        }
+
+        Some(record)
+    }
+
+    fn triangle_bounds(&self, index: usize) -> HittableBounds {
+        let (p0, p1, p2) = self.vertices(index);
+        HittableBounds::new(p0.min(p1).min(p2), p0.max(p1).max(p2), index)
+    }
+}
+
+impl RayHittable for TriangleMesh {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        self.bvh
+            .intersect(query, |index, query| self.intersect_triangle(index, query))
+    }
+
+    fn occludes(&self, query: RayQuery) -> bool {
+        self.bvh.any_hit(query, |index, query| {
+            let (p0, p1, p2) = self.vertices(index);
+            intersect_triangle(query, p0, p1, p2).is_some()
+        })
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        HittableBounds::new(self.min, self.max, hittable_index)
+    }
+}
+
+/// Places a shared hittable in the scene with an affine transform
+pub struct Instance {
+    object: Arc<dyn RayHittable>,
+    object_to_world: Mat4,
+    world_to_object: Mat4,
+    // Inverse transpose for transforming normals
+    normal_to_world: Mat3,
+}
+
+impl Instance {
+    pub fn new(object: Arc<dyn RayHittable>, transform: Mat4) -> Self {
+        let world_to_object = transform.inverse();
+        Instance {
+            object,
+            object_to_world: transform,
+            world_to_object,
+            normal_to_world: Mat3::from_mat4(world_to_object).transpose(),
+        }
+    }
+
+    pub fn transform(&self) -> Mat4 {
+        self.object_to_world
+    }
+
+    pub fn set_transform(&mut self, transform: Mat4) {
+        *self = Instance::new(self.object.clone(), transform);
+    }
+
+    /// Query in object space, the direction is not normalized so t is the same in both spaces
+    fn object_query(&self, query: RayQuery) -> RayQuery {
+        let ray = Ray::new(
+            self.world_to_object.transform_point3(query.ray.origin),
+            self.world_to_object.transform_vector3(query.ray.direction),
+            query.ray.time,
+        );
+        RayQuery { ray, ..query }
+    }
+}
+
+impl RayHittable for Instance {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let mut hit = self.object.intersect(self.object_query(query))?;
+
+        // The inverse transpose keeps the normal facing against the ray, so front_face holds
+        hit.point = query.ray.at(hit.t);
+        hit.normal = (self.normal_to_world * hit.normal).normalize();
+        hit.shading_normal = (self.normal_to_world * hit.shading_normal).normalize();
+        hit.dpdu = self.object_to_world.transform_vector3(hit.dpdu);
+        hit.dpdv = self.object_to_world.transform_vector3(hit.dpdv);
+        Some(hit)
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.object.is_bounded()
+    }
+
+    fn occludes(&self, query: RayQuery) -> bool {
+        self.object.occludes(self.object_query(query))
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        let object_bounds = self.object.compute_bounds(hittable_index);
+        let (object_min, object_max) = (object_bounds.min(), object_bounds.max());
+
+        // Transform all corners of the object space box
+        let mut min = Vec3::MAX;
+        let mut max = Vec3::MIN;
+        for corner in 0..8 {
+            let p = Point3::new(
+                if corner & 1 == 0 {
+                    object_min.x
+                } else {
+                    object_max.x
+                },
+                if corner & 2 == 0 {
+                    object_min.y
+                } else {
+                    object_max.y
+                },
+                if corner & 4 == 0 {
+                    object_min.z
+                } else {
+                    object_max.z
+                },
+            );
+            let p = self.object_to_world.transform_point3(p);
+            min = min.min(p);
+            max = max.max(p);
+        }
+        HittableBounds::new(min, max, hittable_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rays at the ends of the shutter should hit the sphere at its two centers, also beyond
+    /// them, and a static shutter keeps it at the first one
+    #[test]
+    fn test_moving_sphere() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let center0 = Point3::new(0.0, 0.0, 0.0);
+        let center1 = Point3::new(0.0, 2.0, 0.0);
+        let sphere = MovingSphere::new(center0, center1, 0.0, 1.0, 0.5, &material);
+        for (time, center) in [
+            (0.0, center0),
+            (1.0, center1),
+            (-1.0, center0),
+            (2.0, center1),
+        ] {
+            let query = RayQuery {
+                ray: Ray::new(center + Vec3::new(0.0, 0.0, 5.0), -Vec3::Z, time),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let hit = sphere.intersect(query).unwrap();
+            assert!((hit.point - (center + Vec3::new(0.0, 0.0, 0.5))).length() < 1e-5);
+        }
+        let bounds = sphere.compute_bounds(0);
+        for center in [center0, center1] {
+            assert!(
+                bounds.min().cmple(center - 0.5).all() && bounds.max().cmpge(center + 0.5).all()
+            );
+        }
+
+        let fixed = MovingSphere::new(center0, center1, 0.5, 0.5, 0.5, &material);
+        assert_eq!(fixed.center(0.5), center0);
+    }
+
+    /// A sphere scaled into an ellipsoid should hit and shade like the analytic surface
+    #[test]
+    fn test_instance_ellipsoid() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let sphere: Arc<dyn RayHittable> = Arc::new(Sphere::new(Point3::ZERO, 1.0, &material));
+        let transform = Mat4::from_translation(Vec3::new(0.0, 1.0, 0.0))
+            * Mat4::from_scale(Vec3::new(2.0, 1.0, 1.0));
+        let ellipsoid = Instance::new(sphere, transform);
+
+        let bounds = ellipsoid.compute_bounds(0);
+        assert!((bounds.min() - Vec3::new(-2.0, 0.0, -1.0)).length() < 1e-5);
+        assert!((bounds.max() - Vec3::new(2.0, 2.0, 1.0)).length() < 1e-5);
+
+        // Point on the ellipsoid x^2/4 + (y-1)^2 = 1 at 45 degrees
+        let target = Point3::new(2.0 * f32::sqrt(0.5), 1.0 + f32::sqrt(0.5), 0.0);
+        let origin = Point3::new(0.0, 1.0, 0.0) + 2.0 * (target - Point3::new(0.0, 1.0, 0.0));
+        let query = RayQuery {
+            ray: Ray::new(origin, target - origin, 0.0),
+            t_min: TRACE_EPSILON,
+            t_max: TRACE_INFINITY,
+        };
+        let hit = ellipsoid.intersect(query).unwrap();
+        assert!((hit.point - target).length() < 1e-4);
+        assert!(hit.front_face);
+
+        // The gradient of the implicit surface gives the normal
+        let expected = Vec3::new(target.x / 4.0, target.y - 1.0, 0.0).normalize();
+        assert!((hit.normal - expected).length() < 1e-4);
+    }
+
+    /// Rays aimed at the quadrics from outside hit at the analytic distance
+    #[test]
+    fn test_quadric_distances() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let axis = Vec3::new(1.0, 2.0, 0.5).normalize();
+        let side = axis.any_orthonormal_vector();
+        let center = Point3::new(1.0, -2.0, 3.0);
+
+        // Shape, distance of the hit from the center and expected normal
+        let shapes: Vec<(Box<dyn RayHittable>, f32, Vec3)> = vec![
+            (
+                Box::new(Cylinder::new(
+                    center - axis,
+                    center + axis,
+                    0.5,
+                    true,
+                    &material,
+                )),
+                0.5,
+                side,
+            ),
+            (
+                Box::new(Cone::new(
+                    center - axis,
+                    1.0,
+                    center + axis,
+                    0.0,
+                    true,
+                    &material,
+                )),
+                0.5,
+                (2.0 * side + axis).normalize(),
+            ),
+            (
+                Box::new(Capsule::new(center - axis, center + axis, 0.5, &material)),
+                0.5,
+                side,
+            ),
+            (
+                Box::new(Torus::new(center, axis, 1.0, 0.25, &material)),
+                1.25,
+                side,
+            ),
+        ];
+
+        for (shape, distance, normal) in shapes {
+            // From the side through the middle of the axis
+            let query = RayQuery {
+                ray: Ray::new(center + 4.0 * side, -2.0 * side, 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let hit = shape.intersect(query).unwrap();
+            assert!((hit.point - (center + distance * side)).length() < 1e-3);
+            assert!(hit.front_face);
+            assert!((hit.normal - normal).length() < 1e-3);
+            assert!(hit.dpdu.dot(normal).abs() < 1e-3 && hit.dpdv.dot(normal).abs() < 1e-3);
+            assert!(hit.dpdu.cross(hit.dpdv).length() > 1e-3);
+
+            let bounds = shape.compute_bounds(0);
+            assert!(hit.point.cmpge(bounds.min()).all() && hit.point.cmple(bounds.max()).all());
+        }
+    }
+
+    /// The crossing walk clips spans to the query range, and far from the origin it still has
+    /// to step past each crossing
+    #[test]
+    fn test_sphere_intervals() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let sphere = Sphere::new(Point3::ZERO, 1.0, &material);
+        let ray = Ray::new(Point3::new(-5.0, 0.0, 0.0), Vec3::X, 0.0);
+        let ends = |t_min: f32, t_max: f32| {
+            let intervals = sphere.intersect_intervals(RayQuery { ray, t_min, t_max });
+            intervals
+                .iter()
+                .map(|interval| {
+                    (
+                        interval.enter.as_ref().map(|hit| hit.t),
+                        interval.exit.as_ref().map(|hit| hit.t),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ends(0.0, TRACE_INFINITY), [(Some(4.0), Some(6.0))]);
+        assert_eq!(ends(5.0, TRACE_INFINITY), [(None, Some(6.0))]);
+        assert_eq!(ends(0.0, 5.0), [(Some(4.0), None)]);
+        assert_eq!(ends(4.5, 5.5), [(None, None)]);
+        assert!(ends(0.0, 3.0).is_empty() && ends(7.0, TRACE_INFINITY).is_empty());
+
+        let far = Sphere::new(Point3::new(1e5, 0.0, 0.0), 1e3, &material);
+        let intervals = far.intersect_intervals(RayQuery {
+            ray: Ray::new(Point3::ZERO, Vec3::X, 0.0),
+            t_min: -TRACE_INFINITY,
+            t_max: TRACE_INFINITY,
+        });
+        assert_eq!(intervals.len(), 1);
+        let (enter, exit) = (intervals[0].enter.as_ref(), intervals[0].exit.as_ref());
+        assert!((exit.unwrap().t - enter.unwrap().t - 2e3).abs() < 2.0);
+    }
+
+    /// Sphere UVs should follow the spherical coordinates, with matching derivatives
+    #[test]
+    fn test_sphere_uv() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let center = Point3::new(1.0, 2.0, -1.0);
+        let sphere = Sphere::new(center, 2.0, &material);
+        let point_at = |uv: Vec2| {
+            let (sin_phi, cos_phi) = (2.0 * std::f32::consts::PI * uv.x).sin_cos();
+            let (sin_theta, cos_theta) = (std::f32::consts::PI * uv.y).sin_cos();
+            center + 2.0 * Vec3::new(-cos_phi * sin_theta, -cos_theta, sin_phi * sin_theta)
+        };
+
+        let mut rng = RayRng::new(0);
+        for _ in 0..100 {
+            let direction = random_unit_vector(&mut rng);
+            let query = RayQuery {
+                ray: Ray::new(center + 5.0 * direction, -direction, 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let hit = sphere.intersect(query).unwrap();
+            assert!((point_at(hit.uv) - hit.point).length() < 1e-3);
+
+            // Central differences of the parameterisation
+            let h = 1e-3;
+            let dpdu = (point_at(hit.uv + Vec2::new(h, 0.0))
+                - point_at(hit.uv - Vec2::new(h, 0.0)))
+                / (2.0 * h);
+            let dpdv = (point_at(hit.uv + Vec2::new(0.0, h))
+                - point_at(hit.uv - Vec2::new(0.0, h)))
+                / (2.0 * h);
+            assert!((hit.dpdu - dpdu).length() < 1e-2 * dpdu.length().max(1.0));
+            assert!((hit.dpdv - dpdv).length() < 1e-2 * dpdv.length().max(1.0));
+        }
+    }
+
+    /// Quads, disks, boxes and planes should hit inside their extent with the right normal and
+    /// uv, and miss outside it
+    #[test]
+    fn test_flat_primitives() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let query = |origin: Point3, direction: Vec3| RayQuery {
+            ray: Ray::new(origin, direction, 0.0),
+            t_min: TRACE_EPSILON,
+            t_max: TRACE_INFINITY,
+        };
+        let down = -Vec3::Z;
+
+        let quad = Quad::new(
+            Point3::new(-1.0, -1.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            &material,
+        );
+        let hit = quad
+            .intersect(query(Point3::new(0.5, 0.0, 3.0), down))
+            .unwrap();
+        assert!((hit.t - 3.0).abs() < 1e-5);
+        assert!(hit.front_face && (hit.normal - Vec3::Z).length() < 1e-5);
+        assert!((hit.uv - Vec2::new(0.75, 0.5)).length() < 1e-5);
+        let hit = quad
+            .intersect(query(Point3::new(0.5, 0.0, -3.0), Vec3::Z))
+            .unwrap();
+        assert!(!hit.front_face && (hit.normal + Vec3::Z).length() < 1e-5);
+        assert!(quad
+            .intersect(query(Point3::new(1.5, 0.0, 3.0), down))
+            .is_none());
+
+        let disk = Disk::new(Point3::ZERO, Vec3::Z, 1.0, &material);
+        let hit = disk
+            .intersect(query(Point3::new(0.5, 0.0, 3.0), down))
+            .unwrap();
+        assert!((hit.t - 3.0).abs() < 1e-5);
+        assert!((hit.normal - Vec3::Z).length() < 1e-5);
+        // The uv square maps onto the disk, so the center is at (0.5, 0.5)
+        assert!(((hit.uv - Vec2::splat(0.5)).length() - 0.25).abs() < 1e-5);
+        let center = disk
+            .intersect(query(Point3::new(0.0, 0.0, 3.0), down))
+            .unwrap();
+        assert!((center.uv - Vec2::splat(0.5)).length() < 1e-5);
+        assert!(disk
+            .intersect(query(Point3::new(0.8, 0.8, 3.0), down))
+            .is_none());
+
+        let aabox = AaBox::new(Point3::splat(1.0), Point3::splat(-1.0), &material);
+        let hit = aabox
+            .intersect(query(Point3::new(0.2, 0.3, 5.0), down))
+            .unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-5);
+        assert!((hit.normal - Vec3::Z).length() < 1e-5);
+        assert!((hit.uv - Vec2::new(0.6, 0.65)).length() < 1e-5);
+        assert_eq!(hit.primitive_id, 0);
+        let hit = aabox
+            .intersect(query(Point3::new(5.0, 0.2, 0.3), -Vec3::X))
+            .unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-5);
+        assert!((hit.normal - Vec3::X).length() < 1e-5);
+        assert_eq!(hit.primitive_id, 1);
+        // From inside the box the far side is hit as a back face
+        let hit = aabox.intersect(query(Point3::ZERO, Vec3::Y)).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-5);
+        assert!(!hit.front_face && (hit.normal + Vec3::Y).length() < 1e-5);
+        assert!(aabox
+            .intersect(query(Point3::new(1.5, 0.0, 5.0), down))
+            .is_none());
+
+        let plane = Plane::new(Point3::new(0.0, 1.0, 0.0), Vec3::Y, &material);
+        let hit = plane
+            .intersect(query(Point3::new(2.0, 3.0, 1.0), -Vec3::Y))
+            .unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-5);
+        assert!((hit.normal - Vec3::Y).length() < 1e-5);
+        // Planar uv in world units along the tangents
+        assert!((hit.uv.length() - 5.0_f32.sqrt()).abs() < 1e-5);
+        assert!(hit.dpdu.dot(Vec3::Y).abs() < 1e-5 && hit.dpdv.dot(Vec3::Y).abs() < 1e-5);
+        let origin = plane
+            .intersect(query(Point3::new(0.0, 3.0, 0.0), -Vec3::Y))
+            .unwrap();
+        assert!(origin.uv.length() < 1e-5);
+        assert!(plane
+            .intersect(query(Point3::new(2.0, 3.0, 1.0), Vec3::X))
+            .is_none());
+        assert!(plane
+            .intersect(query(Point3::new(2.0, 3.0, 1.0), Vec3::Y))
+            .is_none());
+        assert!(!plane.is_bounded());
+    }
+
+    /// Rays through a shared edge or vertex must not slip between triangles
+    #[test]
+    fn test_triangle_watertight() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let positions = vec![
+            Point3::new(-1.0, 0.0, -1.0),
+            Point3::new(1.0, 0.0, -1.0),
+            Point3::new(1.0, 0.0, 1.0),
+            Point3::new(-1.0, 0.0, 1.0),
+        ];
+        let indices = vec![[0, 2, 1], [0, 3, 2]];
+        let mesh = TriangleMesh::new(positions, Vec::new(), Vec::new(), indices, &material);
+
+        // Along the diagonal edge, including both shared vertices
+        for i in 0..=64 {
+            let s = -1.0 + 2.0 * i as f32 / 64.0;
+            let query = RayQuery {
+                ray: Ray::new(Point3::new(s, 1.0, s), Vec3::new(0.0, -1.0, 0.0), 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let hit = mesh.intersect(query).expect("ray slipped through the mesh");
+            assert!((hit.t - 1.0).abs() < 1e-5);
+            assert!(hit.front_face);
+            assert!(hit.normal.y > 0.0);
         }
     }
 }
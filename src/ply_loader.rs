@@ -0,0 +1,586 @@
+use crate::material::*;
+use crate::object::*;
+use crate::scene::*;
+use crate::shared::*;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Error while loading a PLY file
+#[derive(Debug)]
+pub enum PlyError {
+    Io(PathBuf, std::io::Error),
+    Format(PathBuf, String),
+}
+
+impl fmt::Display for PlyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlyError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            PlyError::Format(path, message) => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for PlyError {}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Encoding {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum ScalarType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Option<ScalarType> {
+        match name {
+            "char" | "int8" => Some(ScalarType::Int8),
+            "uchar" | "uint8" => Some(ScalarType::UInt8),
+            "short" | "int16" => Some(ScalarType::Int16),
+            "ushort" | "uint16" => Some(ScalarType::UInt16),
+            "int" | "int32" => Some(ScalarType::Int32),
+            "uint" | "uint32" => Some(ScalarType::UInt32),
+            "float" | "float32" => Some(ScalarType::Float32),
+            "double" | "float64" => Some(ScalarType::Float64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ScalarType::Int8 | ScalarType::UInt8 => 1,
+            ScalarType::Int16 | ScalarType::UInt16 => 2,
+            ScalarType::Int32 | ScalarType::UInt32 | ScalarType::Float32 => 4,
+            ScalarType::Float64 => 8,
+        }
+    }
+
+    /// Scale which maps integer colors to 0..1
+    fn color_scale(self) -> f32 {
+        match self {
+            ScalarType::UInt8 => 1.0 / 255.0,
+            ScalarType::UInt16 => 1.0 / 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+enum PropertyType {
+    Scalar(ScalarType),
+    List(ScalarType, ScalarType),
+}
+
+struct Property {
+    name: String,
+    ty: PropertyType,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn scalar_index(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .filter(|p| matches!(p.ty, PropertyType::Scalar(_)))
+            .position(|p| names.contains(&p.name.as_str()))
+    }
+
+    fn scalar_type(&self, index: usize) -> ScalarType {
+        self.properties
+            .iter()
+            .filter_map(|p| match p.ty {
+                PropertyType::Scalar(ty) => Some(ty),
+                PropertyType::List(..) => None,
+            })
+            .nth(index)
+            .unwrap()
+    }
+}
+
+/// Values of one element: scalars row by row and the first list property
+struct ElementData {
+    scalar_count: usize,
+    scalars: Vec<f64>,
+    lists: Vec<Vec<u32>>,
+}
+
+impl ElementData {
+    fn scalar(&self, row: usize, index: usize) -> f64 {
+        self.scalars[row * self.scalar_count + index]
+    }
+}
+
+/// Reads scalars from the body in either encoding
+struct BodyReader<'a> {
+    encoding: Encoding,
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BodyReader<'_> {
+    /// Most values the rest of the body can hold, each takes at least a byte. Header counts
+    /// are untrusted, so preallocation is capped by this.
+    fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    fn read(&mut self, ty: ScalarType) -> Result<f64, String> {
+        match self.encoding {
+            Encoding::Ascii => self.read_ascii(),
+            _ => self.read_binary(ty),
+        }
+    }
+
+    fn read_ascii(&mut self) -> Result<f64, String> {
+        while self.position < self.data.len() && self.data[self.position].is_ascii_whitespace() {
+            self.position += 1;
+        }
+        let start = self.position;
+        while self.position < self.data.len() && !self.data[self.position].is_ascii_whitespace() {
+            self.position += 1;
+        }
+        if start == self.position {
+            return Err(String::from("unexpected end of file"));
+        }
+        let token = String::from_utf8_lossy(&self.data[start..self.position]);
+        token
+            .parse()
+            .map_err(|_| format!("invalid value '{}'", token))
+    }
+
+    fn read_binary(&mut self, ty: ScalarType) -> Result<f64, String> {
+        let size = ty.size();
+        let bytes = self
+            .data
+            .get(self.position..self.position + size)
+            .ok_or_else(|| String::from("unexpected end of file"))?;
+        self.position += size;
+
+        let mut buffer = [0u8; 8];
+        buffer[..size].copy_from_slice(bytes);
+        if self.encoding == Encoding::BinaryBigEndian {
+            buffer[..size].reverse();
+        }
+        let value = match ty {
+            ScalarType::Int8 => buffer[0] as i8 as f64,
+            ScalarType::UInt8 => buffer[0] as f64,
+            ScalarType::Int16 => i16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+            ScalarType::UInt16 => u16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+            ScalarType::Int32 => i32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+            ScalarType::UInt32 => u32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+            ScalarType::Float32 => f32::from_le_bytes(buffer[..4].try_into().unwrap()) as f64,
+            ScalarType::Float64 => f64::from_le_bytes(buffer),
+        };
+        Ok(value)
+    }
+
+    fn read_element(&mut self, element: &Element) -> Result<ElementData, String> {
+        let scalar_count = element
+            .properties
+            .iter()
+            .filter(|p| matches!(p.ty, PropertyType::Scalar(_)))
+            .count();
+        let mut data = ElementData {
+            scalar_count,
+            scalars: Vec::with_capacity(
+                scalar_count
+                    .saturating_mul(element.count)
+                    .min(self.remaining()),
+            ),
+            lists: Vec::new(),
+        };
+
+        for row in 0..element.count {
+            let mut first_list = true;
+            for property in &element.properties {
+                match property.ty {
+                    PropertyType::Scalar(ty) => data.scalars.push(self.read(ty)?),
+                    PropertyType::List(count_ty, item_ty) => {
+                        let count = self.read(count_ty)?;
+                        if count < 0.0 {
+                            return Err(format!(
+                                "negative list length {} in {} {}",
+                                count, element.name, row
+                            ));
+                        }
+                        let count = count as usize;
+                        let mut items = Vec::with_capacity(count.min(self.remaining()));
+                        for _ in 0..count {
+                            let item = self.read(item_ty)?;
+                            if item < 0.0 {
+                                return Err(format!(
+                                    "negative index {} in {} {}",
+                                    item, element.name, row
+                                ));
+                            }
+                            items.push(item as u32);
+                        }
+                        // Only the first list (vertex indices) is kept
+                        if first_list {
+                            data.lists.push(items);
+                            first_list = false;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+fn parse_header(header: &str) -> Result<(Encoding, Vec<Element>), String> {
+    let mut lines = header.lines();
+    if lines.next().map(str::trim) != Some("ply") {
+        return Err(String::from("missing 'ply' magic"));
+    }
+
+    let mut encoding = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            ["format", format, _version] => {
+                encoding = Some(match *format {
+                    "ascii" => Encoding::Ascii,
+                    "binary_little_endian" => Encoding::BinaryLittleEndian,
+                    "binary_big_endian" => Encoding::BinaryBigEndian,
+                    _ => return Err(format!("unknown format '{}'", format)),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| format!("invalid element count '{}'", count))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count_ty, item_ty, name] => {
+                let ty = PropertyType::List(
+                    ScalarType::parse(count_ty)
+                        .ok_or_else(|| format!("unknown type '{}'", count_ty))?,
+                    ScalarType::parse(item_ty)
+                        .ok_or_else(|| format!("unknown type '{}'", item_ty))?,
+                );
+                let element = elements.last_mut().ok_or("property before element")?;
+                element.properties.push(Property {
+                    name: name.to_string(),
+                    ty,
+                });
+            }
+            ["property", ty, name] => {
+                let ty = ScalarType::parse(ty).ok_or_else(|| format!("unknown type '{}'", ty))?;
+                let element = elements.last_mut().ok_or("property before element")?;
+                element.properties.push(Property {
+                    name: name.to_string(),
+                    ty: PropertyType::Scalar(ty),
+                });
+            }
+            ["comment", ..] | ["obj_info", ..] | ["end_header"] | [] => {}
+            _ => return Err(format!("invalid header line '{}'", line)),
+        }
+    }
+
+    let encoding = encoding.ok_or("missing format line")?;
+    Ok((encoding, elements))
+}
+
+// Smallest estimated point radius, in model units
+const MIN_POINT_RADIUS: f32 = 1e-3;
+
+/// Load a PLY file into the scene. Files with faces become a triangle mesh, vertex-only files
+/// become a point set of spheres, or disks if they have normals. Per-vertex colors are used as
+/// albedo.
+/// If point_radius is None it is estimated from the point density.
+pub fn load_ply(path: &Path, scene: &mut Scene, point_radius: Option<f32>) -> Result<(), PlyError> {
+    let bytes = std::fs::read(path).map_err(|e| PlyError::Io(path.to_path_buf(), e))?;
+    let format_error = |message: String| PlyError::Format(path.to_path_buf(), message);
+
+    // The header is ASCII text terminated by end_header
+    let marker = b"end_header";
+    let header_end = bytes
+        .windows(marker.len())
+        .position(|w| w == marker)
+        .ok_or_else(|| format_error(String::from("missing end_header")))?;
+    let mut body_start = header_end + marker.len();
+    if bytes.get(body_start) == Some(&b'\r') {
+        body_start += 1;
+    }
+    if bytes.get(body_start) == Some(&b'\n') {
+        body_start += 1;
+    }
+    let header = String::from_utf8_lossy(&bytes[..header_end]);
+    let (encoding, elements) = parse_header(&header).map_err(format_error)?;
+
+    // Read all elements in order, keeping vertices and faces
+    let mut reader = BodyReader {
+        encoding,
+        data: &bytes[body_start..],
+        position: 0,
+    };
+    let mut vertex_data = None;
+    let mut face_data = None;
+    for element in &elements {
+        let data = reader
+            .read_element(element)
+            .map_err(|e| format_error(format!("element '{}': {}", element.name, e)))?;
+        match element.name.as_str() {
+            "vertex" => vertex_data = Some(data),
+            "face" => face_data = Some(data),
+            _ => {}
+        }
+    }
+
+    let vertex_element = elements
+        .iter()
+        .find(|e| e.name == "vertex")
+        .ok_or_else(|| format_error(String::from("missing vertex element")))?;
+    let vertex_data = vertex_data.unwrap();
+
+    let find = |names: &[&str]| vertex_element.scalar_index(names);
+    let (Some(x), Some(y), Some(z)) = (find(&["x"]), find(&["y"]), find(&["z"])) else {
+        return Err(format_error(String::from("vertex element without x, y, z")));
+    };
+    let read_vec3 = |row: usize, i: [usize; 3], scale: f32| {
+        Vec3::new(
+            vertex_data.scalar(row, i[0]) as f32,
+            vertex_data.scalar(row, i[1]) as f32,
+            vertex_data.scalar(row, i[2]) as f32,
+        ) * scale
+    };
+
+    let count = vertex_element.count;
+    let positions: Vec<Point3> = (0..count)
+        .map(|row| read_vec3(row, [x, y, z], 1.0))
+        .collect();
+
+    let normals: Vec<Vec3> = match (find(&["nx"]), find(&["ny"]), find(&["nz"])) {
+        (Some(nx), Some(ny), Some(nz)) => (0..count)
+            .map(|row| read_vec3(row, [nx, ny, nz], 1.0).normalize_or_zero())
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let colors: Vec<Color> = match (
+        find(&["red", "r", "diffuse_red"]),
+        find(&["green", "g", "diffuse_green"]),
+        find(&["blue", "b", "diffuse_blue"]),
+    ) {
+        (Some(r), Some(g), Some(b)) => {
+            let scale = vertex_element.scalar_type(r).color_scale();
+            (0..count)
+                .map(|row| read_vec3(row, [r, g, b], scale))
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+
+    let uvs: Vec<Vec2> = match (
+        find(&["u", "s", "texture_u", "texture_s"]),
+        find(&["v", "t", "texture_v", "texture_t"]),
+    ) {
+        (Some(u), Some(v)) => (0..count)
+            .map(|row| {
+                Vec2::new(
+                    vertex_data.scalar(row, u) as f32,
+                    vertex_data.scalar(row, v) as f32,
+                )
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let material: Arc<dyn Material> = if colors.is_empty() {
+        Arc::new(Lambertian::new(Color::new(0.8, 0.8, 0.8)))
+    } else {
+        Arc::new(VertexColorLambertian::new(Color::ONE))
+    };
+
+    match face_data {
+        Some(faces) if !faces.lists.is_empty() => {
+            // Triangulate polygons as a fan
+            let mut indices = Vec::with_capacity(faces.lists.len());
+            for face in &faces.lists {
+                if let Some(i) = face.iter().find(|i| **i as usize >= count) {
+                    return Err(format_error(format!("vertex index {} out of range", i)));
+                }
+                for i in 1..face.len().saturating_sub(1) {
+                    indices.push([face[0], face[i], face[i + 1]]);
+                }
+            }
+            let mesh =
+                TriangleMesh::new(positions, normals, uvs, indices, &material).with_colors(colors);
+            scene.add_mesh(Arc::new(mesh));
+        }
+        _ => {
+            // Point set, estimate the spacing of points on a scanned surface
+            let radius = point_radius.unwrap_or_else(|| {
+                let min = positions.iter().fold(Vec3::MAX, |a, p| a.min(*p));
+                let max = positions.iter().fold(Vec3::MIN, |a, p| a.max(*p));
+                let radius = 0.5 * (max - min).length() / (count.max(1) as f32).sqrt();
+                // A single point or coincident points have no spacing
+                radius.max(MIN_POINT_RADIUS)
+            });
+            for (i, position) in positions.iter().enumerate() {
+                let point_material = match colors.get(i) {
+                    Some(albedo) => Arc::new(Lambertian::new(*albedo)),
+                    None => material.clone(),
+                };
+                match normals.get(i) {
+                    Some(normal) => scene.objects.push(Box::new(Disk::new(
+                        *position,
+                        *normal,
+                        radius,
+                        &point_material,
+                    ))),
+                    None => scene.objects.push(Box::new(Sphere::new(
+                        *position,
+                        radius,
+                        &point_material,
+                    ))),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_bytes(name: &str, bytes: &[u8]) -> Result<Scene, PlyError> {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, bytes).unwrap();
+        let mut scene = Scene::new();
+        load_ply(&path, &mut scene, None)?;
+        Ok(scene)
+    }
+
+    #[test]
+    fn test_ply_ascii_and_binary_match() {
+        let header = |format: &str| {
+            format!(
+                "ply\nformat {} 1.0\nelement vertex 4\nproperty float x\nproperty float y\n\
+                 property float z\nproperty uchar red\nproperty uchar green\nproperty uchar blue\n\
+                 element face 1\nproperty list uchar int vertex_indices\nend_header\n",
+                format
+            )
+        };
+        let vertices = [
+            [0.0f32, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+
+        let mut ascii = header("ascii");
+        for v in vertices {
+            ascii += &format!("{} {} {} 255 128 0\n", v[0], v[1], v[2]);
+        }
+        ascii += "4 0 1 2 3\n";
+
+        let mut little = header("binary_little_endian").into_bytes();
+        let mut big = header("binary_big_endian").into_bytes();
+        for v in vertices {
+            for c in v {
+                little.extend(c.to_le_bytes());
+                big.extend(c.to_be_bytes());
+            }
+            little.extend([255, 128, 0]);
+            big.extend([255, 128, 0]);
+        }
+        little.push(4);
+        big.push(4);
+        for i in 0..4i32 {
+            little.extend(i.to_le_bytes());
+            big.extend(i.to_be_bytes());
+        }
+
+        for (name, bytes) in [
+            ("one_weekend_test_ascii.ply", ascii.as_bytes()),
+            ("one_weekend_test_le.ply", &little),
+            ("one_weekend_test_be.ply", &big),
+        ] {
+            let scene = load_bytes(name, bytes).unwrap();
+            // Quad split into two triangles of a single mesh
+            assert_eq!(scene.objects.len(), 1);
+
+            let query = RayQuery {
+                ray: Ray::new(Point3::new(0.75, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let hit = scene
+                .objects
+                .iter()
+                .find_map(|o| o.intersect(query))
+                .unwrap();
+            assert!((hit.t - 1.0).abs() < 1e-5);
+            assert!((hit.vertex_color - Color::new(1.0, 128.0 / 255.0, 0.0)).length() < 1e-5);
+        }
+    }
+
+    /// A lone point still gets a usable sphere, and negative face indices are rejected
+    #[test]
+    fn test_ply_degenerate_input() {
+        let point = "ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\n\
+                     property float y\nproperty float z\nend_header\n1 2 3\n";
+        let scene = load_bytes("one_weekend_test_point.ply", point.as_bytes()).unwrap();
+        let query = RayQuery {
+            ray: Ray::new(Point3::new(1.0, 2.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0),
+            t_min: TRACE_EPSILON,
+            t_max: TRACE_INFINITY,
+        };
+        let hit = scene.objects[0].intersect(query).unwrap();
+        assert!(hit.normal.is_finite() && hit.normal.z > 0.9);
+
+        let negative = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\n\
+                        property float y\nproperty float z\nelement face 2\n\
+                        property list uchar int vertex_indices\nend_header\n\
+                        0 0 0\n1 0 0\n0 1 0\n3 0 1 2\n3 0 -1 2\n";
+        let Err(err) = load_bytes("one_weekend_test_negative.ply", negative.as_bytes()) else {
+            panic!("negative index accepted");
+        };
+        assert!(err.to_string().contains("negative index -1 in face 1"));
+    }
+
+    /// Counts far beyond the size of the file are errors rather than huge allocations
+    #[test]
+    fn test_ply_malformed_counts() {
+        let vertices = "ply\nformat ascii 1.0\nelement vertex 18446744073709551615\n\
+                        property float x\nproperty float y\nproperty float z\nend_header\n\
+                        1 2 3\n";
+        let Err(err) = load_bytes("one_weekend_test_huge_count.ply", vertices.as_bytes()) else {
+            panic!("huge vertex count accepted");
+        };
+        assert!(err.to_string().contains("unexpected end of file"));
+
+        let mut list = b"ply\nformat binary_little_endian 1.0\nelement vertex 0\n\
+                         property float x\nelement face 1\n\
+                         property list uint int vertex_indices\nend_header\n"
+            .to_vec();
+        list.extend(u32::MAX.to_le_bytes());
+        list.extend(0i32.to_le_bytes());
+        let Err(err) = load_bytes("one_weekend_test_huge_list.ply", &list) else {
+            panic!("huge list length accepted");
+        };
+        assert!(err.to_string().contains("unexpected end of file"));
+    }
+}
@@ -1,13 +1,26 @@
 use crate::camera::*;
 use crate::scene::*;
 use crate::shared::*;
+use crate::volume::*;
 use crate::BufferPacket;
 use crossbeam_channel::Sender;
 use rayon::prelude::*;
 use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
-/// Recursive ray tracing
-fn ray_color(rng: &mut RayRng, ray: Ray, scene: &Scene, depth: i32, ray_count: &mut u32) -> Color {
+// Cosine of the angle at which the sun disk fades out
+const SUN_COS_MIN: f32 = 0.99;
+
+/// Recursive ray tracing, medium is the participating medium the ray travels through.
+/// Sun sampled means the sun was already sampled directly towards this direction.
+fn ray_color(
+    rng: &mut RayRng,
+    ray: Ray,
+    scene: &Scene,
+    medium: Option<&dyn Medium>,
+    sun_sampled: bool,
+    depth: i32,
+    ray_count: &mut u32,
+) -> Color {
     if depth <= 0 {
         return Color::ZERO;
     }
@@ -21,26 +34,180 @@ fn ray_color(rng: &mut RayRng, ray: Ray, scene: &Scene, depth: i32, ray_count: &
     let hit_option = scene.intersect(query);
     *ray_count += 1;
 
+    // Scatter inside the medium before reaching the next surface
+    if let Some(medium) = medium {
+        let t_max = hit_option.as_ref().map_or(TRACE_INFINITY, |hit| hit.t);
+        if let Some(scatter) = medium.sample(rng, &ray, t_max) {
+            let incoming = Ray::new(ray.at(scatter.t), ray.direction.normalize(), ray.time);
+
+            // Light from the sun reaching the scattering event directly
+            let sample_sun = scene.background == Background::Sky;
+            let direct = if sample_sun {
+                sun_light(rng, scene, &incoming, scatter.phase, medium, ray_count)
+            } else {
+                Color::ZERO
+            };
+
+            let direction = scatter.phase.sample(rng, incoming.direction);
+            let scattered_ray = Ray::new(incoming.origin, direction, ray.time);
+            return scatter.attenuation
+                * (direct
+                    + ray_color(
+                        rng,
+                        scattered_ray,
+                        scene,
+                        Some(medium),
+                        sample_sun,
+                        depth - 1,
+                        ray_count,
+                    ));
+        }
+    }
+
     // If we hit something
-    if let Some(hit) = hit_option {
+    if let Some(mut hit) = hit_option {
+        if let Some(shading_normal) = hit.material.shading_normal(&ray, &hit) {
+            hit.shading_normal = shading_normal;
+        }
+        let emitted = hit.material.emitted(&hit);
         let scatter_option = hit.material.scatter(rng, &ray, &hit);
 
+        // Enter or leave a medium through its boundary
+        let next_medium = match hit.material.medium() {
+            Some(boundary_medium) if hit.front_face => Some(boundary_medium),
+            Some(_) => None,
+            None => medium,
+        };
+        // Rays keep their direction through medium boundaries
+        let sun_sampled = sun_sampled && hit.material.medium().is_some();
+
         // Recurse
         if let Some(scatter) = scatter_option {
-            return scatter.attenuation
-                * ray_color(rng, scatter.scattered_ray, scene, depth - 1, ray_count);
+            return emitted
+                + scatter.attenuation
+                    * ray_color(
+                        rng,
+                        scatter.scattered_ray,
+                        scene,
+                        next_medium,
+                        sun_sampled,
+                        depth - 1,
+                        ray_count,
+                    );
         }
 
-        return Color::ZERO;
+        return emitted;
     }
 
+    match scene.background {
+        Background::Sky => sky_color(&ray, !sun_sampled),
+        Background::Solid(color) => color,
+    }
+}
+
+/// Sample a direction towards the sun from a scattering event in a medium and return the
+/// light it scatters along the incoming ray
+fn sun_light(
+    rng: &mut RayRng,
+    scene: &Scene,
+    incoming: &Ray,
+    phase: PhaseFunction,
+    medium: &dyn Medium,
+    ray_count: &mut u32,
+) -> Color {
+    // Uniform direction within the cone of the sun disk
+    let sun_direction = sun_direction();
+    let cos_theta = 1.0 - rng.gen_range(0.0..1.0) * (1.0 - SUN_COS_MIN);
+    let sin_theta = f32::sqrt(1.0 - cos_theta * cos_theta);
+    let phi = rng.gen_range(0.0..2.0 * std::f32::consts::PI);
+    let tangent = sun_direction.any_orthonormal_vector();
+    let bitangent = sun_direction.cross(tangent);
+    let direction =
+        sin_theta * (phi.cos() * tangent + phi.sin() * bitangent) + cos_theta * sun_direction;
+    let pdf = 1.0 / (2.0 * std::f32::consts::PI * (1.0 - SUN_COS_MIN));
+
+    let light = sun_radiance(direction) * phase.evaluate(incoming.direction.dot(direction)) / pdf;
+    let shadow_ray = Ray::new(incoming.origin, direction, incoming.time);
+    light * shadow_transmittance(rng, scene, shadow_ray, medium, ray_count)
+}
+
+/// Fraction of the light arriving along a shadow ray from infinity
+fn shadow_transmittance(
+    rng: &mut RayRng,
+    scene: &Scene,
+    ray: Ray,
+    medium: &dyn Medium,
+    ray_count: &mut u32,
+) -> f32 {
+    // Any hit test first, most shadow rays are blocked by opaque surfaces
+    let query = RayQuery {
+        ray,
+        t_min: TRACE_EPSILON,
+        t_max: TRACE_INFINITY,
+    };
+    *ray_count += 1;
+    if scene.occluded(query) {
+        return 0.0;
+    }
+    transmittance(rng, scene, ray, Some(medium), ray_count)
+}
+
+/// Transmittance of the media along an unoccluded ray. Opaque surfaces block it, medium
+/// boundaries are crossed and the media attenuate it with their transmittance estimate.
+fn transmittance(
+    rng: &mut RayRng,
+    scene: &Scene,
+    ray: Ray,
+    medium: Option<&dyn Medium>,
+    ray_count: &mut u32,
+) -> f32 {
+    let query = RayQuery {
+        ray,
+        t_min: TRACE_EPSILON,
+        t_max: TRACE_INFINITY,
+    };
+    let hit_option = scene.intersect(query);
+    *ray_count += 1;
+
+    let t_max = hit_option.as_ref().map_or(TRACE_INFINITY, |hit| hit.t);
+    let transmittance_medium = medium.map_or(1.0, |medium| medium.transmittance(rng, &ray, t_max));
+    let Some(hit) = hit_option else {
+        return transmittance_medium;
+    };
+    let Some(boundary_medium) = hit.material.medium() else {
+        return 0.0;
+    };
+    if transmittance_medium == 0.0 {
+        return 0.0;
+    }
+
+    // Continue behind the boundary, inside the medium when entering it
+    let next_medium = hit.front_face.then_some(boundary_medium);
+    let next_ray = Ray::new(hit.point, ray.direction, ray.time);
+    transmittance_medium * transmittance(rng, scene, next_ray, next_medium, ray_count)
+}
+
+fn sun_direction() -> Vec3 {
+    Vec3::new(0.5, 0.4, 0.4).normalize()
+}
+
+/// Light from the sun disk along a direction
+fn sun_radiance(direction: Vec3) -> Color {
+    let sun_amount = smoothstep(SUN_COS_MIN, 0.999, sun_direction().dot(direction));
+    sun_amount * Color::new(40.0, 40.0, 35.0) // Sun color
+}
+
+/// Procedural sky with a sun, which is left out when it was sampled directly
+fn sky_color(ray: &Ray, include_sun: bool) -> Color {
     // Simple sunlight
-    let sun_direction = Vec3::new(0.5, 0.4, 0.4).normalize();
-    let dot_sun = sun_direction.dot(ray.direction);
-    let sun_amount = smoothstep(0.99, 0.999, dot_sun);
-    let sunlight = sun_amount * Color::new(40.0, 40.0, 35.0); // Sun color
+    let sunlight = if include_sun {
+        sun_radiance(ray.direction)
+    } else {
+        Color::ZERO
+    };
 
     // Some sun haze with a smoothstep
+    let dot_sun = sun_direction().dot(ray.direction);
     let haze_amount = smoothstep(0.0, 1.0, dot_sun);
     let haze = haze_amount * Color::new(0.2, 0.2, 0.1); // Haze color
 
@@ -53,6 +220,27 @@ fn ray_color(rng: &mut RayRng, ray: Ray, scene: &Scene, depth: i32, ray_count: &
 }
 
 /// Renderer which generates pixels using the scene and camera
+/// What the renderer writes to each pixel
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RenderPass {
+    // Path traced radiance
+    Beauty,
+    // Flat color per hittable or per primitive of the first hit, black for misses
+    ObjectId,
+    PrimitiveId,
+}
+
+/// Stable pseudo random color for an id, so neighbouring ids stand apart
+fn id_color(id: u64) -> Color {
+    // SplitMix64 finalizer
+    let mut x = id.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    let channel = |shift: u32| 0.1 + 0.9 * ((x >> shift) & 0xff) as f32 / 255.0;
+    Color::new(channel(0), channel(8), channel(16))
+}
+
 pub struct Renderer {
     image_width: u32,
     image_height: u32,
@@ -60,6 +248,7 @@ pub struct Renderer {
     camera: Camera,
     samples_per_pixel: u32,
     max_depth: i32,
+    pass: RenderPass,
 }
 
 impl Renderer {
@@ -77,6 +266,39 @@ impl Renderer {
             camera,
             samples_per_pixel,
             max_depth: 50,
+            pass: RenderPass::Beauty,
+        }
+    }
+
+    pub fn with_pass(mut self, pass: RenderPass) -> Self {
+        self.pass = pass;
+        self
+    }
+
+    /// Color of one camera ray for the selected pass
+    fn sample_color(&self, rng: &mut RayRng, ray: Ray, ray_count: &mut u32) -> Color {
+        if self.pass == RenderPass::Beauty {
+            return ray_color(
+                rng,
+                ray,
+                &self.scene,
+                None,
+                false,
+                self.max_depth,
+                ray_count,
+            );
+        }
+
+        *ray_count += 1;
+        let query = RayQuery {
+            ray,
+            t_min: TRACE_EPSILON,
+            t_max: TRACE_INFINITY,
+        };
+        match self.scene.intersect(query) {
+            Some(hit) if self.pass == RenderPass::ObjectId => id_color(hit.object_id as u64),
+            Some(hit) => id_color(((hit.object_id as u64) << 32) | hit.primitive_id as u64),
+            None => Color::ZERO,
         }
     }
 
@@ -94,14 +316,15 @@ impl Renderer {
             let v = v_base + rng.gen_range(0.0..v_rand);
             let ray = self.camera.get_ray(rng, u, v);
             // Start the primary here from here
-            color_accum += ray_color(rng, ray, &self.scene, self.max_depth, ray_count);
+            color_accum += self.sample_color(rng, ray, ray_count);
         }
 
         // Return color
         color_accum / self.samples_per_pixel as f32
     }
 
-    pub fn render_frame(&self, channel_send: Sender<BufferPacket>) {
+    /// Render all pixels into the channel and return the speed in MRays/sec
+    pub fn render_frame(&self, channel_send: Sender<BufferPacket>) -> f32 {
         println!("Start render");
         let time_start = std::time::Instant::now();
         let atomic_ray_count = AtomicU64::new(0);
@@ -119,7 +342,7 @@ impl Renderer {
             let mut rng = RayRng::new(line as u64);
             // Render the line
             let mut ray_count: u32 = 0;
-            for x in 0..self.image_width as u32 {
+            for x in 0..self.image_width {
                 let col = self.render_pixel(x, line, &mut rng, &mut ray_count);
                 packet
                     .pixels
@@ -143,5 +366,6 @@ impl Renderer {
         );
 
         drop(channel_send);
+        mrays_sec
     }
 }
@@ -1,67 +1,205 @@
+use crate::flat_bvh::*;
 use crate::object::*;
 use crate::shared::*;
+use crate::subdivision::*;
 
 use bvh::bvh::Bvh;
 
+/// Implementation of the top level BVH
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BvhBackend {
+    // The bvh crate, which converts every ray to nalgebra types
+    BvhCrate,
+    // Native binned SAH BVH with flattened nodes
+    Flat,
+}
+
+/// What rays see when they leave the scene
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Background {
+    // Procedural sky with a sun
+    Sky,
+    Solid(Color),
+}
+
 /// Basic scene which holds objects and a BVH
 pub struct Scene {
     // List of hittables
     pub objects: Vec<Box<dyn RayHittable>>,
 
+    // Transformed instances of shared hittables, indexed after the objects
+    pub instances: Vec<Instance>,
+
     // List of bounds for hittables
     pub bounds: Vec<HittableBounds>,
 
-    // Acceleration structure
+    // Indices of unbounded hittables which are not in the BVH
+    pub unbounded: Vec<usize>,
+
+    // Top level acceleration structure, meshes own their bottom level BVH
+    pub backend: BvhBackend,
     pub bvh: Option<Bvh<f32, 3>>,
+    pub flat_bvh: FlatBvh,
+
+    // Subdivision and displacement for meshes added from loaders
+    pub refinement: MeshRefinement,
+
+    pub background: Background,
 }
 
 impl Scene {
     pub fn new() -> Self {
         Scene {
             objects: Vec::new(),
+            instances: Vec::new(),
             bounds: Vec::new(),
+            unbounded: Vec::new(),
+            backend: BvhBackend::Flat,
             bvh: None,
+            flat_bvh: FlatBvh::default(),
+            refinement: MeshRefinement::default(),
+            background: Background::Sky,
+        }
+    }
+
+    /// Add the mesh as a single object, traversed with its own bottom level BVH.
+    /// The mesh is refined first if the scene has a refinement set.
+    pub fn add_mesh(&mut self, mesh: Arc<TriangleMesh>) {
+        let mesh = if self.refinement.is_identity() {
+            mesh
+        } else {
+            Arc::new(self.refinement.apply(&mesh))
+        };
+        self.objects.push(Box::new(mesh));
+    }
+
+    /// Place a shared hittable with a transform, returns the instance index
+    pub fn add_instance(&mut self, object: Arc<dyn RayHittable>, transform: Mat4) -> usize {
+        self.instances.push(Instance::new(object, transform));
+        self.instances.len() - 1
+    }
+
+    /// Move an instance, which only rebuilds the top level BVH
+    pub fn set_instance_transform(&mut self, index: usize, transform: Mat4) {
+        self.instances[index].set_transform(transform);
+        self.build_bvh();
+    }
+
+    /// Object or instance by its index in the top level BVH
+    fn hittable(&self, index: usize) -> &dyn RayHittable {
+        match index.checked_sub(self.objects.len()) {
+            Some(instance_index) => &self.instances[instance_index],
+            None => self.objects[index].as_ref(),
         }
     }
 
+    /// Intersect one object or instance and tag the hit with its index
+    fn intersect_hittable(&self, index: usize, query: RayQuery) -> Option<HitRecord> {
+        let mut hit = self.hittable(index).intersect(query)?;
+        hit.object_id = index as u32;
+        Some(hit)
+    }
+
+    /// Build the top level BVH over all objects and instances
     pub fn build_bvh(&mut self) {
         // Compute bounds
-        for (i, hittable) in self.objects.iter().enumerate() {
-            let bounds = hittable.compute_bounds(i);
-            self.bounds.push(bounds);
+        self.bounds.clear();
+        self.unbounded.clear();
+        for i in 0..self.objects.len() + self.instances.len() {
+            let hittable = self.hittable(i);
+            if hittable.is_bounded() {
+                let bounds = hittable.compute_bounds(i);
+                self.bounds.push(bounds);
+            } else {
+                self.unbounded.push(i);
+            }
         }
         // Build BVH
-        self.bvh = Some(Bvh::build(&mut self.bounds));
+        match self.backend {
+            BvhBackend::BvhCrate => self.bvh = Some(Bvh::build(&mut self.bounds)),
+            BvhBackend::Flat => self.flat_bvh = FlatBvh::build(&self.bounds),
+        }
+    }
+
+    /// Bounding box of all bounded objects, available after build_bvh
+    pub fn bounding_box(&self) -> Option<(Point3, Point3)> {
+        self.bounds.iter().fold(None, |acc, b| match acc {
+            None => Some((b.min(), b.max())),
+            Some((min, max)) => Some((min.min(b.min()), max.max(b.max()))),
+        })
+    }
+
+    /// Whether anything blocks the ray within the query range, cheaper than intersect for
+    /// shadow rays since it stops at the first hit and never builds a HitRecord.
+    /// Media don't block rays, their transmittance is estimated separately.
+    pub fn occluded(&self, query: RayQuery) -> bool {
+        if self
+            .unbounded
+            .iter()
+            .any(|index| self.hittable(*index).occludes(query))
+        {
+            return true;
+        }
+
+        match self.backend {
+            BvhBackend::Flat => self
+                .flat_bvh
+                .any_hit(query, |index, query| self.hittable(index).occludes(query)),
+            BvhBackend::BvhCrate => self.bvh.as_ref().is_some_and(|bvh| {
+                let origin = point_to_nalgebra(query.ray.origin);
+                let direction = vec_to_nalgebra(query.ray.direction);
+                let bvh_ray = bvh::ray::Ray::new(origin, direction);
+                bvh.traverse_iterator(&bvh_ray, &self.bounds)
+                    .any(|bounds| self.hittable(bounds.hittable_index).occludes(query))
+            }),
+        }
     }
 
     /// Return the closest intersection (or None) in the scene using the ray
     pub fn intersect(&self, mut query: RayQuery) -> Option<HitRecord> {
         let mut closest_hit_option: Option<HitRecord> = None;
 
-        if let Some(bvh) = &self.bvh {
-            // Traverse the BVH
-            let origin = point_to_nalgebra(query.ray.origin);
-            let direction = vec_to_nalgebra(query.ray.direction);
-            let bvh_ray = bvh::ray::Ray::new(origin, direction);
-            let nearest = bvh.nearest_traverse_iterator(&bvh_ray, &self.bounds);
-
-            // Iterate over bvh-intersected objects to find closest
-            for bounds in nearest {
-                let obj = self.objects[bounds.hittable_index].as_ref();
-                let hit_option = obj.intersect(query);
-                if hit_option.is_some() {
-                    // Shorten the ray
-                    let hit = hit_option.as_ref().unwrap();
-                    query.t_max = f32::min(query.t_max, hit.t);
-
-                    if closest_hit_option.is_none() {
-                        // First hit along the ray
-                        closest_hit_option = hit_option;
-                    } else {
-                        let closest_hit = closest_hit_option.as_ref().unwrap();
-                        if hit.t < closest_hit.t {
-                            // Found new closest hit
-                            closest_hit_option = hit_option;
+        // Unbounded objects first, any hit shortens the ray for the BVH
+        for index in &self.unbounded {
+            if let Some(hit) = self.intersect_hittable(*index, query) {
+                query.t_max = hit.t;
+                closest_hit_option = Some(hit);
+            }
+        }
+
+        match self.backend {
+            BvhBackend::Flat => {
+                // Ordered traversal which already keeps the closest hit
+                let bvh_hit_option = self
+                    .flat_bvh
+                    .intersect(query, |index, query| self.intersect_hittable(index, query));
+                if bvh_hit_option.is_some() {
+                    closest_hit_option = bvh_hit_option;
+                }
+            }
+            BvhBackend::BvhCrate => {
+                if let Some(bvh) = &self.bvh {
+                    // Traverse the BVH
+                    let origin = point_to_nalgebra(query.ray.origin);
+                    let direction = vec_to_nalgebra(query.ray.direction);
+                    let bvh_ray = bvh::ray::Ray::new(origin, direction);
+                    let nearest = bvh.nearest_traverse_iterator(&bvh_ray, &self.bounds);
+
+                    // Iterate over bvh-intersected objects to find closest
+                    for bounds in nearest {
+                        if let Some(hit) = self.intersect_hittable(bounds.hittable_index, query) {
+                            // Shorten the ray
+                            query.t_max = f32::min(query.t_max, hit.t);
+
+                            match &closest_hit_option {
+                                // Found new closest hit
+                                Some(closest_hit) if hit.t < closest_hit.t => {
+                                    closest_hit_option = Some(hit)
+                                }
+                                Some(_) => {}
+                                // First hit along the ray
+                                None => closest_hit_option = Some(hit),
+                            }
                         }
                     }
                 }
@@ -70,3 +208,177 @@ impl Scene {
         closest_hit_option
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::*;
+
+    /// Moving an instance of a mesh should move its hits after the top level rebuild
+    #[test]
+    fn test_move_instance() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let positions = vec![
+            Point3::new(-1.0, 0.0, -1.0),
+            Point3::new(1.0, 0.0, -1.0),
+            Point3::new(1.0, 0.0, 1.0),
+            Point3::new(-1.0, 0.0, 1.0),
+        ];
+        let indices = vec![[0, 2, 1], [0, 3, 2]];
+        let mesh: Arc<dyn RayHittable> = Arc::new(TriangleMesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            indices,
+            &material,
+        ));
+
+        let mut scene = Scene::new();
+        scene.add_instance(mesh.clone(), Mat4::IDENTITY);
+        let moved = scene.add_instance(mesh, Mat4::from_translation(Vec3::new(5.0, 0.0, 0.0)));
+        scene.build_bvh();
+
+        let query = RayQuery {
+            ray: Ray::new(Point3::new(5.0, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0),
+            t_min: TRACE_EPSILON,
+            t_max: TRACE_INFINITY,
+        };
+        let hit = scene.intersect(query).unwrap();
+        assert!((hit.t - 3.0).abs() < 1e-5);
+        assert_eq!(hit.object_id, moved as u32);
+
+        scene.set_instance_transform(moved, Mat4::from_translation(Vec3::new(5.0, 1.0, 0.0)));
+        assert!((scene.intersect(query).unwrap().t - 2.0).abs() < 1e-5);
+    }
+
+    /// An unbounded plane should win when it is nearer than the BVH objects and lose when it
+    /// is farther, for both backends
+    #[test]
+    fn test_unbounded_plane() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        for backend in [BvhBackend::BvhCrate, BvhBackend::Flat] {
+            let mut scene = Scene::new();
+            scene.backend = backend;
+            scene.objects.push(Box::new(Sphere::new(
+                Point3::new(0.0, 3.0, 0.0),
+                1.0,
+                &material,
+            )));
+            scene
+                .objects
+                .push(Box::new(Plane::new(Point3::ZERO, Vec3::Y, &material)));
+            scene.build_bvh();
+            assert_eq!(scene.unbounded, vec![1]);
+
+            // Plane behind the sphere, the BVH hit must replace it
+            let query = RayQuery {
+                ray: Ray::new(Point3::new(0.0, 6.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let hit = scene.intersect(query).unwrap();
+            assert!((hit.t - 2.0).abs() < 1e-5);
+            assert_eq!(hit.object_id, 0);
+
+            // Plane in front of the sphere, the shortened ray must skip it
+            let query = RayQuery {
+                ray: Ray::new(Point3::new(0.0, -5.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0),
+                ..query
+            };
+            let hit = scene.intersect(query).unwrap();
+            assert!((hit.t - 5.0).abs() < 1e-5);
+            assert_eq!(hit.object_id, 1);
+
+            // Plane alone
+            let query = RayQuery {
+                ray: Ray::new(Point3::new(3.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0),
+                ..query
+            };
+            let hit = scene.intersect(query).unwrap();
+            assert!((hit.t - 5.0).abs() < 1e-5);
+            assert_eq!(hit.object_id, 1);
+        }
+    }
+
+    /// An instanced plane stays out of the BVH instead of giving it infinite bounds
+    #[test]
+    fn test_instanced_plane() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let plane: Arc<dyn RayHittable> = Arc::new(Plane::new(Point3::ZERO, Vec3::Y, &material));
+        for backend in [BvhBackend::BvhCrate, BvhBackend::Flat] {
+            let mut scene = Scene::new();
+            scene.backend = backend;
+            scene.objects.push(Box::new(Sphere::new(
+                Point3::new(0.0, 3.0, 0.0),
+                1.0,
+                &material,
+            )));
+            scene.add_instance(
+                plane.clone(),
+                Mat4::from_translation(Vec3::new(0.0, -1.0, 0.0)),
+            );
+            scene.build_bvh();
+            assert_eq!(scene.unbounded, vec![1]);
+            let (min, max) = scene.bounding_box().unwrap();
+            assert!(min.is_finite() && max.is_finite());
+
+            for (x, t, object_id) in [(0.0, 1.0, 0), (3.0, 6.0, 1)] {
+                let query = RayQuery {
+                    ray: Ray::new(Point3::new(x, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0),
+                    t_min: TRACE_EPSILON,
+                    t_max: TRACE_INFINITY,
+                };
+                let hit = scene.intersect(query).unwrap();
+                assert!((hit.t - t).abs() < 1e-5);
+                assert_eq!(hit.object_id, object_id);
+            }
+        }
+    }
+
+    /// Occlusion should agree with closest hit queries for both backends
+    #[test]
+    fn test_occluded_matches_intersect() {
+        let mut rng = RayRng::new(0);
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let positions = vec![
+            Point3::new(-1.0, 0.0, -1.0),
+            Point3::new(1.0, 0.0, -1.0),
+            Point3::new(0.0, 0.0, 1.0),
+        ];
+        let mesh: Arc<dyn RayHittable> = Arc::new(TriangleMesh::new(
+            positions,
+            Vec::new(),
+            Vec::new(),
+            vec![[0, 2, 1]],
+            &material,
+        ));
+
+        for backend in [BvhBackend::BvhCrate, BvhBackend::Flat] {
+            let mut scene = Scene::new();
+            scene.backend = backend;
+            for i in 0..50 {
+                let center = Point3::new((i % 10) as f32 - 5.0, (i / 10) as f32, -3.0);
+                scene
+                    .objects
+                    .push(Box::new(Sphere::new(center, 0.3, &material)));
+                let rotation = Mat4::from_rotation_x(i as f32);
+                let transform = Mat4::from_translation(center + Vec3::new(0.0, 0.0, 3.0));
+                scene.add_instance(mesh.clone(), transform * rotation);
+            }
+            scene.build_bvh();
+
+            for _ in 0..500 {
+                let query = RayQuery {
+                    ray: Ray::new(
+                        Point3::new(0.0, 2.0, 5.0),
+                        random_unit_vector(&mut rng),
+                        0.0,
+                    ),
+                    t_min: TRACE_EPSILON,
+                    t_max: rng.gen_range(1.0..10.0),
+                };
+                assert_eq!(scene.occluded(query), scene.intersect(query).is_some());
+            }
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use crate::material::*;
+use crate::object::*;
+use crate::shared::*;
+
+/// Maximum number of sphere tracing steps per ray
+const SDF_MAX_STEPS: usize = 256;
+/// Distance to the surface at which sphere tracing reports a hit
+const SDF_EPSILON: f32 = 1e-4;
+
+/// Composable signed distance field, negative inside the surface
+pub enum Sdf {
+    Sphere {
+        radius: f32,
+    },
+    Cuboid {
+        half_size: Vec3,
+    },
+    // Torus around the y axis
+    Torus {
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    Translate {
+        sdf: Box<Sdf>,
+        offset: Vec3,
+    },
+    Union(Box<Sdf>, Box<Sdf>),
+    // Polynomial smooth minimum blending over distance k
+    SmoothUnion {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        k: f32,
+    },
+    // Rotation around the y axis by rate radians per unit of height
+    Twist {
+        sdf: Box<Sdf>,
+        rate: f32,
+    },
+    // Infinite repetition with the given cell size, zero components don't repeat
+    Repeat {
+        sdf: Box<Sdf>,
+        period: Vec3,
+    },
+    // Distance estimator of the Mandelbulb fractal around the origin
+    Mandelbulb {
+        power: f32,
+        iterations: u32,
+    },
+}
+
+impl Sdf {
+    pub fn sphere(radius: f32) -> Self {
+        Sdf::Sphere { radius }
+    }
+
+    pub fn cuboid(half_size: Vec3) -> Self {
+        Sdf::Cuboid { half_size }
+    }
+
+    pub fn torus(major_radius: f32, minor_radius: f32) -> Self {
+        Sdf::Torus {
+            major_radius,
+            minor_radius,
+        }
+    }
+
+    pub fn mandelbulb(power: f32, iterations: u32) -> Self {
+        Sdf::Mandelbulb { power, iterations }
+    }
+
+    pub fn translate(self, offset: Vec3) -> Self {
+        Sdf::Translate {
+            sdf: Box::new(self),
+            offset,
+        }
+    }
+
+    pub fn union(self, other: Sdf) -> Self {
+        Sdf::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn smooth_union(self, other: Sdf, k: f32) -> Self {
+        Sdf::SmoothUnion {
+            a: Box::new(self),
+            b: Box::new(other),
+            k,
+        }
+    }
+
+    pub fn twist(self, rate: f32) -> Self {
+        Sdf::Twist {
+            sdf: Box::new(self),
+            rate,
+        }
+    }
+
+    pub fn repeat(self, period: Vec3) -> Self {
+        Sdf::Repeat {
+            sdf: Box::new(self),
+            period,
+        }
+    }
+
+    /// Signed distance from p to the surface
+    pub fn distance(&self, p: Point3) -> f32 {
+        match self {
+            Sdf::Sphere { radius } => p.length() - radius,
+            Sdf::Cuboid { half_size } => {
+                let q = p.abs() - *half_size;
+                q.max(Vec3::ZERO).length() + q.max_element().min(0.0)
+            }
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let q = Vec2::new(Vec2::new(p.x, p.z).length() - major_radius, p.y);
+                q.length() - minor_radius
+            }
+            Sdf::Translate { sdf, offset } => sdf.distance(p - *offset),
+            Sdf::Union(a, b) => a.distance(p).min(b.distance(p)),
+            Sdf::SmoothUnion { a, b, k } => {
+                let (da, db) = (a.distance(p), b.distance(p));
+                let h = (0.5 + 0.5 * (db - da) / k).clamp(0.0, 1.0);
+                db + (da - db) * h - k * h * (1.0 - h)
+            }
+            Sdf::Twist { sdf, rate } => {
+                let (sin, cos) = (rate * p.y).sin_cos();
+                let q = Point3::new(cos * p.x - sin * p.z, p.y, sin * p.x + cos * p.z);
+                // Twisting stretches space, scale down to keep steps conservative
+                let stretch = rate * Vec2::new(p.x, p.z).length();
+                sdf.distance(q) / f32::sqrt(1.0 + stretch * stretch)
+            }
+            Sdf::Repeat { sdf, period } => {
+                let wrap = |x: f32, period: f32| {
+                    if period > 0.0 {
+                        x - period * (x / period).round()
+                    } else {
+                        x
+                    }
+                };
+                let q = Point3::new(
+                    wrap(p.x, period.x),
+                    wrap(p.y, period.y),
+                    wrap(p.z, period.z),
+                );
+                sdf.distance(q)
+            }
+            Sdf::Mandelbulb { power, iterations } => {
+                let mut z = p;
+                let mut dr = 1.0;
+                let mut r = z.length();
+                for _ in 0..*iterations {
+                    if r > 2.0 || r == 0.0 {
+                        break;
+                    }
+                    // Raise z to the power in spherical coordinates and add p
+                    let theta = (z.z / r).acos() * power;
+                    let phi = z.y.atan2(z.x) * power;
+                    dr = r.powf(power - 1.0) * power * dr + 1.0;
+                    let zr = r.powf(*power);
+                    z =
+                        zr * Vec3::new(
+                            theta.sin() * phi.cos(),
+                            theta.sin() * phi.sin(),
+                            theta.cos(),
+                        ) + p;
+                    r = z.length();
+                }
+                if r == 0.0 {
+                    return 0.0;
+                }
+                0.5 * r.ln() * r / dr
+            }
+        }
+    }
+
+    /// Normalized gradient of the field, using the tetrahedron technique
+    pub fn gradient(&self, p: Point3) -> Vec3 {
+        let h = 0.5 * SDF_EPSILON;
+        let k0 = Vec3::new(1.0, -1.0, -1.0);
+        let k1 = Vec3::new(-1.0, -1.0, 1.0);
+        let k2 = Vec3::new(-1.0, 1.0, -1.0);
+        let k3 = Vec3::new(1.0, 1.0, 1.0);
+        let gradient = k0 * self.distance(p + h * k0)
+            + k1 * self.distance(p + h * k1)
+            + k2 * self.distance(p + h * k2)
+            + k3 * self.distance(p + h * k3);
+        gradient.normalize_or(Vec3::Y)
+    }
+}
+
+/// Object defined by a signed distance field, sphere traced inside its bounding box
+pub struct SdfObject {
+    pub sdf: Sdf,
+    pub min: Point3,
+    pub max: Point3,
+    pub material: Arc<dyn Material>,
+}
+
+impl SdfObject {
+    pub fn new(sdf: Sdf, min: Point3, max: Point3, material: &Arc<dyn Material>) -> Self {
+        SdfObject {
+            sdf,
+            min: min.min(max),
+            max: min.max(max),
+            material: material.clone(),
+        }
+    }
+
+    /// Parametric range of the ray inside the bounding box
+    fn clip(&self, query: &RayQuery) -> Option<(f32, f32)> {
+        let inv_direction = query.ray.direction.recip();
+        let t0 = (self.min - query.ray.origin) * inv_direction;
+        let t1 = (self.max - query.ray.origin) * inv_direction;
+        let t_enter = t0.min(t1).max_element().max(query.t_min);
+        let t_exit = t0.max(t1).min_element().min(query.t_max);
+        (t_enter <= t_exit).then_some((t_enter, t_exit))
+    }
+}
+
+impl RayHittable for SdfObject {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let (t_enter, t_exit) = self.clip(&query)?;
+        let r = query.ray;
+        let direction_length = r.direction.length();
+
+        // March towards the surface from whichever side the ray starts on
+        let mut t = t_enter;
+        let start = self.sdf.distance(r.at(t));
+        // Rays scattered from a hit start on the surface, where the sign of the distance is
+        // noise. They march on the side they head into and only hit once they got away.
+        let mut on_surface = t_enter == query.t_min && start.abs() < SDF_EPSILON;
+        let side = if on_surface {
+            r.direction.dot(self.sdf.gradient(r.at(t))).signum()
+        } else {
+            start.signum()
+        };
+        for _ in 0..SDF_MAX_STEPS {
+            let distance = side * self.sdf.distance(r.at(t));
+            if on_surface {
+                on_surface = distance <= SDF_EPSILON;
+            } else if distance < SDF_EPSILON {
+                let outward_normal = self.sdf.gradient(r.at(t));
+                return Some(HitRecord::new(r, t, outward_normal, self.material.clone()));
+            }
+            t += distance.max(SDF_EPSILON) / direction_length;
+            if t > t_exit {
+                break;
+            }
+        }
+        None
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        HittableBounds::new(self.min, self.max, hittable_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sphere tracing a sphere field should match the analytic sphere from outside and inside
+    #[test]
+    fn test_sdf_sphere() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let center = Point3::new(0.0, 1.0, 0.0);
+        let analytic = Sphere::new(center, 1.0, &material);
+        let traced = SdfObject::new(
+            Sdf::sphere(1.0).translate(center),
+            center - Vec3::splat(1.1),
+            center + Vec3::splat(1.1),
+            &material,
+        );
+
+        for origin in [Point3::new(3.0, 1.5, 0.5), center] {
+            let query = RayQuery {
+                ray: Ray::new(origin, Vec3::new(-2.0, -0.3, -0.2), 0.0),
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            };
+            let expected = analytic.intersect(query).unwrap();
+            let hit = traced.intersect(query).unwrap();
+            assert!((hit.point - expected.point).length() < 1e-3);
+            assert!((hit.normal - expected.normal).length() < 1e-3);
+            assert_eq!(hit.front_face, expected.front_face);
+        }
+
+        // Rays scattered from the surface at shallow angles, from the exact top and from a
+        // traced hit which stops up to SDF_EPSILON short of it, must not hit it again
+        let down = Ray::new(center + 3.0 * Vec3::Y, -Vec3::Y, 0.0);
+        let traced_top = traced
+            .intersect(RayQuery {
+                ray: down,
+                t_min: TRACE_EPSILON,
+                t_max: TRACE_INFINITY,
+            })
+            .unwrap()
+            .point;
+        let query = |origin: Point3, direction: Vec3| RayQuery {
+            ray: Ray::new(origin, direction, 0.0),
+            t_min: TRACE_EPSILON,
+            t_max: TRACE_INFINITY,
+        };
+        let grazing = |sin: f32| Vec3::new(f32::sqrt(1.0 - sin * sin), sin, 0.0);
+        for top in [center + Vec3::Y, traced_top] {
+            for sin in [0.02, 0.005] {
+                assert!(traced.intersect(query(top, grazing(sin))).is_none());
+            }
+            // Into the sphere it has to find the far side of the chord, hits stop up to
+            // SDF_EPSILON / sin short of the surface
+            let hit = traced.intersect(query(top, grazing(-0.1))).unwrap();
+            assert!((hit.t - 0.2).abs() < 2e-3);
+            assert!(!hit.front_face);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 pub use bvh::aabb::{Aabb, Bounded};
 pub use bvh::bounding_hierarchy::{BHShape, BoundingHierarchy};
-pub use glam::Vec3;
+pub use glam::{EulerRot, Mat3, Mat4, Quat, Vec2, Vec3};
 use rand::Rng;
 use rand_xoshiro::rand_core::SeedableRng;
 use rand_xoshiro::Xoshiro128Plus;
@@ -18,11 +18,17 @@ pub const TRACE_INFINITY: f32 = f32::MAX;
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    // Time within the camera shutter interval
+    pub time: f32,
 }
 
 impl Ray {
-    pub fn new(origin: Vec3, direction: Vec3) -> Self {
-        Ray { origin, direction }
+    pub fn new(origin: Vec3, direction: Vec3, time: f32) -> Self {
+        Ray {
+            origin,
+            direction,
+            time,
+        }
     }
 
     pub fn at(&self, t: f32) -> Point3 {
@@ -119,6 +125,7 @@ pub fn random_in_hemisphere(rng: &mut RayRng, normal: Vec3) -> Vec3 {
 /// Vec3 extensions
 pub trait VecExt {
     fn near_zero(&self) -> bool;
+    fn max_dimension(&self) -> usize;
 }
 
 impl VecExt for Vec3 {
@@ -127,6 +134,21 @@ impl VecExt for Vec3 {
         let s = 1e-8;
         (self.x.abs() < s) && (self.y.abs() < s) && (self.z.abs() < s)
     }
+
+    /// Index of the largest component
+    fn max_dimension(&self) -> usize {
+        if self.x > self.y {
+            if self.x > self.z {
+                0
+            } else {
+                2
+            }
+        } else if self.y > self.z {
+            1
+        } else {
+            2
+        }
+    }
 }
 
 pub fn vec_reflect(v: Vec3, n: Vec3) -> Vec3 {
@@ -147,6 +169,27 @@ pub fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
     r0 + (1.0 - r0) * ((1.0 - cosine).powf(5.0))
 }
 
+/// Exact Fresnel reflectance of a dielectric interface for unpolarized light.
+/// eta is the inside over the outside index, a negative cosine means the ray comes from inside.
+pub fn fresnel_dielectric(cos_theta_i: f32, eta: f32) -> f32 {
+    let (cos_theta_i, eta) = if cos_theta_i < 0.0 {
+        (-cos_theta_i.max(-1.0), 1.0 / eta)
+    } else {
+        (cos_theta_i.min(1.0), eta)
+    };
+
+    // Total internal reflection
+    let sin2_theta_t = (1.0 - cos_theta_i * cos_theta_i) / (eta * eta);
+    if sin2_theta_t >= 1.0 {
+        return 1.0;
+    }
+
+    let cos_theta_t = (1.0 - sin2_theta_t).sqrt();
+    let r_parallel = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let r_perpendicular = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
 pub fn random_in_unit_disk(rng: &mut RayRng) -> Vec3 {
     loop {
         let p = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0);
@@ -169,11 +212,179 @@ pub fn color_random_range(rng: &mut RayRng, range: std::ops::Range<f32>) -> Colo
 }
 
 pub fn point_to_nalgebra(p: Point3) -> nalgebra::Point3<f32> {
-    return nalgebra::Point3::new(p.x, p.y, p.z);
+    nalgebra::Point3::new(p.x, p.y, p.z)
 }
 
 pub fn vec_to_nalgebra(p: Vec3) -> nalgebra::Vector3<f32> {
-    return nalgebra::Vector3::new(p.x, p.y, p.z);
+    nalgebra::Vector3::new(p.x, p.y, p.z)
+}
+
+const EQUATION_EPSILON: f64 = 1e-9;
+
+fn is_zero(x: f64) -> bool {
+    x.abs() < EQUATION_EPSILON
+}
+
+/// Real roots of a polynomial of up to fourth degree, kept on the stack
+#[derive(Copy, Clone, Default)]
+pub struct Roots {
+    values: [f64; 4],
+    count: usize,
+}
+
+impl Roots {
+    fn from_slice(values: &[f64]) -> Self {
+        let mut roots = Roots::default();
+        roots.values[..values.len()].copy_from_slice(values);
+        roots.count = values.len();
+        roots
+    }
+
+    fn push(&mut self, root: f64) {
+        self.values[self.count] = root;
+        self.count += 1;
+    }
+}
+
+impl std::ops::Deref for Roots {
+    type Target = [f64];
+
+    fn deref(&self) -> &[f64] {
+        &self.values[..self.count]
+    }
+}
+
+impl std::ops::DerefMut for Roots {
+    fn deref_mut(&mut self) -> &mut [f64] {
+        &mut self.values[..self.count]
+    }
+}
+
+/// Real roots of c[2]x^2 + c[1]x + c[0]
+fn solve_quadric(c: [f64; 3]) -> Roots {
+    // Normal form x^2 + 2px + q
+    let p = c[1] / (2.0 * c[2]);
+    let q = c[0] / c[2];
+    let d = p * p - q;
+
+    if is_zero(d) {
+        Roots::from_slice(&[-p])
+    } else if d < 0.0 {
+        Roots::default()
+    } else {
+        let sqrt_d = d.sqrt();
+        Roots::from_slice(&[sqrt_d - p, -sqrt_d - p])
+    }
+}
+
+/// Real roots of c[3]x^3 + c[2]x^2 + c[1]x + c[0]
+fn solve_cubic(c: [f64; 4]) -> Roots {
+    // Normal form x^3 + Ax^2 + Bx + C
+    let a = c[2] / c[3];
+    let b = c[1] / c[3];
+    let c = c[0] / c[3];
+
+    // Substitute x = y - A/3 to eliminate the quadric term: y^3 + 3py + 2q
+    let sq_a = a * a;
+    let p = (-sq_a / 3.0 + b) / 3.0;
+    let q = (2.0 / 27.0 * a * sq_a - a * b / 3.0 + c) / 2.0;
+
+    // Cardano's formula
+    let cb_p = p * p * p;
+    let d = q * q + cb_p;
+
+    let mut roots = if is_zero(d) {
+        if is_zero(q) {
+            Roots::from_slice(&[0.0])
+        } else {
+            let u = (-q).cbrt();
+            Roots::from_slice(&[2.0 * u, -u])
+        }
+    } else if d < 0.0 {
+        // Three real roots
+        let phi = (-q / (-cb_p).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        let third = std::f64::consts::PI / 3.0;
+        Roots::from_slice(&[
+            t * phi.cos(),
+            -t * (phi + third).cos(),
+            -t * (phi - third).cos(),
+        ])
+    } else {
+        let sqrt_d = d.sqrt();
+        Roots::from_slice(&[(sqrt_d - q).cbrt() - (sqrt_d + q).cbrt()])
+    };
+
+    for root in roots.iter_mut() {
+        *root -= a / 3.0;
+    }
+    roots
+}
+
+/// Real roots of c[4]x^4 + c[3]x^3 + c[2]x^2 + c[1]x + c[0] using Ferrari's method,
+/// after Schwarze in Graphics Gems. Roots are polished with Newton iterations.
+pub fn solve_quartic(coefficients: [f64; 5]) -> Roots {
+    let c = coefficients;
+
+    // Normal form x^4 + Ax^3 + Bx^2 + Cx + D
+    let a = c[3] / c[4];
+    let b = c[2] / c[4];
+    let c1 = c[1] / c[4];
+    let d = c[0] / c[4];
+
+    // Substitute x = y - A/4 to eliminate the cubic term: y^4 + py^2 + qy + r
+    let sq_a = a * a;
+    let p = -3.0 / 8.0 * sq_a + b;
+    let q = sq_a * a / 8.0 - a * b / 2.0 + c1;
+    let r = -3.0 / 256.0 * sq_a * sq_a + sq_a * b / 16.0 - a * c1 / 4.0 + d;
+
+    let mut roots = if is_zero(r) {
+        // No absolute term: y(y^3 + py + q) = 0
+        let mut roots = solve_cubic([q, p, 0.0, 1.0]);
+        roots.push(0.0);
+        roots
+    } else {
+        // Take one root of the resolvent cubic
+        let z = solve_cubic([r * p / 2.0 - q * q / 8.0, -r, -p / 2.0, 1.0])[0];
+
+        // Build two quadric equations from it
+        let u = z * z - r;
+        let v = 2.0 * z - p;
+        let u = if is_zero(u) {
+            0.0
+        } else if u > 0.0 {
+            u.sqrt()
+        } else {
+            return Roots::default();
+        };
+        let v = if is_zero(v) {
+            0.0
+        } else if v > 0.0 {
+            v.sqrt()
+        } else {
+            return Roots::default();
+        };
+
+        let sign_v = if q < 0.0 { -v } else { v };
+        let mut roots = solve_quadric([z - u, sign_v, 1.0]);
+        for root in solve_quadric([z + u, -sign_v, 1.0]).iter() {
+            roots.push(*root);
+        }
+        roots
+    };
+
+    let evaluate = |x: f64| (((c[4] * x + c[3]) * x + c[2]) * x + c[1]) * x + c[0];
+    let derivative = |x: f64| ((4.0 * c[4] * x + 3.0 * c[3]) * x + 2.0 * c[2]) * x + c[1];
+    for root in roots.iter_mut() {
+        *root -= a / 4.0;
+        for _ in 0..2 {
+            let slope = derivative(*root);
+            if slope != 0.0 {
+                *root -= evaluate(*root) / slope;
+            }
+        }
+    }
+    roots
 }
 
 pub fn smoothstep(left: f32, right: f32, x: f32) -> f32 {
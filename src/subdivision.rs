@@ -0,0 +1,390 @@
+use crate::image::*;
+use crate::object::*;
+use crate::shared::*;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Scalar displacement along the vertex normals
+#[derive(Clone)]
+pub enum Displacement {
+    // Grayscale image sampled bilinearly at the vertex UVs, repeating outside 0..1
+    Image {
+        values: Arc<Vec<f32>>,
+        width: usize,
+        height: usize,
+    },
+    // Function of the undisplaced position and UV
+    Function(Arc<dyn Fn(Point3, Vec2) -> f32 + Send + Sync>),
+}
+
+impl Displacement {
+    /// Load an 8 or 16 bit grayscale PNG, black is no displacement and white is the full scale
+    pub fn load_image(path: &Path) -> Result<Self, ImageError> {
+        let image = Image::load_grayscale_png(path)?;
+        Ok(Displacement::Image {
+            values: Arc::new(image.channel(0)),
+            width: image.width,
+            height: image.height,
+        })
+    }
+
+    /// Displacement computed from the undisplaced vertex position and UV, such as a noise field
+    pub fn function<F>(f: F) -> Self
+    where
+        F: Fn(Point3, Vec2) -> f32 + Send + Sync + 'static,
+    {
+        Displacement::Function(Arc::new(f))
+    }
+
+    fn value(&self, p: Point3, uv: Vec2) -> f32 {
+        match self {
+            Displacement::Image {
+                values,
+                width,
+                height,
+            } => {
+                // The first image row is at the top, where v is one
+                let x = uv.x.rem_euclid(1.0) * *width as f32 - 0.5;
+                let y = (1.0 - uv.y).rem_euclid(1.0) * *height as f32 - 0.5;
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let texel = |x: f32, y: f32| {
+                    let x = (x as isize).rem_euclid(*width as isize) as usize;
+                    let y = (y as isize).rem_euclid(*height as isize) as usize;
+                    values[y * width + x]
+                };
+                let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1.0, y0) * fx;
+                let bottom = texel(x0, y0 + 1.0) * (1.0 - fx) + texel(x0 + 1.0, y0 + 1.0) * fx;
+                top * (1.0 - fy) + bottom * fy
+            }
+            Displacement::Function(f) => f(p, uv),
+        }
+    }
+}
+
+/// Refinement applied to meshes when they are loaded, before their BVH is built
+#[derive(Clone, Default)]
+pub struct MeshRefinement {
+    // Number of Loop subdivision steps, each one splits every triangle in four
+    pub subdivision_levels: u32,
+    pub displacement: Option<Displacement>,
+    // Distance along the normal for a displacement value of one
+    pub displacement_scale: f32,
+}
+
+impl MeshRefinement {
+    pub fn is_identity(&self) -> bool {
+        self.subdivision_levels == 0 && self.displacement.is_none()
+    }
+
+    /// Subdivide and displace the mesh, the result has smooth vertex normals
+    pub fn apply(&self, mesh: &TriangleMesh) -> TriangleMesh {
+        let mut refined = SubdivisionMesh::from_mesh(mesh);
+        for _ in 0..self.subdivision_levels {
+            refined = refined.subdivide();
+        }
+
+        let mut normals = refined.smooth_normals();
+        if let Some(displacement) = &self.displacement {
+            // Average over welded vertices so UV seams don't tear the surface apart
+            let mut offsets = vec![(0.0, 0); refined.welded_count];
+            for (i, &w) in refined.welded.iter().enumerate() {
+                let uv = refined.uvs.get(i).copied().unwrap_or(Vec2::ZERO);
+                offsets[w as usize].0 += displacement.value(refined.positions[i], uv);
+                offsets[w as usize].1 += 1;
+            }
+            for (i, &w) in refined.welded.iter().enumerate() {
+                let (sum, count) = offsets[w as usize];
+                refined.positions[i] += normals[i] * self.displacement_scale * sum / count as f32;
+            }
+            normals = refined.smooth_normals();
+        }
+
+        TriangleMesh::new(
+            refined.positions,
+            normals,
+            refined.uvs,
+            refined.indices,
+            &mesh.material,
+        )
+        .with_colors(refined.colors)
+    }
+}
+
+/// Mesh data during subdivision
+struct SubdivisionMesh {
+    positions: Vec<Point3>,
+    uvs: Vec<Vec2>,
+    colors: Vec<Color>,
+    indices: Vec<[u32; 3]>,
+    // Vertices at the same position share a welded index, so the surface is smoothed across
+    // seams in the UVs or normals
+    welded: Vec<u32>,
+    welded_count: usize,
+}
+
+/// Edge between two welded vertices
+struct Edge {
+    // Welded index of the new vertex on the edge
+    welded: u32,
+    // Sum of the vertices opposite the edge and the number of triangles sharing it
+    opposite_sum: Point3,
+    face_count: u32,
+}
+
+fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+impl SubdivisionMesh {
+    fn from_mesh(mesh: &TriangleMesh) -> Self {
+        let mut welded_indices: HashMap<[u32; 3], u32> = HashMap::new();
+        let welded = mesh
+            .positions
+            .iter()
+            .map(|p| {
+                let next = welded_indices.len() as u32;
+                *welded_indices
+                    .entry(p.to_array().map(f32::to_bits))
+                    .or_insert(next)
+            })
+            .collect();
+        SubdivisionMesh {
+            positions: mesh.positions.clone(),
+            uvs: mesh.uvs.clone(),
+            colors: mesh.colors.clone(),
+            indices: mesh.indices.clone(),
+            welded,
+            welded_count: welded_indices.len(),
+        }
+    }
+
+    /// Position of each welded vertex
+    fn welded_positions(&self) -> Vec<Point3> {
+        let mut positions = vec![Point3::ZERO; self.welded_count];
+        for (i, &w) in self.welded.iter().enumerate() {
+            positions[w as usize] = self.positions[i];
+        }
+        positions
+    }
+
+    /// One step of Loop subdivision with Warren's weights, boundaries become cubic B-splines
+    fn subdivide(&self) -> Self {
+        let welded_positions = self.welded_positions();
+
+        // Gather the triangles around each edge, in a vector to keep the sums deterministic
+        let mut edge_indices: HashMap<(u32, u32), usize> = HashMap::new();
+        let mut edges: Vec<((u32, u32), Edge)> = Vec::new();
+        for triangle in &self.indices {
+            let w = triangle.map(|i| self.welded[i as usize]);
+            for k in 0..3 {
+                let key = edge_key(w[k], w[(k + 1) % 3]);
+                let index = *edge_indices.entry(key).or_insert_with(|| {
+                    let edge = Edge {
+                        welded: (self.welded_count + edges.len()) as u32,
+                        opposite_sum: Point3::ZERO,
+                        face_count: 0,
+                    };
+                    edges.push((key, edge));
+                    edges.len() - 1
+                });
+                let edge = &mut edges[index].1;
+                edge.opposite_sum += welded_positions[w[(k + 2) % 3] as usize];
+                edge.face_count += 1;
+            }
+        }
+
+        // Neighbours of each vertex, separately along boundary edges
+        let mut neighbour_sum = vec![Point3::ZERO; self.welded_count];
+        let mut valence = vec![0u32; self.welded_count];
+        let mut boundary_sum = vec![Point3::ZERO; self.welded_count];
+        let mut boundary_count = vec![0u32; self.welded_count];
+        for &((a, b), ref edge) in &edges {
+            for (v, other) in [(a, b), (b, a)] {
+                neighbour_sum[v as usize] += welded_positions[other as usize];
+                valence[v as usize] += 1;
+                if edge.face_count == 1 {
+                    boundary_sum[v as usize] += welded_positions[other as usize];
+                    boundary_count[v as usize] += 1;
+                }
+            }
+        }
+
+        // Existing vertices move towards their neighbours
+        let even: Vec<Point3> = (0..self.welded_count)
+            .map(|w| {
+                let p = welded_positions[w];
+                match boundary_count[w] {
+                    0 => {
+                        let n = valence[w] as f32;
+                        let beta = if valence[w] == 3 {
+                            3.0 / 16.0
+                        } else {
+                            3.0 / (8.0 * n)
+                        };
+                        (1.0 - n * beta) * p + beta * neighbour_sum[w]
+                    }
+                    2 => 0.75 * p + 0.125 * boundary_sum[w],
+                    // Keep corners of non-manifold geometry in place
+                    _ => p,
+                }
+            })
+            .collect();
+
+        let mut refined = SubdivisionMesh {
+            positions: self.welded.iter().map(|&w| even[w as usize]).collect(),
+            uvs: self.uvs.clone(),
+            colors: self.colors.clone(),
+            indices: Vec::with_capacity(4 * self.indices.len()),
+            welded: self.welded.clone(),
+            welded_count: self.welded_count + edges.len(),
+        };
+
+        // New vertices on the edges, split like the original vertices along seams
+        let mut midpoints: HashMap<(u32, u32), u32> = HashMap::new();
+        let mut midpoint = |refined: &mut SubdivisionMesh, i: u32, j: u32| {
+            *midpoints.entry(edge_key(i, j)).or_insert_with(|| {
+                let (wi, wj) = (self.welded[i as usize], self.welded[j as usize]);
+                let edge = &edges[edge_indices[&edge_key(wi, wj)]].1;
+                let ends = welded_positions[wi as usize] + welded_positions[wj as usize];
+                let position = if edge.face_count == 2 {
+                    0.375 * ends + 0.125 * edge.opposite_sum
+                } else {
+                    0.5 * ends
+                };
+                let (i, j) = (i as usize, j as usize);
+                refined.positions.push(position);
+                if !self.uvs.is_empty() {
+                    refined.uvs.push(0.5 * (self.uvs[i] + self.uvs[j]));
+                }
+                if !self.colors.is_empty() {
+                    refined.colors.push(0.5 * (self.colors[i] + self.colors[j]));
+                }
+                refined.welded.push(edge.welded);
+                refined.positions.len() as u32 - 1
+            })
+        };
+
+        for &[a, b, c] in &self.indices {
+            let ab = midpoint(&mut refined, a, b);
+            let bc = midpoint(&mut refined, b, c);
+            let ca = midpoint(&mut refined, c, a);
+            refined.indices.push([a, ab, ca]);
+            refined.indices.push([ab, b, bc]);
+            refined.indices.push([ca, bc, c]);
+            refined.indices.push([ab, bc, ca]);
+        }
+        refined
+    }
+
+    /// Area weighted vertex normals, shared by welded vertices
+    fn smooth_normals(&self) -> Vec<Vec3> {
+        let mut normals = vec![Vec3::ZERO; self.welded_count];
+        for &[a, b, c] in &self.indices {
+            let (p0, p1, p2) = (
+                self.positions[a as usize],
+                self.positions[b as usize],
+                self.positions[c as usize],
+            );
+            let normal = (p1 - p0).cross(p2 - p0);
+            for i in [a, b, c] {
+                normals[self.welded[i as usize] as usize] += normal;
+            }
+        }
+        self.welded
+            .iter()
+            .map(|&w| normals[w as usize].normalize_or(Vec3::Y))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::*;
+
+    /// Cube with 8 shared vertices, or split per face with UVs like an exported asset
+    fn cube(split_faces: bool) -> TriangleMesh {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let corner = |i: u32| Point3::new((i & 1) as f32, (i >> 1 & 1) as f32, (i >> 2) as f32);
+        let faces = [
+            [0, 2, 3, 1],
+            [4, 5, 7, 6],
+            [0, 1, 5, 4],
+            [2, 6, 7, 3],
+            [0, 4, 6, 2],
+            [1, 3, 7, 5],
+        ];
+        let (mut positions, mut uvs, mut indices) = (Vec::new(), Vec::new(), Vec::new());
+        if !split_faces {
+            positions = (0..8).map(corner).collect();
+        }
+        for face in faces {
+            let quad = if split_faces {
+                let first = positions.len() as u32;
+                for (k, &i) in face.iter().enumerate() {
+                    positions.push(corner(i));
+                    uvs.push(Vec2::new(
+                        (k == 1 || k == 2) as u32 as f32,
+                        (k >= 2) as u32 as f32,
+                    ));
+                }
+                [first, first + 1, first + 2, first + 3]
+            } else {
+                face
+            };
+            indices.push([quad[0], quad[1], quad[2]]);
+            indices.push([quad[0], quad[2], quad[3]]);
+        }
+        TriangleMesh::new(positions, Vec::new(), uvs, indices, &material)
+    }
+
+    /// Seams in the UVs must not change the subdivided surface or open holes in it
+    #[test]
+    fn test_loop_subdivision_seams() {
+        let refinement = MeshRefinement {
+            subdivision_levels: 3,
+            displacement: Some(Displacement::function(|p, _| p.y)),
+            displacement_scale: 0.05,
+        };
+        let shared = refinement.apply(&cube(false));
+        let split = refinement.apply(&cube(true));
+        assert_eq!(shared.indices.len(), 12 * 64);
+        assert_eq!(split.indices.len(), 12 * 64);
+
+        // Compare the triangles with positions rounded, as the sums may run in another order
+        let mut shared_triangles: Vec<[i32; 9]> = Vec::new();
+        let mut split_triangles: Vec<[i32; 9]> = Vec::new();
+        for (mesh, triangles) in [
+            (&shared, &mut shared_triangles),
+            (&split, &mut split_triangles),
+        ] {
+            for triangle in &mesh.indices {
+                let p = triangle.map(|i| {
+                    let p = (mesh.positions[i as usize] * 1e4).round();
+                    p.to_array().map(|x| x as i32)
+                });
+                triangles.push(p.concat().try_into().unwrap());
+            }
+            triangles.sort();
+        }
+        assert!(shared_triangles == split_triangles);
+
+        // The smoothed cube stays inside its hull and every edge keeps two triangles
+        let welded = SubdivisionMesh::from_mesh(&split);
+        let mut edge_counts: HashMap<(u32, u32), u32> = HashMap::new();
+        for triangle in &welded.indices {
+            let w = triangle.map(|i| welded.welded[i as usize]);
+            for k in 0..3 {
+                *edge_counts
+                    .entry(edge_key(w[k], w[(k + 1) % 3]))
+                    .or_default() += 1;
+            }
+        }
+        assert!(edge_counts.values().all(|&count| count == 2));
+        for p in &split.positions {
+            assert!(p.cmpgt(Vec3::ZERO).all() && p.cmplt(Vec3::ONE).all());
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::image::*;
+use crate::shared::*;
+
+use std::path::Path;
+
+/// Color which varies over a surface
+pub trait Texture: Send + Sync {
+    fn value(&self, uv: Vec2, p: Point3) -> Color;
+}
+
+/// Same color everywhere
+pub struct SolidColor {
+    pub color: Color,
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _uv: Vec2, _p: Point3) -> Color {
+        self.color
+    }
+}
+
+/// Texture multiplied by a constant color, such as a glTF base color factor
+pub struct ScaledTexture {
+    pub texture: Arc<dyn Texture>,
+    pub scale: Color,
+}
+
+impl Texture for ScaledTexture {
+    fn value(&self, uv: Vec2, p: Point3) -> Color {
+        self.scale * self.texture.value(uv, p)
+    }
+}
+
+/// Single value which varies over a surface, such as roughness or height
+pub trait ScalarTexture: Send + Sync {
+    fn value(&self, uv: Vec2, p: Point3) -> f32;
+}
+
+/// Same value everywhere
+pub struct SolidScalar {
+    pub value: f32,
+}
+
+impl ScalarTexture for SolidScalar {
+    fn value(&self, _uv: Vec2, _p: Point3) -> f32 {
+        self.value
+    }
+}
+
+/// Mean of the channels of a color texture, so noise and images can drive scalars
+pub struct ChannelAverage {
+    pub texture: Arc<dyn Texture>,
+}
+
+impl ScalarTexture for ChannelAverage {
+    fn value(&self, uv: Vec2, p: Point3) -> f32 {
+        let color = self.texture.value(uv, p);
+        (color.x + color.y + color.z) / 3.0
+    }
+}
+
+/// Alternating cubes of two textures in space, so the pattern doesn't depend on UVs
+pub struct CheckerTexture {
+    pub inv_scale: f32,
+    pub even: Arc<dyn Texture>,
+    pub odd: Arc<dyn Texture>,
+}
+
+impl CheckerTexture {
+    pub fn new(scale: f32, even: Color, odd: Color) -> Self {
+        CheckerTexture {
+            inv_scale: 1.0 / scale,
+            even: Arc::new(SolidColor { color: even }),
+            odd: Arc::new(SolidColor { color: odd }),
+        }
+    }
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, uv: Vec2, p: Point3) -> Color {
+        let cell = (self.inv_scale * p).floor();
+        if (cell.x + cell.y + cell.z).rem_euclid(2.0) == 0.0 {
+            self.even.value(uv, p)
+        } else {
+            self.odd.value(uv, p)
+        }
+    }
+}
+
+/// How UVs outside 0..1 are mapped onto the image
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+}
+
+/// Decode an sRGB encoded value in 0..1 to linear
+pub fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Image sampled with bilinear filtering, v = 0 is the bottom row
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    // Linear colors, row major from the top row
+    pixels: Vec<Color>,
+    pub wrap: WrapMode,
+}
+
+impl ImageTexture {
+    pub fn new(width: usize, height: usize, pixels: Vec<Color>, wrap: WrapMode) -> Self {
+        assert!(width > 0 && height > 0);
+        assert_eq!(pixels.len(), width * height);
+        ImageTexture {
+            width,
+            height,
+            pixels,
+            wrap,
+        }
+    }
+
+    /// Convert an image to linear colors. Color images are usually sRGB encoded and decoded
+    /// to linear, data such as normal maps should pass srgb = false.
+    pub fn from_image(image: &Image, srgb: bool, wrap: WrapMode) -> Self {
+        let decode = |x: f32| if srgb { srgb_to_linear(x) } else { x };
+        let pixels = (0..image.width * image.height)
+            .map(|index| {
+                let channel = |channel: usize| decode(image.sample(index, channel));
+                // Gray images repeat one channel, alpha is ignored
+                if image.channels < 3 {
+                    Color::splat(channel(0))
+                } else {
+                    Color::new(channel(0), channel(1), channel(2))
+                }
+            })
+            .collect();
+        ImageTexture::new(image.width, image.height, pixels, wrap)
+    }
+
+    /// Load an 8 or 16 bit PNG, see from_image for srgb
+    pub fn load(path: &Path, srgb: bool, wrap: WrapMode) -> Result<Self, ImageError> {
+        Ok(ImageTexture::from_image(
+            &Image::load_png(path)?,
+            srgb,
+            wrap,
+        ))
+    }
+
+    fn texel(&self, x: isize, y: isize) -> Color {
+        let (x, y) = match self.wrap {
+            WrapMode::Repeat => (
+                x.rem_euclid(self.width as isize),
+                y.rem_euclid(self.height as isize),
+            ),
+            WrapMode::Clamp => (
+                x.clamp(0, self.width as isize - 1),
+                y.clamp(0, self.height as isize - 1),
+            ),
+        };
+        self.pixels[y as usize * self.width + x as usize]
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, uv: Vec2, _p: Point3) -> Color {
+        // Texel centers are at half integer coordinates
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+
+        let top = self.texel(x0, y0).lerp(self.texel(x0 + 1, y0), fx);
+        let bottom = self.texel(x0, y0 + 1).lerp(self.texel(x0 + 1, y0 + 1), fx);
+        top.lerp(bottom, fy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    /// An sRGB PNG should decode to linear and filter between texels with either wrap mode
+    #[test]
+    fn test_image_texture() {
+        let path = std::env::temp_dir().join("one_weekend_test_texture.png");
+        {
+            let file = File::create(&path).unwrap();
+            let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), 2, 1);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder
+                .write_header()
+                .unwrap()
+                .write_image_data(&[0, 0, 0, 255, 188, 255])
+                .unwrap();
+        }
+        let repeat = ImageTexture::load(&path, true, WrapMode::Repeat).unwrap();
+        let clamp = ImageTexture::load(&path, true, WrapMode::Clamp).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Texel centers, 188 is about half in linear
+        let right = repeat.value(Vec2::new(0.75, 0.5), Point3::ZERO);
+        assert!((right - Color::new(1.0, 0.5, 1.0)).length() < 0.01);
+        assert_eq!(
+            repeat.value(Vec2::new(0.25, 0.5), Point3::ZERO),
+            Color::ZERO
+        );
+
+        // Halfway between the texels, and across the edge which only wraps when repeating
+        let middle = clamp.value(Vec2::new(0.5, 0.5), Point3::ZERO);
+        assert!((middle - 0.5 * right).length() < 1e-5);
+        let edge = Vec2::new(1.0, 0.5);
+        assert!((repeat.value(edge, Point3::ZERO) - middle).length() < 1e-5);
+        assert!((clamp.value(edge, Point3::ZERO) - right).length() < 1e-5);
+    }
+}
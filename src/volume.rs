@@ -0,0 +1,534 @@
+use crate::material::*;
+use crate::object::*;
+use crate::shared::*;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Error while loading a voxel grid
+#[derive(Debug)]
+pub enum VolumeError {
+    Io(PathBuf, std::io::Error),
+    Format(PathBuf, String),
+}
+
+impl fmt::Display for VolumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VolumeError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            VolumeError::Format(path, message) => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for VolumeError {}
+
+/// Phase function for scattering inside a medium
+#[derive(Copy, Clone)]
+pub enum PhaseFunction {
+    Isotropic,
+    // Asymmetry g in -1..1, positive scatters forward
+    HenyeyGreenstein(f32),
+}
+
+impl PhaseFunction {
+    /// Probability density of scattering into a direction at cos_theta to the unit direction
+    pub fn evaluate(&self, cos_theta: f32) -> f32 {
+        let inv_4pi = 0.25 / std::f32::consts::PI;
+        match *self {
+            PhaseFunction::Isotropic => inv_4pi,
+            PhaseFunction::HenyeyGreenstein(g) => {
+                let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+                inv_4pi * (1.0 - g * g) / (denom * denom.sqrt())
+            }
+        }
+    }
+
+    /// Sample a new direction for a ray traveling along the unit direction
+    pub fn sample(&self, rng: &mut RayRng, direction: Vec3) -> Vec3 {
+        match *self {
+            PhaseFunction::Isotropic => random_unit_vector(rng),
+            PhaseFunction::HenyeyGreenstein(g) => {
+                let u = rng.gen_range(0.0..1.0);
+                let cos_theta = if g.abs() < 1e-3 {
+                    1.0 - 2.0 * u
+                } else {
+                    let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+                    ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+                };
+                let sin_theta = f32::sqrt(1.0 - cos_theta * cos_theta);
+                let phi = rng.gen_range(0.0..2.0 * std::f32::consts::PI);
+
+                let tangent = direction.any_orthonormal_vector();
+                let bitangent = direction.cross(tangent);
+                sin_theta * (phi.cos() * tangent + phi.sin() * bitangent) + cos_theta * direction
+            }
+        }
+    }
+}
+
+/// Scattering event inside a medium
+pub struct MediumScatter {
+    pub t: f32,
+    pub attenuation: Color,
+    pub phase: PhaseFunction,
+}
+
+/// A participating medium which rays travel through
+pub trait Medium: Send + Sync {
+    /// Sample a scattering event along the ray before t_max, None if the ray passes through
+    fn sample(&self, rng: &mut RayRng, ray: &Ray, t_max: f32) -> Option<MediumScatter>;
+    /// Fraction of light passing along the ray up to t_max, may be a stochastic estimate
+    fn transmittance(&self, rng: &mut RayRng, ray: &Ray, t_max: f32) -> f32;
+}
+
+/// Medium with the same density and albedo everywhere
+pub struct HomogeneousMedium {
+    pub density: f32,
+    pub albedo: Color,
+    pub phase: PhaseFunction,
+}
+
+impl Medium for HomogeneousMedium {
+    fn sample(&self, rng: &mut RayRng, ray: &Ray, t_max: f32) -> Option<MediumScatter> {
+        // Sample a free-flight distance, converted to ray parameter units
+        let distance = -f32::ln(1.0 - rng.gen_range(0.0..1.0)) / self.density;
+        let t = distance / ray.direction.length();
+        if t >= t_max {
+            return None;
+        }
+        Some(MediumScatter {
+            t,
+            attenuation: self.albedo,
+            phase: self.phase,
+        })
+    }
+
+    fn transmittance(&self, _rng: &mut RayRng, ray: &Ray, t_max: f32) -> f32 {
+        f32::exp(-self.density * t_max * ray.direction.length())
+    }
+}
+
+/// Medium with density and albedo stored on a regular voxel grid, with values at the voxel
+/// centers interpolated trilinearly. Sampled with delta tracking against the maximum density.
+pub struct GridMedium {
+    pub min: Point3,
+    pub max: Point3,
+    resolution: [usize; 3],
+    density: Vec<f32>,
+    // Either a single albedo or one per voxel
+    albedo: Vec<Color>,
+    // Majorant for delta and ratio tracking
+    max_density: f32,
+    pub phase: PhaseFunction,
+}
+
+impl GridMedium {
+    /// Grid of densities with x varying fastest, then y, then z
+    pub fn new(
+        min: Point3,
+        max: Point3,
+        resolution: [usize; 3],
+        density: Vec<f32>,
+        albedo: Color,
+        phase: PhaseFunction,
+    ) -> Self {
+        assert!(resolution.iter().all(|r| *r > 0));
+        assert_eq!(density.len(), resolution[0] * resolution[1] * resolution[2]);
+        let max_density = density.iter().fold(0.0f32, |a, d| a.max(*d));
+        GridMedium {
+            min,
+            max,
+            resolution,
+            density,
+            albedo: vec![albedo],
+            max_density,
+            phase,
+        }
+    }
+
+    /// Fill the grid by evaluating a density function, such as a noise field, at voxel centers
+    pub fn from_fn<F: Fn(Point3) -> f32>(
+        min: Point3,
+        max: Point3,
+        resolution: [usize; 3],
+        density: F,
+        albedo: Color,
+        phase: PhaseFunction,
+    ) -> Self {
+        let [nx, ny, nz] = resolution;
+        let voxel_size = (max - min) / Vec3::new(nx as f32, ny as f32, nz as f32);
+        let values = (0..nx * ny * nz)
+            .map(|i| {
+                let voxel = Vec3::new((i % nx) as f32, (i / nx % ny) as f32, (i / nx / ny) as f32);
+                density(min + (voxel + Vec3::splat(0.5)) * voxel_size).max(0.0)
+            })
+            .collect();
+        GridMedium::new(min, max, resolution, values, albedo, phase)
+    }
+
+    /// Load a Mitsuba gridvolume file with float32 data. One channel holds the density,
+    /// three channels hold RGB densities which are averaged.
+    pub fn load_vol(
+        path: &Path,
+        density_scale: f32,
+        albedo: Color,
+        phase: PhaseFunction,
+    ) -> Result<Self, VolumeError> {
+        let bytes = std::fs::read(path).map_err(|e| VolumeError::Io(path.to_path_buf(), e))?;
+        let format_error = |message: &str| VolumeError::Format(path.to_path_buf(), message.into());
+
+        if bytes.len() < 48 || &bytes[0..3] != b"VOL" || bytes[3] != 3 {
+            return Err(format_error("not a version 3 VOL file"));
+        }
+        let int_at =
+            |offset: usize| i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let float_at =
+            |offset: usize| f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        if int_at(4) != 1 {
+            return Err(format_error("only float32 data is supported"));
+        }
+        let resolution = [int_at(8), int_at(12), int_at(16)];
+        let channels = int_at(20);
+        if resolution.iter().any(|r| *r <= 0) || (channels != 1 && channels != 3) {
+            return Err(format_error("invalid resolution or channel count"));
+        }
+        let resolution = resolution.map(|r| r as usize);
+        let channels = channels as usize;
+        let min = Point3::new(float_at(24), float_at(28), float_at(32));
+        let max = Point3::new(float_at(36), float_at(40), float_at(44));
+
+        // Corrupt resolutions can overflow the size
+        let size = resolution
+            .iter()
+            .try_fold(4 * channels, |size, r| size.checked_mul(*r))
+            .and_then(|size| size.checked_add(48));
+        if size.is_none_or(|size| bytes.len() < size) {
+            return Err(format_error("file is shorter than the grid"));
+        }
+        let count = resolution[0] * resolution[1] * resolution[2];
+        let density = (0..count)
+            .map(|i| {
+                let sum: f32 = (0..channels)
+                    .map(|c| float_at(48 + 4 * (i * channels + c)))
+                    .sum();
+                (density_scale * sum / channels as f32).max(0.0)
+            })
+            .collect();
+        Ok(GridMedium::new(
+            min, max, resolution, density, albedo, phase,
+        ))
+    }
+
+    /// Use a separate albedo for every voxel
+    pub fn with_albedo_grid(mut self, albedo: Vec<Color>) -> Self {
+        assert_eq!(albedo.len(), self.density.len());
+        self.albedo = albedo;
+        self
+    }
+
+    /// Trilinear interpolation of a voxel grid at p
+    fn lookup<T>(&self, values: &[T], p: Point3) -> T
+    where
+        T: Copy + std::ops::Mul<f32, Output = T> + std::ops::Add<Output = T>,
+    {
+        let [nx, ny, nz] = self.resolution;
+        let size = Vec3::new(nx as f32, ny as f32, nz as f32);
+        let g = ((p - self.min) / (self.max - self.min) * size - Vec3::splat(0.5))
+            .clamp(Vec3::ZERO, size - Vec3::ONE);
+        let (x0, y0, z0) = (g.x as usize, g.y as usize, g.z as usize);
+        let (x1, y1, z1) = (
+            (x0 + 1).min(nx - 1),
+            (y0 + 1).min(ny - 1),
+            (z0 + 1).min(nz - 1),
+        );
+        let f = g - g.floor();
+
+        let at = |x: usize, y: usize, z: usize| values[(z * ny + y) * nx + x];
+        let lerp = |a: T, b: T, t: f32| a * (1.0 - t) + b * t;
+        let y0z0 = lerp(at(x0, y0, z0), at(x1, y0, z0), f.x);
+        let y1z0 = lerp(at(x0, y1, z0), at(x1, y1, z0), f.x);
+        let y0z1 = lerp(at(x0, y0, z1), at(x1, y0, z1), f.x);
+        let y1z1 = lerp(at(x0, y1, z1), at(x1, y1, z1), f.x);
+        lerp(lerp(y0z0, y1z0, f.y), lerp(y0z1, y1z1, f.y), f.z)
+    }
+
+    fn density_at(&self, p: Point3) -> f32 {
+        self.lookup(&self.density, p)
+    }
+
+    fn albedo_at(&self, p: Point3) -> Color {
+        if self.albedo.len() == 1 {
+            self.albedo[0]
+        } else {
+            self.lookup(&self.albedo, p)
+        }
+    }
+
+    /// Parametric range of the ray inside the grid before t_max
+    fn clip(&self, ray: &Ray, t_max: f32) -> Option<(f32, f32)> {
+        let inv_direction = ray.direction.recip();
+        let t0 = (self.min - ray.origin) * inv_direction;
+        let t1 = (self.max - ray.origin) * inv_direction;
+        let t_enter = t0.min(t1).max_element().max(0.0);
+        let t_exit = t0.max(t1).min_element().min(t_max);
+        (t_enter < t_exit && self.max_density > 0.0).then_some((t_enter, t_exit))
+    }
+
+    /// Free-flight distance against the majorant, in ray parameter units
+    fn majorant_step(&self, rng: &mut RayRng, ray: &Ray) -> f32 {
+        -f32::ln(1.0 - rng.gen_range(0.0..1.0)) / (self.max_density * ray.direction.length())
+    }
+}
+
+impl Medium for GridMedium {
+    /// Delta tracking: tentative collisions against the majorant are real with probability
+    /// density / max_density, which keeps the estimate unbiased
+    fn sample(&self, rng: &mut RayRng, ray: &Ray, t_max: f32) -> Option<MediumScatter> {
+        let (mut t, t_exit) = self.clip(ray, t_max)?;
+        loop {
+            t += self.majorant_step(rng, ray);
+            if t >= t_exit {
+                return None;
+            }
+            let p = ray.at(t);
+            if rng.gen_range(0.0..1.0) * self.max_density < self.density_at(p) {
+                return Some(MediumScatter {
+                    t,
+                    attenuation: self.albedo_at(p),
+                    phase: self.phase,
+                });
+            }
+        }
+    }
+
+    /// Ratio tracking: multiply the probability of each tentative collision being null
+    fn transmittance(&self, rng: &mut RayRng, ray: &Ray, t_max: f32) -> f32 {
+        let Some((mut t, t_exit)) = self.clip(ray, t_max) else {
+            return 1.0;
+        };
+        let mut transmittance = 1.0;
+        loop {
+            t += self.majorant_step(rng, ray);
+            if t >= t_exit {
+                return transmittance;
+            }
+            transmittance *= 1.0 - self.density_at(ray.at(t)) / self.max_density;
+        }
+    }
+}
+
+/// Material on the boundary of a medium, rays pass straight through into the medium
+pub struct MediumBoundary {
+    pub medium: Arc<dyn Medium>,
+}
+
+impl Material for MediumBoundary {
+    fn scatter(&self, _rng: &mut RayRng, ray: &Ray, hit: &HitRecord) -> Option<ScatterResult> {
+        Some(ScatterResult {
+            attenuation: Color::ONE,
+            scattered_ray: Ray::new(hit.point, ray.direction, ray.time),
+        })
+    }
+
+    fn medium(&self) -> Option<&dyn Medium> {
+        Some(self.medium.as_ref())
+    }
+}
+
+/// Fills a closed boundary hittable with a medium, such as fog, smoke or clouds.
+/// Rays starting inside the boundary don't see the medium, and media don't nest.
+pub struct ConstantMedium {
+    boundary: Box<dyn RayHittable>,
+    material: Arc<dyn Material>,
+}
+
+impl ConstantMedium {
+    pub fn new(
+        boundary: Box<dyn RayHittable>,
+        density: f32,
+        albedo: Color,
+        phase: PhaseFunction,
+    ) -> Self {
+        let medium = Arc::new(HomogeneousMedium {
+            density,
+            albedo,
+            phase,
+        });
+        ConstantMedium::with_medium(boundary, medium)
+    }
+
+    /// Fill the boundary with any medium, such as a GridMedium covering the boundary
+    pub fn with_medium(boundary: Box<dyn RayHittable>, medium: Arc<dyn Medium>) -> Self {
+        ConstantMedium {
+            boundary,
+            material: Arc::new(MediumBoundary { medium }),
+        }
+    }
+}
+
+impl RayHittable for ConstantMedium {
+    fn intersect(&self, query: RayQuery) -> Option<HitRecord> {
+        let mut hit = self.boundary.intersect(query)?;
+        hit.material = self.material.clone();
+        Some(hit)
+    }
+
+    fn compute_bounds(&self, hittable_index: usize) -> HittableBounds {
+        self.boundary.compute_bounds(hittable_index)
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.boundary.is_bounded()
+    }
+
+    // The boundary only attenuates shadow rays through the medium, it never blocks them
+    fn occludes(&self, _query: RayQuery) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Delta and ratio tracking through a loaded density ramp should match its optical depth
+    #[test]
+    fn test_grid_medium_tracking() {
+        // Two voxels along x, density 0 and 2 at the voxel centers x = 0.25 and x = 0.75
+        let mut bytes = b"VOL".to_vec();
+        bytes.push(3);
+        for value in [1, 2, 1, 1, 1] {
+            bytes.extend(i32::to_le_bytes(value));
+        }
+        for value in [0.0f32, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 2.0] {
+            bytes.extend(f32::to_le_bytes(value));
+        }
+        let path = std::env::temp_dir().join("one_weekend_test_grid.vol");
+        std::fs::write(&path, bytes).unwrap();
+        let medium =
+            GridMedium::load_vol(&path, 1.0, Color::ONE, PhaseFunction::Isotropic).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Optical depth along x is 0.5 * 2 * 0.5 for the ramp plus 2 * 0.25 after it
+        let expected = f32::exp(-1.0);
+        let ray = Ray::new(Point3::new(-1.0, 0.5, 0.5), Vec3::new(2.0, 0.0, 0.0), 0.0);
+        let mut rng = RayRng::new(0);
+        let count = 20000;
+        let escaped = (0..count)
+            .filter(|_| medium.sample(&mut rng, &ray, 10.0).is_none())
+            .count();
+        let ratio: f32 = (0..count)
+            .map(|_| medium.transmittance(&mut rng, &ray, 10.0))
+            .sum();
+        assert!((escaped as f32 / count as f32 - expected).abs() < 0.02);
+        assert!((ratio / count as f32 - expected).abs() < 0.02);
+    }
+
+    /// Resolutions whose grid size overflows are a format error
+    #[test]
+    fn test_grid_medium_corrupt_header() {
+        let mut bytes = b"VOL".to_vec();
+        bytes.push(3);
+        for value in [1, i32::MAX, i32::MAX, i32::MAX, 3] {
+            bytes.extend(i32::to_le_bytes(value));
+        }
+        bytes.extend([0; 24]);
+        let path = std::env::temp_dir().join(format!(
+            "one_weekend_test_corrupt_{}.vol",
+            std::process::id()
+        ));
+        std::fs::write(&path, bytes).unwrap();
+        let result = GridMedium::load_vol(&path, 1.0, Color::ONE, PhaseFunction::Isotropic);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(VolumeError::Format(..))));
+    }
+
+    /// Rays crossing a constant medium slab should escape with probability exp(-density * d)
+    #[test]
+    fn test_constant_medium_slab() {
+        let (density, thickness) = (0.8, 1.5);
+        let white: Arc<dyn Material> = Arc::new(Lambertian::new(Color::ONE));
+        let slab = AaBox::new(
+            Point3::new(0.0, -10.0, -10.0),
+            Point3::new(thickness, 10.0, 10.0),
+            &white,
+        );
+        let fog = ConstantMedium::new(
+            Box::new(slab),
+            density,
+            Color::ONE,
+            PhaseFunction::Isotropic,
+        );
+
+        // A direction longer than one checks the conversion to ray parameter units
+        let ray = Ray::new(Point3::new(-1.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0), 0.0);
+        let query = |ray: Ray| RayQuery {
+            ray,
+            t_min: TRACE_EPSILON,
+            t_max: TRACE_INFINITY,
+        };
+        let enter = fog.intersect(query(ray)).unwrap();
+        assert!(enter.front_face);
+        let medium = enter.material.medium().unwrap();
+        let inside = enter
+            .material
+            .scatter(&mut RayRng::new(0), &ray, &enter)
+            .unwrap();
+        let exit = fog.intersect(query(inside.scattered_ray)).unwrap();
+        assert!(!exit.front_face);
+
+        let mut rng = RayRng::new(0);
+        let count = 20000;
+        let escaped = (0..count)
+            .filter(|_| {
+                medium
+                    .sample(&mut rng, &inside.scattered_ray, exit.t)
+                    .is_none()
+            })
+            .count();
+        let expected = f32::exp(-density * thickness);
+        assert!((escaped as f32 / count as f32 - expected).abs() < 0.02);
+        let transmittance = medium.transmittance(&mut rng, &inside.scattered_ray, exit.t);
+        assert!((transmittance - expected).abs() < 1e-3);
+    }
+
+    /// The mean cosine of Henyey-Greenstein samples is the asymmetry g, and the evaluated
+    /// density integrates to one with the same mean
+    #[test]
+    fn test_phase_mean_cosine() {
+        let mut rng = RayRng::new(0);
+        let direction = Vec3::new(1.0, 2.0, -1.0).normalize();
+        let count = 20000;
+        for (phase, g) in [
+            (PhaseFunction::Isotropic, 0.0),
+            (PhaseFunction::HenyeyGreenstein(-0.5), -0.5),
+            (PhaseFunction::HenyeyGreenstein(0.0), 0.0),
+            (PhaseFunction::HenyeyGreenstein(0.3), 0.3),
+            (PhaseFunction::HenyeyGreenstein(0.8), 0.8),
+        ] {
+            let mean = (0..count)
+                .map(|_| {
+                    let sample = phase.sample(&mut rng, direction);
+                    assert!((sample.length() - 1.0).abs() < 1e-3);
+                    sample.dot(direction)
+                })
+                .sum::<f32>()
+                / count as f32;
+            assert!((mean - g).abs() < 0.02);
+
+            // Midpoint rule over cos_theta, the azimuth contributes 2 pi
+            let steps = 10000;
+            let (mut total, mut mean) = (0.0, 0.0);
+            for i in 0..steps {
+                let cos_theta = -1.0 + 2.0 * (i as f32 + 0.5) / steps as f32;
+                let weight =
+                    2.0 * std::f32::consts::PI * phase.evaluate(cos_theta) * 2.0 / steps as f32;
+                total += weight;
+                mean += weight * cos_theta;
+            }
+            assert!((total - 1.0).abs() < 1e-3);
+            assert!((mean - g).abs() < 1e-3);
+        }
+    }
+}